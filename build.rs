@@ -0,0 +1,491 @@
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+#[path = "src/xml.rs"]
+mod xml;
+use xml::{tags, Tag};
+
+/// インターフェースの定義を置くディレクトリ
+const INTERFACES_DIR: &str = "interfaces";
+/// メンバーの説明に使用するアノテーション
+const DOC_ANNOTATION: &str = "org.gtk.GDBus.DocString";
+/// オブジェクトパスの型(`DevicePath`など)を指定するアノテーション
+const PATH_TYPE_ANNOTATION: &str = "bluez_dbus.PathType";
+/// BlueZが省略することのあるプロパティを示すアノテーション
+const OPTIONAL_ANNOTATION: &str = "bluez_dbus.Optional";
+/// Variantを値に持つ辞書(`a{qv}`など)の、Variantの中身の型を指定するアノテーション
+const VARIANT_TYPE_ANNOTATION: &str = "bluez_dbus.VariantType";
+
+/// BlueZのイントロスペクションのXMLからバインディングを生成する
+///
+/// `interfaces/`の各XMLを読み込み、インターフェースごとにメンバーを展開するマクロを
+/// `OUT_DIR/interfaces.rs`に書き出す。
+/// blockingとnonblockはそれぞれのマクロを渡して同じ定義から関数を作成する。
+///
+/// ハンドルの型(`Adapter`など)は生成しない。新しいインターフェースのXMLを追加した場合はマクロのみが作成されるため、
+/// 各フレーバーにハンドルの型を作成し、`members`のマクロを展開する。
+fn main() {
+    println!("cargo:rerun-if-changed={}", INTERFACES_DIR);
+    println!("cargo:rerun-if-changed=src/xml.rs");
+    let mut files: Vec<_> = fs::read_dir(INTERFACES_DIR)
+        .expect("failed to read interfaces directory")
+        .map(|entry| entry.expect("failed to read interfaces directory").path())
+        .filter(|path| path.extension().map(|ext| ext == "xml").unwrap_or(false))
+        .collect();
+    files.sort();
+
+    let mut out = String::new();
+    for file in files {
+        println!("cargo:rerun-if-changed={}", file.display());
+        let xml = fs::read_to_string(&file)
+            .unwrap_or_else(|e| panic!("failed to read {}: {}", file.display(), e));
+        for interface in parse(&xml).unwrap_or_else(|e| panic!("{}: {}", file.display(), e)) {
+            generate(&mut out, &interface, &file);
+        }
+    }
+    let dest = Path::new(&env::var("OUT_DIR").unwrap()).join("interfaces.rs");
+    fs::write(&dest, out).unwrap_or_else(|e| panic!("failed to write {}: {}", dest.display(), e));
+}
+
+#[derive(Default)]
+struct Interface {
+    name: String,
+    methods: Vec<Method>,
+    properties: Vec<Property>,
+}
+
+#[derive(Default)]
+struct Method {
+    name: String,
+    args: Vec<Arg>,
+    doc: Option<String>,
+}
+
+struct Arg {
+    name: String,
+    signature: String,
+    out: bool,
+    path_type: Option<String>,
+}
+
+#[derive(Default)]
+struct Property {
+    name: String,
+    signature: String,
+    writable: bool,
+    doc: Option<String>,
+    path_type: Option<String>,
+    variant_type: Option<String>,
+    optional: bool,
+}
+
+//--------------------------------------------------------------------------------
+// XMLの読み込み
+
+/// イントロスペクションのXMLからインターフェースの一覧を読み込む
+///
+/// `interface`、`method`、`arg`、`property`、`annotation`以外の要素は無視する。
+fn parse(xml: &str) -> Result<Vec<Interface>, String> {
+    let mut interfaces = vec![];
+    let mut stack: Vec<String> = vec![];
+    let mut interface: Option<Interface> = None;
+    let mut method: Option<Method> = None;
+    let mut property: Option<Property> = None;
+
+    for tag in tags(xml)? {
+        match tag {
+            Tag::Start(name, attrs) => {
+                let attr = |key: &str| -> Result<String, String> {
+                    attrs
+                        .iter()
+                        .find(|(k, _)| k == key)
+                        .map(|(_, v)| v.clone())
+                        .ok_or_else(|| format!("<{}> has no `{}` attribute", name, key))
+                };
+                match name.as_str() {
+                    "interface" => {
+                        interface = Some(Interface {
+                            name: attr("name")?,
+                            ..Default::default()
+                        })
+                    }
+                    "method" => {
+                        method = Some(Method {
+                            name: attr("name")?,
+                            ..Default::default()
+                        })
+                    }
+                    "arg" => {
+                        if let Some(method) = &mut method {
+                            let out = attrs.iter().any(|(k, v)| k == "direction" && v == "out");
+                            method.args.push(Arg {
+                                name: attr("name")?,
+                                signature: attr("type")?,
+                                out,
+                                path_type: None,
+                            });
+                        }
+                    }
+                    "property" => {
+                        property = Some(Property {
+                            name: attr("name")?,
+                            signature: attr("type")?,
+                            writable: attr("access")?.contains("write"),
+                            doc: None,
+                            path_type: None,
+                            variant_type: None,
+                            optional: false,
+                        })
+                    }
+                    "annotation" if attr("name")? == DOC_ANNOTATION => {
+                        let doc = Some(attr("value")?);
+                        match stack.last().map(|s| s.as_str()) {
+                            Some("method") => method.as_mut().unwrap().doc = doc,
+                            Some("property") => property.as_mut().unwrap().doc = doc,
+                            _ => {}
+                        }
+                    }
+                    "annotation" if attr("name")? == PATH_TYPE_ANNOTATION => {
+                        let path_type = Some(attr("value")?);
+                        match stack.last().map(|s| s.as_str()) {
+                            Some("arg") => {
+                                let method = method.as_mut().unwrap();
+                                method.args.last_mut().unwrap().path_type = path_type;
+                            }
+                            Some("property") => property.as_mut().unwrap().path_type = path_type,
+                            _ => {}
+                        }
+                    }
+                    "annotation" if attr("name")? == VARIANT_TYPE_ANNOTATION => {
+                        if let Some("property") = stack.last().map(|s| s.as_str()) {
+                            property.as_mut().unwrap().variant_type = Some(attr("value")?);
+                        }
+                    }
+                    "annotation" if attr("name")? == OPTIONAL_ANNOTATION => {
+                        if let Some("property") = stack.last().map(|s| s.as_str()) {
+                            property.as_mut().unwrap().optional = attr("value")? == "true";
+                        }
+                    }
+                    _ => {}
+                }
+                stack.push(name);
+            }
+            Tag::End(name) => {
+                if stack.pop().as_deref() != Some(name.as_str()) {
+                    return Err(format!("unexpected </{}>", name));
+                }
+                match name.as_str() {
+                    "interface" => interfaces.extend(interface.take()),
+                    "method" => {
+                        if let (Some(interface), Some(method)) = (&mut interface, method.take()) {
+                            interface.methods.push(method);
+                        }
+                    }
+                    "property" => {
+                        if let (Some(interface), Some(property)) = (&mut interface, property.take())
+                        {
+                            interface.properties.push(property);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    if let Some(name) = stack.pop() {
+        return Err(format!("<{}> is not closed", name));
+    }
+    Ok(interfaces)
+}
+
+//--------------------------------------------------------------------------------
+// コードの生成
+
+/// インターフェース一つ分のマクロを書き出す
+///
+/// - `members`: メソッド、プロパティの取得、設定の関数をそれぞれ渡されたマクロで作成する
+///   (省略されることのあるプロパティの型には`optional`を付ける)
+/// - `changes`: プロパティの変更の型を渡されたマクロで作成する
+/// - `info`: プロパティの一覧の型を渡されたマクロで作成する(必須のプロパティ、省略されることのあるプロパティの順)
+///
+/// 型が対応していないメンバーは生成せず、マクロの説明に列挙する。
+fn generate(out: &mut String, interface: &Interface, file: &Path) {
+    let mut members = String::new();
+    let mut changes = String::new();
+    let mut required = String::new();
+    let mut optional = String::new();
+    let mut skipped = vec![];
+
+    for method in &interface.methods {
+        match method_call(method) {
+            Some(call) => writeln!(members, "        $method!({});", call).unwrap(),
+            None => skipped.push(method.name.as_str()),
+        }
+    }
+    for property in &interface.properties {
+        let ty = match property_type(property) {
+            Some(ty) => ty,
+            None => {
+                skipped.push(property.name.as_str());
+                continue;
+            }
+        };
+        let name = snake_case(&property.name);
+        let getter = if property.signature == "b" {
+            "is"
+        } else {
+            "get"
+        };
+        writeln!(
+            members,
+            "        $get!({}{}_{}, {}{}, {:?});",
+            doc(&property.doc),
+            getter,
+            name,
+            if property.optional { "optional " } else { "" },
+            ty,
+            property.name
+        )
+        .unwrap();
+        writeln!(
+            changes,
+            "            {}({}) = {:?},",
+            camel_case(&name),
+            ty,
+            property.name
+        )
+        .unwrap();
+        writeln!(
+            if property.optional {
+                &mut optional
+            } else {
+                &mut required
+            },
+            "            {}{}: {} = {:?},",
+            doc(&property.doc),
+            name,
+            ty,
+            property.name
+        )
+        .unwrap();
+    }
+    for property in &interface.properties {
+        if let (true, Some(ty)) = (
+            property.writable,
+            rust_type(&property.signature, &property.path_type),
+        ) {
+            writeln!(
+                members,
+                "        $set!(set_{}, {}, {:?});",
+                snake_case(&property.name),
+                ty,
+                property.name
+            )
+            .unwrap();
+        }
+    }
+
+    writeln!(
+        out,
+        "/// `{}`のメンバーを展開する(`{}`から生成)",
+        interface.name,
+        file.display()
+    )
+    .unwrap();
+    if !skipped.is_empty() {
+        writeln!(out, "///").unwrap();
+        writeln!(
+            out,
+            "/// 型が対応していないため生成しないメンバー: {}",
+            skipped.join(", ")
+        )
+        .unwrap();
+    }
+    writeln!(out, "#[allow(unused_macros)]").unwrap();
+    writeln!(
+        out,
+        "macro_rules! {} {{",
+        interface.name.replace('.', "_").to_lowercase()
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "    (members: $method: ident, $get: ident, $set: ident) => {{"
+    )
+    .unwrap();
+    out.push_str(&members);
+    writeln!(out, "    }};").unwrap();
+    writeln!(
+        out,
+        "    (changes: $m: ident, $(#[$meta: meta])* $name: ident) => {{"
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "        $m!($(#[$meta])* $name, {:?}, {{",
+        interface.name
+    )
+    .unwrap();
+    out.push_str(&changes);
+    writeln!(out, "        }});").unwrap();
+    writeln!(out, "    }};").unwrap();
+    writeln!(
+        out,
+        "    (info: $m: ident, $(#[$meta: meta])* $name: ident) => {{"
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "        $m!($(#[$meta])* $name, {:?}, {{",
+        interface.name
+    )
+    .unwrap();
+    out.push_str(&required);
+    writeln!(out, "        }}, {{").unwrap();
+    out.push_str(&optional);
+    writeln!(out, "        }});").unwrap();
+    writeln!(out, "    }};").unwrap();
+    writeln!(out, "}}").unwrap();
+    writeln!(out).unwrap();
+}
+
+/// メソッドを作成するマクロの引数
+///
+/// `関数名, "メソッド名", (引数名: 型 => 送信する値, ...) -> 戻り値の型`の形式。
+/// 戻り値が二つ以上ある場合はタプルとする。
+/// 引数のオプションの辞書(`a{sv}`)は値の型が決まらないため、Variantを値とする`HashMap`として受け取る。
+/// 対応していない型を含む場合は`None`を返す。
+fn method_call(method: &Method) -> Option<String> {
+    let mut params = vec![];
+    let mut outputs = vec![];
+    for arg in &method.args {
+        if arg.out {
+            outputs.push(match arg.signature.as_str() {
+                "h" => "dbus::arg::OwnedFd".to_string(),
+                signature => rust_type(signature, &arg.path_type)?,
+            });
+            continue;
+        }
+        let name = param_name(&arg.name);
+        let ty = match (arg.signature.as_str(), &arg.path_type) {
+            ("s", _) => "&str".to_string(),
+            ("o", Some(path_type)) => format!("&crate::{}", path_type),
+            ("a{sv}", _) => {
+                "std::collections::HashMap<&str, dbus::arg::Variant<Box<dyn dbus::arg::RefArg>>>"
+                    .to_string()
+            }
+            (signature, path_type) => rust_type(signature, path_type)?,
+        };
+        params.push(format!("{}: {} => {}", name, ty, name));
+    }
+    let ret = match outputs.len() {
+        0 => "()".to_string(),
+        1 => outputs.remove(0),
+        _ => format!("({})", outputs.join(", ")),
+    };
+    Some(format!(
+        "{}{}, {:?}, ({}) -> {}",
+        doc(&method.doc),
+        snake_case(&method.name),
+        method.name,
+        params.join(", "),
+        ret
+    ))
+}
+
+/// D-Busの型に対応するRustの型
+///
+/// オブジェクトパスは型が指定されていればその型を使用する。
+fn rust_type(signature: &str, path_type: &Option<String>) -> Option<String> {
+    match (signature, path_type) {
+        ("o", Some(path_type)) => return Some(format!("crate::{}", path_type)),
+        ("ao", Some(path_type)) => return Some(format!("Vec<crate::{}>", path_type)),
+        _ => {}
+    }
+    let ty = match signature {
+        "s" => "String",
+        "o" => "dbus::Path<'static>",
+        "b" => "bool",
+        "y" => "u8",
+        "q" => "u16",
+        "n" => "i16",
+        "u" => "u32",
+        "i" => "i32",
+        "t" => "u64",
+        "x" => "i64",
+        "as" => "Vec<String>",
+        "ao" => "Vec<dbus::Path<'static>>",
+        "ay" => "Vec<u8>",
+        _ => return None,
+    };
+    Some(ty.to_string())
+}
+
+/// プロパティの型に対応するRustの型
+///
+/// Variantを値に持つ辞書は、中身の型が指定されていれば`HashMap`とする(値は`FromRefArg`がVariantを外して読み込む)。
+/// 送信時の型とは一致しないため、設定の関数には使用しない。
+fn property_type(property: &Property) -> Option<String> {
+    let variant_type = match &property.variant_type {
+        Some(variant_type) => variant_type,
+        None => return rust_type(&property.signature, &property.path_type),
+    };
+    let key = property
+        .signature
+        .strip_prefix("a{")
+        .and_then(|s| s.strip_suffix("v}"))?;
+    Some(format!(
+        "std::collections::HashMap<{}, {}>",
+        rust_type(key, &None)?,
+        rust_type(variant_type, &None)?
+    ))
+}
+
+fn doc(doc: &Option<String>) -> String {
+    match doc {
+        Some(doc) => format!("#[doc = {:?}] ", doc),
+        None => String::new(),
+    }
+}
+
+/// `TxPower` -> `tx_power`、`UUIDs` -> `uuids`
+///
+/// 大文字が続く部分は一つの単語として扱う。
+fn snake_case(name: &str) -> String {
+    let mut out = String::new();
+    let mut prev_lower = false;
+    for c in name.chars() {
+        if c.is_uppercase() && prev_lower {
+            out.push('_');
+        }
+        prev_lower = c.is_lowercase() || c.is_ascii_digit();
+        out.extend(c.to_lowercase());
+    }
+    out
+}
+
+/// `tx_power` -> `TxPower`
+fn camel_case(name: &str) -> String {
+    name.split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            chars
+                .next()
+                .map(|c| c.to_uppercase().chain(chars).collect::<String>())
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
+/// 引数名(予約語の場合は`r#`を付ける)
+fn param_name(name: &str) -> String {
+    let name = snake_case(name);
+    match name.as_str() {
+        "type" | "match" | "ref" | "move" | "in" | "loop" | "fn" | "mod" | "use" => {
+            format!("r#{}", name)
+        }
+        _ => name,
+    }
+}
@@ -0,0 +1,22 @@
+use bluez_dbus::nonblock::Session;
+use std::error::Error;
+
+/// tokioのランタイムを使用せずにアダプターの一覧を表示する
+#[cfg(not(feature = "local"))]
+pub fn main() -> Result<(), Box<dyn Error>> {
+    futures::executor::block_on(process())
+}
+
+#[cfg(feature = "local")]
+pub fn main() -> Result<(), Box<dyn Error>> {
+    Err("this example requires a non-local session".into())
+}
+
+#[allow(dead_code)]
+async fn process() -> Result<(), Box<dyn Error>> {
+    let s = Session::new()?;
+    for adapter in s.get_adapters().await? {
+        println!("{}: {}", adapter.get_path(), adapter.get_address().await?);
+    }
+    Ok(())
+}
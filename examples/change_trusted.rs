@@ -1,11 +1,12 @@
 use bluez_dbus::blocking::{Device, Session};
+use bluez_dbus::DevicePath;
 use std::env;
 use std::error::Error;
 
 pub fn main() -> Result<(), Box<dyn Error>> {
     let s = Session::new().unwrap();
     let mut args = env::args();
-    let dev_path = args.nth(1).unwrap();
+    let dev_path: DevicePath = args.nth(1).unwrap().parse()?;
     let dev = Device::new(&s, &dev_path);
     let trusted = dev.is_trusted()?;
     println!("now: {}", trusted);
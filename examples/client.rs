@@ -1,70 +1,54 @@
-use bluez_dbus::blocking::{Adapter, Device, GattService, Session};
+use bluez_dbus::blocking::{Device, GattService, Session};
+use bluez_dbus::Bus;
 use std::error::Error;
 use std::thread;
 use std::time::Duration;
 
 pub fn main() -> Result<(), Box<dyn Error>> {
-    let s = Session::new().unwrap();
-    let adapters = s.get_adapters()?;
-    let adapters = adapters.unwrap();
-    adapters.iter().for_each(|adapter| {
-        println!("{}", adapter);
-    });
+    // `mock_bluez`の例などで起動したバスに接続する場合は、アドレスを環境変数で指定する
+    let s = match std::env::var("BLUEZ_DBUS_ADDRESS") {
+        Ok(address) => Session::builder().bus(Bus::Address(address)).build()?,
+        Err(_) => Session::new()?,
+    };
+    for adapter in s.get_adapters()? {
+        println!("{}", adapter.get_path());
+    }
 
-    let adapter_path = &adapters[0];
-    let adapter = Adapter::create(&s, adapter_path).unwrap().unwrap();
-    adapter.start_discovery().unwrap();
+    let adapter = s.default_adapter()?;
+    adapter.start_discovery()?;
     thread::sleep(Duration::from_millis(2000));
 
-    if let Some(a) = Adapter::create(&s, adapter_path)? {
-        if let Some(devices) = a.get_devices()? {
-            let devices: Vec<Device> = devices
-                .iter()
-                .map(|device| Device::new(&s, device))
-                .collect();
-            for dev in devices.iter() {
-                print_dev(&s, &dev)?;
-            }
-        }
-    } else {
-        println!("not found: {}", adapter_path);
+    for dev in adapter.get_devices()?.iter() {
+        print_dev(dev)?;
     }
-    adapter.stop_discovery().unwrap();
+    adapter.stop_discovery()?;
     Ok(())
 }
 
-fn print_dev(session: &Session, dev: &Device) -> Result<(), Box<dyn Error>> {
-    println!("【{}】", match dev.get_name() {
-        Ok(name) => name,
-        _ => "no_name".to_string(),
-    });
-    if let Ok(address) = dev.get_address() {
-        println!("  address: {}", address);
-    }
+fn print_dev(dev: &Device) -> Result<(), Box<dyn Error>> {
+    // プロパティはまとめて一度に取得する
+    let info = dev.info()?;
+    println!("【{}】", info.name.as_deref().unwrap_or("no_name"));
+    println!("  address: {}", info.address);
     println!("     path: {}", dev.get_path());
-    println!("  trusted: {}", dev.is_trusted()?);
-    println!("   paired: {}", dev.is_paired()?);
-    println!("  Adapter: {}", dev.get_adapter()?);
-    println!("    UUIDs: {:?}", dev.get_uuids()?);
-    if let Ok(icon) = dev.get_icon() {
+    println!("  trusted: {}", info.trusted);
+    println!("   paired: {}", info.paired);
+    println!("  Adapter: {}", info.adapter);
+    println!("    UUIDs: {:?}", info.uuids.unwrap_or_default());
+    if let Some(icon) = info.icon {
         println!("     icon: {}", icon);
     }
-    if let Ok(Some(gatts)) = dev.get_gatt_services() {
-        for gatt in gatts.iter() {
-            print_gatt(session, gatt)?;
-        }
+    for gatt in dev.get_gatt_services()?.iter() {
+        print_gatt(gatt)?;
     }
     Ok(())
 }
 
-fn print_gatt(session: &Session, gatt: &str) -> Result<(), Box<dyn Error>> {
-    println!("Gatt Service: {}", gatt);
-    let gatt_service = GattService::new(session, gatt);
+fn print_gatt(gatt_service: &GattService) -> Result<(), Box<dyn Error>> {
+    println!("Gatt Service: {}", gatt_service.get_path());
     println!(" device:{}", gatt_service.get_device()?);
-    if let Ok(Some(chars)) = gatt_service.get_characteristics() {
-        chars.iter().for_each(|charc| {
-            println!("    {}", charc);
-        });
+    for charc in gatt_service.get_characteristics()?.iter() {
+        println!("    {}", charc.get_path());
     }
     Ok(())
 }
@@ -1,4 +1,4 @@
-use bluez_dbus::nonblock::{Adapter, Device, GattService, Session};
+use bluez_dbus::nonblock::{Device, GattService, Session};
 use std::error::Error;
 use std::time::Duration;
 use tokio::runtime::Builder;
@@ -21,69 +21,45 @@ pub fn main() -> Result<(), Box<dyn Error>> {
 
 pub async fn process() -> Result<(), Box<dyn Error>> {
     let s = Session::new().unwrap();
-    let adapters = s.get_adapters().await?;
-    let adapters = adapters.unwrap();
-    adapters.iter().for_each(|adapter| {
-        println!("{}", adapter);
-    });
+    for adapter in s.get_adapters().await? {
+        println!("{}", adapter.get_path());
+    }
 
-    let adapter_path = &adapters[0];
-    let adapter = Adapter::create(&s, adapter_path).await.unwrap().unwrap();
-    adapter.start_discovery().await.unwrap();
+    let adapter = s.default_adapter().await?;
+    adapter.start_discovery().await?;
     delay_for(Duration::from_millis(2000)).await;
 
-    if let Some(a) = Adapter::create(&s, adapter_path).await? {
-        if let Some(devices) = a.get_devices().await? {
-            let devices: Vec<Device> = devices
-                .iter()
-                .map(|device| Device::new(&s, device))
-                .collect();
-            for dev in devices.iter() {
-                print_dev(&s, &dev).await?;
-            }
-        }
-    } else {
-        println!("not found: {}", adapter_path);
+    for dev in adapter.get_devices().await?.iter() {
+        print_dev(dev).await?;
     }
-    adapter.stop_discovery().await.unwrap();
+    adapter.stop_discovery().await?;
     Ok(())
 }
 
-async fn print_dev(session: &Session, dev: &Device) -> Result<(), Box<dyn Error>> {
-    println!(
-        "【{}】",
-        match dev.get_name().await {
-            Ok(name) => name,
-            _ => "no_name".to_string(),
-        }
-    );
-    if let Ok(address) = dev.get_address().await {
-        println!("  address: {}", address);
-    }
+async fn print_dev(dev: &Device) -> Result<(), Box<dyn Error>> {
+    // プロパティはまとめて一度に取得する
+    let info = dev.info().await?;
+    println!("【{}】", info.name.as_deref().unwrap_or("no_name"));
+    println!("  address: {}", info.address);
     println!("     path: {}", dev.get_path());
-    println!("  trusted: {}", dev.is_trusted().await?);
-    println!("   paired: {}", dev.is_paired().await?);
-    println!("  Adapter: {}", dev.get_adapter().await?);
-    println!("    UUIDs: {:?}", dev.get_uuids().await?);
-    if let Ok(icon) = dev.get_icon().await {
+    println!("  trusted: {}", info.trusted);
+    println!("   paired: {}", info.paired);
+    println!("  Adapter: {}", info.adapter);
+    println!("    UUIDs: {:?}", info.uuids.unwrap_or_default());
+    if let Some(icon) = info.icon {
         println!("     icon: {}", icon);
     }
-    if let Ok(Some(gatts)) = dev.get_gatt_services().await {
-        for gatt in gatts.iter() {
-            print_gatt(session, gatt).await?;
-        }
+    for gatt in dev.get_gatt_services().await?.iter() {
+        print_gatt(gatt).await?;
     }
     Ok(())
 }
 
-async fn print_gatt(session: &Session, gatt: &str) -> Result<(), Box<dyn Error>> {
-    println!("Gatt Service: {}", gatt);
-    let gatt_service = GattService::new(session, gatt);
+async fn print_gatt(gatt_service: &GattService) -> Result<(), Box<dyn Error>> {
+    println!("Gatt Service: {}", gatt_service.get_path());
     println!(" device:{}", gatt_service.get_device().await?);
-    if let Ok(Some(chars)) = gatt_service.get_characteristics().await {
-        chars.iter().for_each(|charc| {
-            println!("    {}", charc);
-        });
+    for charc in gatt_service.get_characteristics().await?.iter() {
+        println!("    {}", charc.get_path());
     }
     Ok(())
 }
@@ -0,0 +1,28 @@
+use bluez_dbus::blocking::{Device, Session};
+use bluez_dbus::{DeviceProperty, Event};
+use std::error::Error;
+
+pub fn main() -> Result<(), Box<dyn Error>> {
+    let s = Session::new()?;
+    s.on_event(|event| {
+        if let Event::DeviceAdded { device, .. } = event {
+            println!("added: {}", device);
+            // コールバック内ではセッションを使用できないため、受信は別のスレッドで行う
+            std::thread::spawn(move || {
+                let s = Session::new().unwrap();
+                let dev = Device::new(&s, &device);
+                for change in dev.property_changes().unwrap() {
+                    if let DeviceProperty::Rssi(rssi) = change {
+                        println!("{}: {}", device, rssi);
+                    }
+                }
+            });
+        }
+    })?;
+    for event in s.events()? {
+        if let Event::DeviceRemoved { device } = event {
+            println!("removed: {}", device);
+        }
+    }
+    Ok(())
+}
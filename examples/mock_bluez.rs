@@ -0,0 +1,40 @@
+use bluez_dbus::mock::{MockBluez, MockCharacteristic, MockDevice, MockService, PrivateBus};
+use std::error::Error;
+use std::thread;
+use std::time::Duration;
+
+/// プライベートなバスにモックのBlueZを公開し続ける
+///
+/// 表示されたアドレスを`BLUEZ_DBUS_ADDRESS`に指定すると、`client`の例などをモックに対して実行できる。
+pub fn main() -> Result<(), Box<dyn Error>> {
+    let bus = PrivateBus::spawn()?;
+    let mock = MockBluez::new();
+    mock.add_device(
+        MockDevice::new("00:11:22:33:44:55")
+            .name("paired-speaker")
+            .rssi(-60),
+    );
+    let sensor = MockDevice::new("66:77:88:99:AA:BB")
+        .name("sensor")
+        .rssi(-45)
+        .service(
+            MockService::new("0000181a-0000-1000-8000-00805f9b34fb").characteristic(
+                MockCharacteristic::new("00002a6e-0000-1000-8000-00805f9b34fb")
+                    .flags(&["read", "notify"])
+                    .value(vec![0x10, 0x09])
+                    .descriptor("00002902-0000-1000-8000-00805f9b34fb", vec![0, 0]),
+            ),
+        );
+    mock.add_discoverable(sensor, Duration::from_millis(500));
+    mock.add_discoverable(
+        MockDevice::new("CC:DD:EE:FF:00:11")
+            .connect_error("org.bluez.Error.Failed", "Page Timeout"),
+        Duration::from_millis(1500),
+    );
+    let _server = mock.serve(&bus.bus())?;
+
+    println!("BLUEZ_DBUS_ADDRESS={}", bus.address());
+    loop {
+        thread::sleep(Duration::from_secs(60));
+    }
+}
@@ -1,4 +1,5 @@
 use bluez_dbus::nonblock::{Device, Session};
+use bluez_dbus::DevicePath;
 use std::env;
 use std::error::Error;
 use tokio::runtime::Builder;
@@ -21,7 +22,7 @@ pub fn main() -> Result<(), Box<dyn Error>> {
 async fn process() -> Result<(), Box<dyn Error>> {
     let s = Session::new().unwrap();
     let mut args = env::args();
-    let dev_path = args.nth(1).unwrap();
+    let dev_path: DevicePath = args.nth(1).unwrap().parse()?;
     let dev = Device::new(&s, &dev_path);
     let trusted = dev.is_trusted().await?;
     println!("now: {}", trusted);
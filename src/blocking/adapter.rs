@@ -1,100 +1,152 @@
-use crate::blocking::Session;
+use crate::blocking::{Device, PropertyChanges, Session};
 use crate::*;
-use dbus::arg::{Append, Arg, Get};
-use std::error::Error;
+use dbus::arg::{Append, AppendAll, Arg, ReadAll, Variant};
+use dbus::channel::Token;
+use std::collections::HashMap;
+use std::time::Duration;
 
-#[derive(Debug)]
-pub struct Adapter<'a> {
-    session: &'a Session,
-    path: String,
+#[derive(Debug, Clone)]
+pub struct Adapter {
+    session: Session,
+    path: AdapterPath,
+    timeout: Option<Timeout>,
 }
 
-impl<'a> Adapter<'a> {
-    fn new(session: &'a Session, path: &str) -> Self {
+impl Adapter {
+    pub(in crate) fn new(session: &Session, path: &AdapterPath) -> Self {
         Adapter {
-            session,
-            path: path.to_string(),
+            session: session.clone(),
+            path: path.clone(),
+            timeout: None,
         }
     }
 
+    /// タイムアウトを指定したアダプターを作成
+    ///
+    /// 作成したアダプターからの呼び出しにはセッションの既定値の代わりにこの値を使用する。
+    pub fn with_timeout(&self, timeout: Duration) -> Self {
+        Adapter {
+            session: self.session.clone(),
+            path: self.path.clone(),
+            timeout: Some(Timeout::Duration(timeout)),
+        }
+    }
+
+    /// 期限を指定したアダプターを作成
+    ///
+    /// 各呼び出しのタイムアウトは期限までの残り時間となる。
+    /// 期限を過ぎている場合は`BluezError::Timeout`を返す。
+    pub fn with_deadline(&self, deadline: Deadline) -> Self {
+        Adapter {
+            session: self.session.clone(),
+            path: self.path.clone(),
+            timeout: Some(Timeout::Deadline(deadline)),
+        }
+    }
+
+    /// アダプターのオブジェクトパスを取得
+    pub fn get_path(&self) -> AdapterPath {
+        self.path.clone()
+    }
+
+    /// アダプターの全てのプロパティを一度に取得
+    ///
+    /// `GetAll`を一度だけ呼び出す。セッションのキャッシュが有効な場合は問い合わせを行わない。
+    pub fn info(&self) -> Result<AdapterInfo, BluezError> {
+        self.session
+            .get_all(&self.path, Timeout::resolve(self.timeout)?)
+    }
+
     /// bluetoothアダプターの作成
     ///
     /// 指定されたパスの存在を確認してアダプターを作成する。
     /// 存在しない場合は`Ok(None)`を返す。
-    pub fn create(
-        session: &'a Session,
-        path: &str,
-    ) -> Result<Option<Self>, Box<dyn Error + 'static>> {
-        if let Some(adapters) = session.get_adapters()? {
-            if adapters.contains(&path.to_string()) {
-                return Ok(Some(Adapter::new(session, path)));
-            }
-        }
-        Ok(None)
+    pub fn create(session: &Session, path: &AdapterPath) -> Result<Option<Self>, BluezError> {
+        Ok(session
+            .get_adapters()?
+            .into_iter()
+            .find(|adapter| adapter.path == *path))
     }
 
     /// デバイスリスト取得
     ///
-    /// アダプターに登録されているデバイスのパスのリストを取得する
-    pub fn get_devices(&self) -> Result<Option<Vec<String>>, BoxError> {
-        self.session.get_children(&self.path, "Adapter")
+    /// アダプターに登録されているデバイスの一覧を取得する。
+    /// デバイスが無い場合は空の`Vec`を返す。
+    pub fn get_devices(&self) -> Result<Vec<Device>, BluezError> {
+        let paths: Vec<DevicePath> =
+            self.session
+                .get_children(&self.path, "Adapter", Timeout::resolve(self.timeout)?)?;
+        Ok(paths
+            .iter()
+            .map(|path| Device::new(&self.session, path))
+            .collect())
     }
 
-    /// デバイスの検索を開始する
-    pub fn start_discovery(&self) -> Result<(), BoxError> {
-        self.sub_discovery("StartDiscovery")
+    /// アドレスを指定してデバイスに接続する
+    ///
+    /// 検索で見つかっていないデバイスにも接続できる。`address_type`には`"public"`か`"random"`を指定する
+    /// (省略した場合はBlueZが判断する)。
+    /// BlueZ 5.49以降の機能のため、接続先のBlueZに`ConnectDevice`が無い場合は呼び出しを行わずに
+    /// `BluezError::Unsupported`を返す。
+    pub fn connect_device(
+        &self,
+        address: &str,
+        address_type: Option<&str>,
+    ) -> Result<Device, BluezError> {
+        self.session
+            .require_method(ADAPTER_INTERFACE, "ConnectDevice")?;
+        let mut properties = HashMap::new();
+        properties.insert("Address", Variant(address));
+        if let Some(address_type) = address_type {
+            properties.insert("AddressType", Variant(address_type));
+        }
+        let (path,): (DevicePath,) = self.method_call("ConnectDevice", (properties,))?;
+        Ok(Device::new(&self.session, &path))
     }
 
-    /// デバイスの検索を停止する
-    pub fn stop_discovery(&self) -> Result<(), BoxError> {
-        self.sub_discovery("StopDiscovery")
+    /// アダプターのプロパティの変更を受信する
+    ///
+    /// 返されたイテレーターが破棄されるまで受信を続ける。
+    pub fn property_changes(&self) -> Result<PropertyChanges<AdapterProperty>, BluezError> {
+        self.session.property_changes(&self.path)
     }
 
-    pub fn remove_device(&self, device: &str) -> Result<(), BoxError> {
-        self.session
-            .method_call(&self.path, ADAPTER_INTERFACE, "RemoveDevice", (device,))?;
-        Ok(())
+    /// アダプターのプロパティの変更を受信するコールバックを登録する
+    ///
+    /// コールバック内で呼び出せるメソッドは`Session::on_event`と同じ。
+    /// 返された`Token`を`Session::remove_callback`に渡すと登録を解除する。
+    pub fn on_property_change<F>(&self, f: F) -> Result<Token, BluezError>
+    where
+        F: FnMut(AdapterProperty) + Send + 'static,
+    {
+        self.session.on_property_change(&self.path, f)
     }
 
-    // TODO: SetDiscoveryFilter
-
-    fn sub_discovery(&self, method: &str) -> Result<(), BoxError> {
+    fn method_call<R: ReadAll, A: AppendAll>(&self, method: &str, arg: A) -> Result<R, BluezError> {
+        let timeout = Timeout::resolve(self.timeout)?;
         self.session
-            .method_call(&self.path, ADAPTER_INTERFACE, method, ())?;
-        Ok(())
+            .method_call(&self.path, ADAPTER_INTERFACE, method, arg, timeout)
     }
 
-    fn get_property<A: for<'z> Get<'z>>(&self, property: &str) -> Result<A, BoxError> {
-        Ok(self
-            .session
-            .get_property(&self.path, ADAPTER_INTERFACE, property)?)
+    fn get_property<A: FromRefArg>(&self, property: &str) -> Result<A, BluezError> {
+        self.session.get_property(
+            &self.path,
+            ADAPTER_INTERFACE,
+            property,
+            Timeout::resolve(self.timeout)?,
+        )
     }
-    fn set_property<T: Append + Arg>(&self, prop: &str, value: T) -> Result<(), BoxError> {
-        Ok(self
-            .session
-            .set_property(&self.path, ADAPTER_INTERFACE, prop, value)?)
+    fn set_property<T: Append + Arg>(&self, prop: &str, value: T) -> Result<(), BluezError> {
+        self.session.set_property(
+            &self.path,
+            ADAPTER_INTERFACE,
+            prop,
+            value,
+            Timeout::resolve(self.timeout)?,
+        )
     }
 
     //--------------------------------------------------------------------------------
-    // プロパティ
-    // get
-    get_property!(get_address, String, "Address");
-    get_property!(get_name, String, "Name");
-    get_property!(get_alias, String, "Alias");
-    get_property!(get_class, u32, "Class");
-    get_property!(is_powered, bool, "Powered");
-    get_property!(is_discoverable, bool, "Discoverable");
-    get_property!(is_pairable, bool, "Pairable");
-    get_property!(get_pairable_timeout, u32, "PairableTimeout");
-    get_property!(get_discoverable_timeout, u32, "DiscoverableTimeout");
-    get_property!(is_discovering, bool, "Discovering");
-    get_property!(get_uuids, Vec<String>, "UUIDs");
-    get_property!(get_modalias, String, "Modalias");
-    // set
-    set_property!(set_alias, String, "Alias");
-    set_property!(set_powered, bool, "Powered");
-    set_property!(set_discoverable, bool, "Discoverable");
-    set_property!(set_pairable, bool, "Pairable");
-    set_property!(set_pairable_timeout, u32, "PairableTimeout");
-    set_property!(set_discoverable_timeout, u32, "DiscoverableTimeout");
+    // メソッド、プロパティ(interfaces/org.bluez.Adapter1.xmlから生成)
+    org_bluez_adapter1!(members: call_method, get_property, set_property);
 }
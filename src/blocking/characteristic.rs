@@ -1,69 +1,119 @@
-use crate::blocking::Session;
+use crate::blocking::{Descriptor, GattService, PropertyChanges, Session};
 use crate::*;
-use dbus::arg::Get;
-use dbus::strings::Path;
+use dbus::arg::{AppendAll, ReadAll};
+use dbus::channel::Token;
+use std::time::Duration;
 
 static CHARACTERISTIC_INTERFACE: &str = "org.bluez.GattCharacteristic1";
 
-#[derive(Debug)]
-pub struct Characteristic<'a> {
-    session: &'a Session,
-    path: String,
+#[derive(Debug, Clone)]
+pub struct Characteristic {
+    session: Session,
+    path: CharacteristicPath,
+    timeout: Option<Timeout>,
 }
 
-impl<'a> Characteristic<'a> {
+impl Characteristic {
     /// Gatt Service作成
-    pub fn new(session: &'a Session, path: &str) -> Self {
+    pub fn new(session: &Session, path: &CharacteristicPath) -> Self {
         Characteristic {
-            session,
-            path: path.to_string(),
+            session: session.clone(),
+            path: path.clone(),
+            timeout: None,
         }
     }
 
-    pub fn get_descriptors(&self) -> Result<Option<Vec<String>>, BoxError> {
-        self.session.get_children(&self.path, "Characteristic")
+    /// タイムアウトを指定したCharacteristicを作成
+    ///
+    /// 作成したCharacteristicからの呼び出しにはセッションの既定値の代わりにこの値を使用する。
+    pub fn with_timeout(&self, timeout: Duration) -> Self {
+        Characteristic {
+            session: self.session.clone(),
+            path: self.path.clone(),
+            timeout: Some(Timeout::Duration(timeout)),
+        }
+    }
+
+    /// 期限を指定したCharacteristicを作成
+    ///
+    /// 各呼び出しのタイムアウトは期限までの残り時間となる。
+    /// 期限を過ぎている場合は`BluezError::Timeout`を返す。
+    pub fn with_deadline(&self, deadline: Deadline) -> Self {
+        Characteristic {
+            session: self.session.clone(),
+            path: self.path.clone(),
+            timeout: Some(Timeout::Deadline(deadline)),
+        }
+    }
+
+    /// キャラクタリスティックのオブジェクトパスを取得
+    pub fn get_path(&self) -> CharacteristicPath {
+        self.path.clone()
     }
 
-    pub fn read_value(&self) -> Result<Vec<u8>, BoxError> {
-        let (value,): (Vec<u8>,) =
-            self.session
-                .method_call(&self.path, CHARACTERISTIC_INTERFACE, "ReadValue", ())?;
-        Ok(value)
+    /// キャラクタリスティックの全てのプロパティを一度に取得
+    ///
+    /// `GetAll`を一度だけ呼び出す。セッションのキャッシュが有効な場合は問い合わせを行わない。
+    pub fn info(&self) -> Result<CharacteristicInfo, BluezError> {
+        self.session
+            .get_all(&self.path, Timeout::resolve(self.timeout)?)
     }
 
-    pub fn write_value(&self, values: Vec<u8>) -> Result<(), BoxError> {
-        Ok(self.session.method_call(
+    /// キャラクタリスティックが属するGATTサービスを取得
+    ///
+    /// オブジェクトパスから求めるため、BlueZへの問い合わせは行わない。
+    pub fn service(&self) -> GattService {
+        GattService::new(&self.session, &self.path.service())
+    }
+
+    /// キャラクタリスティックに属するディスクリプターの一覧を取得
+    pub fn get_descriptors(&self) -> Result<Vec<Descriptor>, BluezError> {
+        let paths: Vec<DescriptorPath> = self.session.get_children(
             &self.path,
-            CHARACTERISTIC_INTERFACE,
-            "WriteValue",
-            (values,),
-        )?)
+            "Characteristic",
+            Timeout::resolve(self.timeout)?,
+        )?;
+        Ok(paths
+            .iter()
+            .map(|path| Descriptor::new(&self.session, path))
+            .collect())
     }
 
-    pub fn start_notify(&self) -> Result<(), BoxError> {
-        Ok(self
-            .session
-            .method_call(&self.path, CHARACTERISTIC_INTERFACE, "StartNotify", ())?)
+    /// キャラクタリスティックのプロパティの変更を受信する
+    ///
+    /// 返されたイテレーターが破棄されるまで受信を続ける。
+    pub fn property_changes(&self) -> Result<PropertyChanges<CharacteristicProperty>, BluezError> {
+        self.session.property_changes(&self.path)
     }
 
-    pub fn stop_notify(&self) -> Result<(), BoxError> {
-        Ok(self
-            .session
-            .method_call(&self.path, CHARACTERISTIC_INTERFACE, "StopNotify", ())?)
+    /// キャラクタリスティックのプロパティの変更を受信するコールバックを登録する
+    ///
+    /// 通知は`CharacteristicProperty::Value`として届く。
+    /// コールバック内で呼び出せるメソッドは`Session::on_event`と同じ。
+    /// 返された`Token`を`Session::remove_callback`に渡すと登録を解除する。
+    pub fn on_property_change<F>(&self, f: F) -> Result<Token, BluezError>
+    where
+        F: FnMut(CharacteristicProperty) + Send + 'static,
+    {
+        self.session.on_property_change(&self.path, f)
     }
 
-    fn get_property<A: for<'z> Get<'z>>(&self, property: &str) -> Result<A, BoxError> {
-        Ok(self
-            .session
-            .get_property(&self.path, CHARACTERISTIC_INTERFACE, property)?)
+    fn method_call<R: ReadAll, A: AppendAll>(&self, method: &str, arg: A) -> Result<R, BluezError> {
+        let timeout = Timeout::resolve(self.timeout)?;
+        self.session
+            .method_call(&self.path, CHARACTERISTIC_INTERFACE, method, arg, timeout)
+    }
+
+    fn get_property<A: FromRefArg>(&self, property: &str) -> Result<A, BluezError> {
+        self.session.get_property(
+            &self.path,
+            CHARACTERISTIC_INTERFACE,
+            property,
+            Timeout::resolve(self.timeout)?,
+        )
     }
 
     //--------------------------------------------------------------------------------
-    // プロパティ
-    // get
-    get_property!(get_uuid, String, "UUID");
-    get_property!(get_service, Path, "Service");
-    get_property!(is_notifying, bool, "Notifying");
-    // TODO: Flags
-    // TODO: Descriptors
+    // メソッド、プロパティ(interfaces/org.bluez.GattCharacteristic1.xmlから生成)
+    org_bluez_gattcharacteristic1!(members: call_method, get_property, set_property);
 }
@@ -0,0 +1,155 @@
+use super::Transport;
+use crate::driver;
+use crate::*;
+use dbus::channel::{Channel, MatchingReceiver, Sender, Token};
+use dbus::message::MatchRule;
+use dbus::nonblock::{NonblockReply, SyncConnection};
+use dbus::Message;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+/// 応答待ちの間に切断を確認する間隔
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+static DBUS_SERVICE: &str = "org.freedesktop.DBus";
+static DBUS_PATH: &str = "/org/freedesktop/DBus";
+static DBUS_INTERFACE: &str = "org.freedesktop.DBus";
+
+/// 専用スレッドで送受信を行うコネクション
+///
+/// 応答はシリアル番号で呼び出し元に振り分けるため、複数のスレッドから同時にメソッドを呼び出せる。
+/// 受信したシグナルは送受信を行うスレッドで処理する。
+pub(in crate) struct Conn {
+    conn: Arc<SyncConnection>,
+    // 送受信を行うスレッドの終了通知と、受信済みの切断理由
+    exited: Mutex<(oneshot::Receiver<String>, Option<String>)>,
+}
+
+impl Conn {
+    pub(in crate) fn new(channel: Channel) -> Result<Self, BluezError> {
+        let (conn, exited) = driver::spawn(channel)?;
+        Ok(Conn {
+            conn,
+            exited: Mutex::new((exited, None)),
+        })
+    }
+
+    pub(in crate) fn unique_name(&self) -> String {
+        self.conn.unique_name().to_string()
+    }
+
+    /// 切断されていれば切断理由を返す
+    pub(in crate) fn lost(&self) -> Option<String> {
+        let mut exited = self.exited.lock().unwrap();
+        if exited.1.is_none() {
+            exited.1 = match exited.0.try_recv() {
+                Ok(reason) => Some(reason),
+                Err(oneshot::error::TryRecvError::Empty) => None,
+                Err(oneshot::error::TryRecvError::Closed) => Some("I/O thread exited".to_string()),
+            };
+        }
+        exited.1.clone()
+    }
+
+    /// 受信した全てのメッセージを渡す受信者を登録する
+    pub(in crate) fn start_receive<F>(&self, rule: MatchRule<'static>, mut f: F) -> Token
+    where
+        F: FnMut(&Message) + Send + 'static,
+    {
+        self.conn.start_receive(
+            rule,
+            Box::new(move |msg, _| {
+                f(&msg);
+                true
+            }),
+        )
+    }
+
+    /// バスにシグナルを受信するルールを追加する
+    pub(in crate) fn add_match(
+        &self,
+        rule: &MatchRule<'static>,
+        timeout: Duration,
+    ) -> Result<(), BluezError> {
+        let mut msg = Message::new_method_call(DBUS_SERVICE, DBUS_PATH, DBUS_INTERFACE, "AddMatch")
+            .map_err(|e| BluezError::DBus(dbus::Error::new_failed(&e)))?;
+        msg = msg.append1(rule.match_str());
+        self.call_with(msg, timeout, |_| Ok(()))
+    }
+
+    /// バスからシグナルを受信するルールを削除する
+    ///
+    /// 応答は待たない。
+    pub(in crate) fn remove_match(&self, rule: &MatchRule<'static>) -> Result<(), BluezError> {
+        let msg = Message::new_method_call(DBUS_SERVICE, DBUS_PATH, DBUS_INTERFACE, "RemoveMatch")
+            .map_err(|e| BluezError::DBus(dbus::Error::new_failed(&e)))?
+            .append1(rule.match_str());
+        self.conn
+            .send(msg)
+            .map_err(|()| BluezError::ConnectionLost("failed to send message".to_string()))?;
+        Ok(())
+    }
+
+    /// メソッド呼び出しのメッセージを送信し、応答を待つ
+    ///
+    /// `f`は応答を受信したスレッドで呼ばれるため、応答の受信とシグナルの処理の順序を保ったまま応答を処理できる。
+    /// エラーの応答の場合は呼ばれない。
+    pub(in crate) fn call_with<T, F>(
+        &self,
+        msg: Message,
+        timeout: Duration,
+        f: F,
+    ) -> Result<T, BluezError>
+    where
+        T: Send + 'static,
+        F: FnOnce(Message) -> Result<T, BluezError> + Send + 'static,
+    {
+        if let Some(reason) = self.lost() {
+            return Err(BluezError::ConnectionLost(reason));
+        }
+        let member = msg.member().map(|m| m.to_string()).unwrap_or_default();
+        let (tx, rx) = mpsc::sync_channel(1);
+        let token = self
+            .conn
+            .send_with_reply(
+                msg,
+                SyncConnection::make_f(move |mut reply: Message, _: &SyncConnection| {
+                    let reply = reply
+                        .as_result()
+                        .map(|_| ())
+                        .map_err(BluezError::from)
+                        .map(|()| reply);
+                    let _ = tx.send(reply.and_then(f));
+                }),
+            )
+            .map_err(|()| BluezError::ConnectionLost("failed to send message".to_string()))?;
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                self.conn.cancel_reply(token);
+                return Err(BluezError::Timeout(member));
+            }
+            match rx.recv_timeout(std::cmp::min(deadline - now, POLL_INTERVAL)) {
+                Ok(result) => return result,
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    return Err(BluezError::ConnectionLost("reply dropped".to_string()))
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    if let Some(reason) = self.lost() {
+                        self.conn.cancel_reply(token);
+                        return Err(BluezError::ConnectionLost(reason));
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Transport for Conn {
+    fn call(&self, msg: Message, timeout: Duration) -> Result<Message, BluezError> {
+        self.call_with(msg, timeout, Ok)
+    }
+}
@@ -1,48 +1,105 @@
-use crate::blocking::Session;
+use crate::blocking::{Characteristic, PropertyChanges, Session};
 use crate::*;
-use dbus::arg::Get;
-use dbus::strings::Path;
+use dbus::arg::{AppendAll, ReadAll};
+use dbus::channel::Token;
+use std::time::Duration;
 
 static DESCRIPTOR_INTERFACE: &str = "org.bluez.GattDescriptor1";
 
-#[derive(Debug)]
-pub struct Descriptor<'a> {
-    session: &'a Session,
-    path: String,
+#[derive(Debug, Clone)]
+pub struct Descriptor {
+    session: Session,
+    path: DescriptorPath,
+    timeout: Option<Timeout>,
 }
 
-impl<'a> Descriptor<'a> {
+impl Descriptor {
     /// Descriptor作成
-    pub fn new(session: &'a Session, path: &str) -> Self {
+    pub fn new(session: &Session, path: &DescriptorPath) -> Self {
         Descriptor {
-            session,
-            path: path.to_string(),
+            session: session.clone(),
+            path: path.clone(),
+            timeout: None,
         }
     }
-    pub fn read_value(&self) -> Result<Vec<u8>, BoxError> {
-        let (value,): (Vec<u8>,) =
-            self.session
-                .method_call(&self.path, DESCRIPTOR_INTERFACE, "ReadValue", ())?;
-        Ok(value)
+
+    /// タイムアウトを指定したDescriptorを作成
+    ///
+    /// 作成したDescriptorからの呼び出しにはセッションの既定値の代わりにこの値を使用する。
+    pub fn with_timeout(&self, timeout: Duration) -> Self {
+        Descriptor {
+            session: self.session.clone(),
+            path: self.path.clone(),
+            timeout: Some(Timeout::Duration(timeout)),
+        }
+    }
+
+    /// 期限を指定したDescriptorを作成
+    ///
+    /// 各呼び出しのタイムアウトは期限までの残り時間となる。
+    /// 期限を過ぎている場合は`BluezError::Timeout`を返す。
+    pub fn with_deadline(&self, deadline: Deadline) -> Self {
+        Descriptor {
+            session: self.session.clone(),
+            path: self.path.clone(),
+            timeout: Some(Timeout::Deadline(deadline)),
+        }
+    }
+
+    /// ディスクリプターのオブジェクトパスを取得
+    pub fn get_path(&self) -> DescriptorPath {
+        self.path.clone()
+    }
+
+    /// ディスクリプターの全てのプロパティを一度に取得
+    ///
+    /// `GetAll`を一度だけ呼び出す。セッションのキャッシュが有効な場合は問い合わせを行わない。
+    pub fn info(&self) -> Result<DescriptorInfo, BluezError> {
+        self.session
+            .get_all(&self.path, Timeout::resolve(self.timeout)?)
+    }
+
+    /// ディスクリプターが属するキャラクタリスティックを取得
+    ///
+    /// オブジェクトパスから求めるため、BlueZへの問い合わせは行わない。
+    pub fn characteristic(&self) -> Characteristic {
+        Characteristic::new(&self.session, &self.path.characteristic())
+    }
+
+    /// ディスクリプターのプロパティの変更を受信する
+    ///
+    /// 返されたイテレーターが破棄されるまで受信を続ける。
+    pub fn property_changes(&self) -> Result<PropertyChanges<DescriptorProperty>, BluezError> {
+        self.session.property_changes(&self.path)
+    }
+
+    /// ディスクリプターのプロパティの変更を受信するコールバックを登録する
+    ///
+    /// コールバック内で呼び出せるメソッドは`Session::on_event`と同じ。
+    /// 返された`Token`を`Session::remove_callback`に渡すと登録を解除する。
+    pub fn on_property_change<F>(&self, f: F) -> Result<Token, BluezError>
+    where
+        F: FnMut(DescriptorProperty) + Send + 'static,
+    {
+        self.session.on_property_change(&self.path, f)
     }
 
-    pub fn write_value(&self, values: Vec<u8>) -> Result<(), BoxError> {
-        Ok(self
-            .session
-            .method_call(&self.path, DESCRIPTOR_INTERFACE, "WriteValue", (values,))?)
+    fn method_call<R: ReadAll, A: AppendAll>(&self, method: &str, arg: A) -> Result<R, BluezError> {
+        let timeout = Timeout::resolve(self.timeout)?;
+        self.session
+            .method_call(&self.path, DESCRIPTOR_INTERFACE, method, arg, timeout)
     }
 
-    fn get_property<A: for<'z> Get<'z>>(&self, property: &str) -> Result<A, BoxError> {
-        Ok(self
-            .session
-            .get_property(&self.path, DESCRIPTOR_INTERFACE, property)?)
+    fn get_property<A: FromRefArg>(&self, property: &str) -> Result<A, BluezError> {
+        self.session.get_property(
+            &self.path,
+            DESCRIPTOR_INTERFACE,
+            property,
+            Timeout::resolve(self.timeout)?,
+        )
     }
 
     //--------------------------------------------------------------------------------
-    // プロパティ
-    // get
-    get_property!(get_uuid, String, "UUID");
-    get_property!(get_characteristic, Path, "Characteristic");
-    get_property!(get_value, Vec<u8>, "Value");
-    get_property!(get_flags, Vec<String>, "Flags");
+    // メソッド、プロパティ(interfaces/org.bluez.GattDescriptor1.xmlから生成)
+    org_bluez_gattdescriptor1!(members: call_method, get_property, set_property);
 }
@@ -1,103 +1,125 @@
-use crate::blocking::Session;
+use crate::blocking::{Adapter, GattService, PropertyChanges, Session};
 use crate::*;
-use dbus::arg::{Append, Arg, Get};
-use dbus::strings::Path;
+use dbus::arg::{Append, AppendAll, Arg, ReadAll};
+use dbus::channel::Token;
+use std::time::Duration;
 
 static DEVICE_INTERFACE: &str = "org.bluez.Device1";
 
-#[derive(Debug)]
-pub struct Device<'a> {
-    session: &'a Session,
-    path: String,
+#[derive(Debug, Clone)]
+pub struct Device {
+    session: Session,
+    path: DevicePath,
+    timeout: Option<Timeout>,
 }
 
-impl<'a> Device<'a> {
+impl Device {
     /// デバイス作成
-    pub fn new(session: &'a Session, path: &str) -> Self {
+    pub fn new(session: &Session, path: &DevicePath) -> Self {
         Device {
-            session,
-            path: path.to_string(),
+            session: session.clone(),
+            path: path.clone(),
+            timeout: None,
         }
     }
 
-    /// デバイスのオブジェクトパスを取得
-    pub fn get_path(&self) -> String {
-        self.path.clone()
+    /// タイムアウトを指定したデバイスを作成
+    ///
+    /// 作成したデバイスからの呼び出しにはセッションの既定値の代わりにこの値を使用する。
+    pub fn with_timeout(&self, timeout: Duration) -> Self {
+        Device {
+            session: self.session.clone(),
+            path: self.path.clone(),
+            timeout: Some(Timeout::Duration(timeout)),
+        }
     }
 
-    /// デバイスに属するgattサービスの一覧を取得
-    pub fn get_gatt_services(&self) -> Result<Option<Vec<String>>, BoxError> {
-        self.session.get_children(&self.path, "Device")
+    /// 期限を指定したデバイスを作成
+    ///
+    /// 各呼び出しのタイムアウトは期限までの残り時間となる。
+    /// 期限を過ぎている場合は`BluezError::Timeout`を返す。
+    pub fn with_deadline(&self, deadline: Deadline) -> Self {
+        Device {
+            session: self.session.clone(),
+            path: self.path.clone(),
+            timeout: Some(Timeout::Deadline(deadline)),
+        }
+    }
+
+    /// デバイスが属するアダプターを取得
+    ///
+    /// オブジェクトパスから求めるため、BlueZへの問い合わせは行わない。
+    pub fn adapter(&self) -> Adapter {
+        Adapter::new(&self.session, &self.path.adapter())
     }
 
-    pub fn connect(&self) -> Result<(), BoxError> {
-        Ok(self
-            .session
-            .method_call(&self.path, DEVICE_INTERFACE, "Connect", ())?)
+    /// デバイスのオブジェクトパスを取得
+    pub fn get_path(&self) -> DevicePath {
+        self.path.clone()
     }
 
-    pub fn disconnect(&self) -> Result<(), BoxError> {
-        Ok(self
-            .session
-            .method_call(&self.path, DEVICE_INTERFACE, "Disconnect", ())?)
+    /// デバイスの全てのプロパティを一度に取得
+    ///
+    /// `GetAll`を一度だけ呼び出す。セッションのキャッシュが有効な場合は問い合わせを行わない。
+    pub fn info(&self) -> Result<DeviceInfo, BluezError> {
+        self.session
+            .get_all(&self.path, Timeout::resolve(self.timeout)?)
     }
 
-    pub fn connect_profile(&self, value: &str) -> Result<(), BoxError> {
-        Ok(self
-            .session
-            .method_call(&self.path, DEVICE_INTERFACE, "ConnectProfile", (value,))?)
+    /// デバイスに属するgattサービスの一覧を取得
+    pub fn get_gatt_services(&self) -> Result<Vec<GattService>, BluezError> {
+        let paths: Vec<ServicePath> =
+            self.session
+                .get_children(&self.path, "Device", Timeout::resolve(self.timeout)?)?;
+        Ok(paths
+            .iter()
+            .map(|path| GattService::new(&self.session, path))
+            .collect())
     }
 
-    pub fn disconnect_profile(&self, value: &str) -> Result<(), BoxError> {
-        Ok(self
-            .session
-            .method_call(&self.path, DEVICE_INTERFACE, "DisconnectProfile", (value,))?)
+    /// デバイスのプロパティの変更を受信する
+    ///
+    /// 返されたイテレーターが破棄されるまで受信を続ける。
+    pub fn property_changes(&self) -> Result<PropertyChanges<DeviceProperty>, BluezError> {
+        self.session.property_changes(&self.path)
     }
 
-    pub fn pair(&self) -> Result<(), BoxError> {
-        Ok(self
-            .session
-            .method_call(&self.path, DEVICE_INTERFACE, "Pair", ())?)
+    /// デバイスのプロパティの変更を受信するコールバックを登録する
+    ///
+    /// コールバック内で呼び出せるメソッドは`Session::on_event`と同じ。
+    /// 返された`Token`を`Session::remove_callback`に渡すと登録を解除する。
+    pub fn on_property_change<F>(&self, f: F) -> Result<Token, BluezError>
+    where
+        F: FnMut(DeviceProperty) + Send + 'static,
+    {
+        self.session.on_property_change(&self.path, f)
     }
 
-    pub fn cancel_pairing(&self) -> Result<(), BoxError> {
-        Ok(self
-            .session
-            .method_call(&self.path, DEVICE_INTERFACE, "CancelPairing", ())?)
+    fn method_call<R: ReadAll, A: AppendAll>(&self, method: &str, arg: A) -> Result<R, BluezError> {
+        let timeout = Timeout::resolve(self.timeout)?;
+        self.session
+            .method_call(&self.path, DEVICE_INTERFACE, method, arg, timeout)
     }
 
-    fn get_property<A: for<'z> Get<'z>>(&self, property: &str) -> Result<A, BoxError> {
-        Ok(self
-            .session
-            .get_property(&self.path, DEVICE_INTERFACE, property)?)
+    fn get_property<A: FromRefArg>(&self, property: &str) -> Result<A, BluezError> {
+        self.session.get_property(
+            &self.path,
+            DEVICE_INTERFACE,
+            property,
+            Timeout::resolve(self.timeout)?,
+        )
     }
-    fn set_property<T: Append + Arg>(&self, prop: &str, value: T) -> Result<(), BoxError> {
-        Ok(self
-            .session
-            .set_property(&self.path, DEVICE_INTERFACE, prop, value)?)
+    fn set_property<T: Append + Arg>(&self, prop: &str, value: T) -> Result<(), BluezError> {
+        self.session.set_property(
+            &self.path,
+            DEVICE_INTERFACE,
+            prop,
+            value,
+            Timeout::resolve(self.timeout)?,
+        )
     }
 
     //--------------------------------------------------------------------------------
-    // プロパティ
-    // get
-    get_property!(get_address, String, "Address");
-    get_property!(get_name, String, "Name");
-    get_property!(get_icon, String, "Icon");
-    get_property!(get_class, u32, "Class");
-    get_property!(get_appearance, u16, "Appearance");
-    get_property!(get_uuids, Vec<String>, "UUIDs");
-    get_property!(is_paired, bool, "Paired");
-    get_property!(is_connected, bool, "Connected");
-    get_property!(is_trusted, bool, "Trusted");
-    get_property!(is_blocked, bool, "Blocked");
-    get_property!(get_alias, String, "Alias");
-    get_property!(get_adapter, Path, "Adapter");
-    get_property!(is_legacy_pairing, bool, "LegacyPairing");
-    get_property!(get_modalias, String, "Modalias");
-    get_property!(get_rssi, i16, "RSSI");
-    get_property!(get_tx_power, i16, "TxPower");
-    // set
-    set_property!(set_trusted, bool, "Trusted");
-    set_property!(set_blocked, bool, "Blocked");
-    set_property!(set_alias, String, "Alias");
+    // メソッド、プロパティ(interfaces/org.bluez.Device1.xmlから生成)
+    org_bluez_device1!(members: call_method, get_property, set_property);
 }
@@ -0,0 +1,104 @@
+use crate::blocking::Session;
+use crate::*;
+use dbus::channel::Token;
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
+
+/// 切断に気付けるよう、受信待ちはこの間隔で区切る
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// オブジェクトの追加・削除のイベントを受信するイテレーター
+///
+/// `Session::events`で作成する。
+///
+/// ```no_run
+/// use bluez_dbus::blocking::Session;
+/// use bluez_dbus::Event;
+///
+/// let s = Session::new()?;
+/// for event in s.events()? {
+///     if let Event::DeviceAdded { device, .. } = event {
+///         println!("{}", device);
+///     }
+/// }
+/// # Ok::<(), bluez_dbus::BluezError>(())
+/// ```
+pub type Events = Signals<Event>;
+
+/// プロパティの変更を受信するイテレーター
+///
+/// `Device::property_changes`などで作成する。
+///
+/// ```no_run
+/// use bluez_dbus::blocking::{Device, Session};
+/// use bluez_dbus::DeviceProperty;
+///
+/// let s = Session::new()?;
+/// let dev = Device::new(&s, &"/org/bluez/hci0/dev_00_11_22_33_44_55".parse()?);
+/// for change in dev.property_changes()? {
+///     if let DeviceProperty::Rssi(rssi) = change {
+///         println!("{}", rssi);
+///     }
+/// }
+/// # Ok::<(), bluez_dbus::BluezError>(())
+/// ```
+pub type PropertyChanges<P> = Signals<P>;
+
+/// シグナルを受信するイテレーター
+///
+/// `next`は受信するまでブロックし、切断された場合は`None`を返す。
+/// 破棄すると受信を止める。
+pub struct Signals<T> {
+    session: Session,
+    token: Token,
+    rx: Receiver<T>,
+}
+
+impl<T> Signals<T> {
+    pub(in crate) fn new(session: Session, token: Token, rx: Receiver<T>) -> Self {
+        Signals { session, token, rx }
+    }
+
+    /// 指定時間まで受信を待つ
+    ///
+    /// 時間内に受信しなかった場合は`None`を返す。
+    pub fn next_timeout(&mut self, timeout: Duration) -> Result<Option<T>, BluezError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Ok(item) = self.rx.try_recv() {
+                return Ok(Some(item));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            if let Some(reason) = self.session.lost() {
+                return Err(BluezError::ConnectionLost(reason));
+            }
+            let wait = std::cmp::min(deadline - now, POLL_INTERVAL);
+            if let Ok(item) = self.rx.recv_timeout(wait) {
+                return Ok(Some(item));
+            }
+        }
+    }
+}
+
+impl<T> Iterator for Signals<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        loop {
+            match self.next_timeout(POLL_INTERVAL) {
+                Ok(Some(item)) => return Some(item),
+                Ok(None) => continue,
+                Err(_) => return None,
+            }
+        }
+    }
+}
+
+impl<T> Drop for Signals<T> {
+    fn drop(&mut self) {
+        let _ = self.session.remove_callback(self.token);
+    }
+}
@@ -1,42 +1,109 @@
-use crate::blocking::Session;
+use crate::blocking::{Characteristic, Device, PropertyChanges, Session};
 use crate::*;
-use dbus::arg::Get;
-use dbus::strings::Path;
+use dbus::channel::Token;
+use std::time::Duration;
 
 static GATT_SERVICE_INTERFACE: &str = "org.bluez.GattService1";
 
-#[derive(Debug)]
-pub struct GattService<'a> {
-    session: &'a Session,
-    path: String,
+#[derive(Debug, Clone)]
+pub struct GattService {
+    session: Session,
+    path: ServicePath,
+    timeout: Option<Timeout>,
 }
 
-impl<'a> GattService<'a> {
+impl GattService {
     /// Gatt Service作成
-    pub fn new(session: &'a Session, path: &str) -> Self {
+    pub fn new(session: &Session, path: &ServicePath) -> Self {
         GattService {
-            session,
-            path: path.to_string(),
+            session: session.clone(),
+            path: path.clone(),
+            timeout: None,
         }
     }
 
+    /// タイムアウトを指定したGatt Serviceを作成
+    ///
+    /// 作成したGatt Serviceからの呼び出しにはセッションの既定値の代わりにこの値を使用する。
+    pub fn with_timeout(&self, timeout: Duration) -> Self {
+        GattService {
+            session: self.session.clone(),
+            path: self.path.clone(),
+            timeout: Some(Timeout::Duration(timeout)),
+        }
+    }
+
+    /// 期限を指定したGatt Serviceを作成
+    ///
+    /// 各呼び出しのタイムアウトは期限までの残り時間となる。
+    /// 期限を過ぎている場合は`BluezError::Timeout`を返す。
+    pub fn with_deadline(&self, deadline: Deadline) -> Self {
+        GattService {
+            session: self.session.clone(),
+            path: self.path.clone(),
+            timeout: Some(Timeout::Deadline(deadline)),
+        }
+    }
+
+    /// GATTサービスのオブジェクトパスを取得
+    pub fn get_path(&self) -> ServicePath {
+        self.path.clone()
+    }
+
+    /// GATTサービスの全てのプロパティを一度に取得
+    ///
+    /// `GetAll`を一度だけ呼び出す。セッションのキャッシュが有効な場合は問い合わせを行わない。
+    pub fn info(&self) -> Result<GattServiceInfo, BluezError> {
+        self.session
+            .get_all(&self.path, Timeout::resolve(self.timeout)?)
+    }
+
+    /// GATTサービスが属するデバイスを取得
+    ///
+    /// オブジェクトパスから求めるため、BlueZへの問い合わせは行わない。
+    pub fn device(&self) -> Device {
+        Device::new(&self.session, &self.path.device())
+    }
+
     /// Gatt Serviceに属するCharacteristicの一覧を取得
-    pub fn get_characteristics(&self) -> Result<Option<Vec<String>>, BoxError> {
-        self.session.get_children(&self.path, "Service")
+    pub fn get_characteristics(&self) -> Result<Vec<Characteristic>, BluezError> {
+        let paths: Vec<CharacteristicPath> =
+            self.session
+                .get_children(&self.path, "Service", Timeout::resolve(self.timeout)?)?;
+        Ok(paths
+            .iter()
+            .map(|path| Characteristic::new(&self.session, path))
+            .collect())
+    }
+
+    /// GATTサービスのプロパティの変更を受信する
+    ///
+    /// 返されたイテレーターが破棄されるまで受信を続ける。
+    pub fn property_changes(&self) -> Result<PropertyChanges<GattServiceProperty>, BluezError> {
+        self.session.property_changes(&self.path)
+    }
+
+    /// GATTサービスのプロパティの変更を受信するコールバックを登録する
+    ///
+    /// コールバック内で呼び出せるメソッドは`Session::on_event`と同じ。
+    /// 返された`Token`を`Session::remove_callback`に渡すと登録を解除する。
+    pub fn on_property_change<F>(&self, f: F) -> Result<Token, BluezError>
+    where
+        F: FnMut(GattServiceProperty) + Send + 'static,
+    {
+        self.session.on_property_change(&self.path, f)
     }
 
-    fn get_property<A: for<'z> Get<'z>>(&self, property: &str) -> Result<A, BoxError> {
-        Ok(self
-            .session
-            .get_property(&self.path, GATT_SERVICE_INTERFACE, property)?)
+    fn get_property<A: FromRefArg>(&self, property: &str) -> Result<A, BluezError> {
+        self.session.get_property(
+            &self.path,
+            GATT_SERVICE_INTERFACE,
+            property,
+            Timeout::resolve(self.timeout)?,
+        )
     }
 
     //--------------------------------------------------------------------------------
-    // プロパティ
-    // get
-    get_property!(get_uuid, String, "UUID");
-    get_property!(is_primary, bool, "Primary");
-    get_property!(get_device, Path, "Device");
-    // get_property!(get_characteristics, Vec<String>, "Characteristics");
-    get_property!(get_includes, Vec<Path>, "Includes");
+    // メソッド、プロパティ(interfaces/org.bluez.GattService1.xmlから生成)
+    org_bluez_gattservice1!(members: call_method, get_property, set_property);
 }
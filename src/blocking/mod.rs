@@ -1,5 +1,5 @@
 mod session;
-pub use session::Session;
+pub use session::{Session, SessionBuilder};
 
 mod adapter;
 pub use adapter::Adapter;
@@ -16,25 +16,70 @@ pub use characteristic::Characteristic;
 mod descriptor;
 pub use descriptor::Descriptor;
 
+mod connection;
+
+mod events;
+pub use events::{Events, PropertyChanges, Signals};
+
+mod transport;
+pub use transport::Transport;
+
+mod queue;
+pub use queue::{OperationQueue, QueuedCharacteristic, QueuedDevice};
+
+/// メソッド呼び出しの関数を作成するマクロ
+#[doc(hidden)]
+#[macro_export]
+macro_rules! call_method {
+    ($(#[$meta: meta])* $func: ident, $method: expr, ($($arg: ident: $t: ty => $value: expr),*) -> ()) => {
+        $(#[$meta])*
+        pub fn $func(&self, $($arg: $t),*) -> Result<(), BluezError> {
+            self.method_call($method, ($($value,)*))
+        }
+    };
+    ($(#[$meta: meta])* $func: ident, $method: expr, ($($arg: ident: $t: ty => $value: expr),*) -> ($($r: ty),+)) => {
+        $(#[$meta])*
+        pub fn $func(&self, $($arg: $t),*) -> Result<($($r),+), BluezError> {
+            self.method_call($method, ($($value,)*))
+        }
+    };
+    ($(#[$meta: meta])* $func: ident, $method: expr, ($($arg: ident: $t: ty => $value: expr),*) -> $r: ty) => {
+        $(#[$meta])*
+        pub fn $func(&self, $($arg: $t),*) -> Result<$r, BluezError> {
+            let (value,): ($r,) = self.method_call($method, ($($value,)*))?;
+            Ok(value)
+        }
+    };
+}
+
 /// プロパティ取得の関数を作成するマクロ
 #[doc(hidden)]
 #[macro_export]
 macro_rules! get_property {
-    ($func: ident, $t: ty, $prop: expr) => {
-        pub fn $func(&self) -> Result<$t, BoxError> {
+    ($(#[$meta: meta])* $func: ident, optional $t: ty, $prop: expr) => {
+        $(#[$meta])*
+        ///
+        /// BlueZが値を持たず、プロパティが存在しない場合は`Ok(None)`を返す。
+        pub fn $func(&self) -> Result<Option<$t>, BluezError> {
+            $crate::absent_as_none(self.get_property($prop))
+        }
+    };
+    ($(#[$meta: meta])* $func: ident, $t: ty, $prop: expr) => {
+        $(#[$meta])*
+        pub fn $func(&self) -> Result<$t, BluezError> {
             self.get_property($prop)
         }
     }
-    }
+}
 
 /// プロパティ設定の関数を作成するマクロ
 #[doc(hidden)]
 #[macro_export]
 macro_rules! set_property {
-    ($func: ident, $t: ty, $prop: expr) => {
-        pub fn $func(&self, value: $t) -> Result<(), BoxError> {
+    ($(#[$meta: meta])* $func: ident, $t: ty, $prop: expr) => {
+        $(#[$meta])*
+        pub fn $func(&self, value: $t) -> Result<(), BluezError> {
             self.set_property($prop, value)
         }
     }
 }
-
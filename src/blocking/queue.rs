@@ -0,0 +1,212 @@
+use crate::blocking::{Characteristic, Device};
+use crate::*;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+type Lock = Arc<Mutex<()>>;
+
+/// デバイスごとに操作を直列化し、一時的なエラーを再試行するキュー
+///
+/// 同じデバイスに対する操作は、このキュー(と複製したもの)を通したもの同士で一つずつ実行する。
+/// 再試行の待ち時間の間もデバイスの順番は保持したままとなる。
+/// キューを通さない呼び出しとは直列化しない。
+///
+/// ```no_run
+/// use bluez_dbus::blocking::{OperationQueue, Session};
+/// use bluez_dbus::RetryPolicy;
+///
+/// let s = Session::new()?;
+/// let queue = OperationQueue::new(RetryPolicy::default());
+/// for device in s.default_adapter()?.get_devices()? {
+///     let connected = queue.device(&device).connect()?;
+///     println!("{}: connected after {} attempts", device.get_path(), connected.attempts);
+/// }
+/// # Ok::<(), bluez_dbus::BluezError>(())
+/// ```
+#[derive(Clone, Default)]
+pub struct OperationQueue {
+    policy: RetryPolicy,
+    devices: Arc<Mutex<HashMap<DevicePath, Lock>>>,
+}
+
+impl OperationQueue {
+    pub fn new(policy: RetryPolicy) -> Self {
+        OperationQueue {
+            policy,
+            devices: Default::default(),
+        }
+    }
+
+    /// 再試行の方針を取得
+    pub fn policy(&self) -> &RetryPolicy {
+        &self.policy
+    }
+
+    /// キューを通して操作するデバイスを作成
+    pub fn device(&self, device: &Device) -> QueuedDevice {
+        QueuedDevice {
+            queue: self.clone(),
+            device: device.clone(),
+        }
+    }
+
+    /// キューを通して操作するキャラクタリスティックを作成
+    ///
+    /// キャラクタリスティックが属するデバイスの操作と直列化する。
+    pub fn characteristic(&self, characteristic: &Characteristic) -> QueuedCharacteristic {
+        QueuedCharacteristic {
+            queue: self.clone(),
+            characteristic: characteristic.clone(),
+        }
+    }
+
+    /// デバイスの順番を待って`f`を実行する
+    ///
+    /// 一時的なエラーで失敗した場合は方針に従って待ち、`f`を再度呼び出す。
+    /// 成功・失敗のいずれも試行回数を返す。
+    pub fn run<T, F>(
+        &self,
+        device: &DevicePath,
+        mut f: F,
+    ) -> Result<Retried<T>, Retried<BluezError>>
+    where
+        F: FnMut() -> Result<T, BluezError>,
+    {
+        let turn = Turn::acquire(self, device);
+        let _guard = turn.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            match f() {
+                Ok(value) => return Ok(Retried { value, attempts }),
+                Err(err) => match self.policy.backoff(&err, attempts) {
+                    Some(delay) => thread::sleep(delay),
+                    None => {
+                        return Err(Retried {
+                            value: err,
+                            attempts,
+                        })
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// デバイスの順番待ち
+///
+/// 破棄した時点で順番待ちをしているものが無ければロックを削除する。
+/// 操作が`panic`した場合も削除されるようにするため、`Drop`で行う。
+struct Turn<'a> {
+    queue: &'a OperationQueue,
+    device: DevicePath,
+    lock: Lock,
+}
+
+impl<'a> Turn<'a> {
+    fn acquire(queue: &'a OperationQueue, device: &DevicePath) -> Self {
+        let lock = {
+            let mut devices = queue.devices.lock().unwrap();
+            devices.entry(device.clone()).or_default().clone()
+        };
+        Turn {
+            queue,
+            device: device.clone(),
+            lock,
+        }
+    }
+}
+
+impl Drop for Turn<'_> {
+    fn drop(&mut self) {
+        let mut devices = self.queue.devices.lock().unwrap_or_else(|e| e.into_inner());
+        // キューが保持するものと`self.lock`のみであれば、他に待っているものはいない
+        if Arc::strong_count(&self.lock) == 2 {
+            devices.remove(&self.device);
+        }
+    }
+}
+
+/// キューを通して操作するデバイス
+#[derive(Clone)]
+pub struct QueuedDevice {
+    queue: OperationQueue,
+    device: Device,
+}
+
+impl QueuedDevice {
+    /// 元のデバイスを取得
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    /// デバイスの順番を待って`f`を実行する
+    pub fn run<T, F>(&self, mut f: F) -> Result<Retried<T>, Retried<BluezError>>
+    where
+        F: FnMut(&Device) -> Result<T, BluezError>,
+    {
+        self.queue.run(&self.device.get_path(), || f(&self.device))
+    }
+
+    pub fn connect(&self) -> Result<Retried<()>, Retried<BluezError>> {
+        self.run(Device::connect)
+    }
+
+    pub fn disconnect(&self) -> Result<Retried<()>, Retried<BluezError>> {
+        self.run(Device::disconnect)
+    }
+
+    pub fn connect_profile(&self, uuid: &str) -> Result<Retried<()>, Retried<BluezError>> {
+        self.run(|device| device.connect_profile(uuid))
+    }
+
+    pub fn disconnect_profile(&self, uuid: &str) -> Result<Retried<()>, Retried<BluezError>> {
+        self.run(|device| device.disconnect_profile(uuid))
+    }
+
+    pub fn pair(&self) -> Result<Retried<()>, Retried<BluezError>> {
+        self.run(Device::pair)
+    }
+}
+
+/// キューを通して操作するキャラクタリスティック
+#[derive(Clone)]
+pub struct QueuedCharacteristic {
+    queue: OperationQueue,
+    characteristic: Characteristic,
+}
+
+impl QueuedCharacteristic {
+    /// 元のキャラクタリスティックを取得
+    pub fn characteristic(&self) -> &Characteristic {
+        &self.characteristic
+    }
+
+    /// キャラクタリスティックが属するデバイスの順番を待って`f`を実行する
+    pub fn run<T, F>(&self, mut f: F) -> Result<Retried<T>, Retried<BluezError>>
+    where
+        F: FnMut(&Characteristic) -> Result<T, BluezError>,
+    {
+        let device = self.characteristic.get_path().service().device();
+        self.queue.run(&device, || f(&self.characteristic))
+    }
+
+    /// オプションを指定せずに呼び出す
+    pub fn read_value(&self) -> Result<Retried<Vec<u8>>, Retried<BluezError>> {
+        self.run(|characteristic| characteristic.read_value(HashMap::new()))
+    }
+
+    /// オプションを指定せずに呼び出す
+    pub fn write_value(&self, values: Vec<u8>) -> Result<Retried<()>, Retried<BluezError>> {
+        self.run(|characteristic| characteristic.write_value(values.clone(), HashMap::new()))
+    }
+
+    pub fn start_notify(&self) -> Result<Retried<()>, Retried<BluezError>> {
+        self.run(Characteristic::start_notify)
+    }
+
+    pub fn stop_notify(&self) -> Result<Retried<()>, Retried<BluezError>> {
+        self.run(Characteristic::stop_notify)
+    }
+}
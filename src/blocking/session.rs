@@ -1,71 +1,417 @@
+use super::connection::Conn;
+use super::{Adapter, Events, PropertyChanges, Transport};
+use crate::path::parse_sorted;
 use crate::*;
-use dbus::arg::{Append, AppendAll, Arg, Get, ReadAll, Variant};
-use dbus::blocking::Connection;
+use dbus::arg::{Append, AppendAll, Arg, ReadAll, RefArg, Variant};
+use dbus::channel::{Channel, Token};
+use dbus::message::MatchRule;
+use dbus::Message;
+use std::collections::HashMap;
 use std::fmt;
 use std::fmt::Debug;
-use std::sync::{Arc, Mutex};
+use std::sync::{mpsc, Arc, Mutex};
 use std::time::Duration;
 
 static MANAGED_OBJECT_INTERFACE: &str = "org.freedesktop.DBus.ObjectManager";
 static MANAGED_OBJECT_METHOD: &str = "GetManagedObjects";
 
+/// BlueZとのセッション
+///
+/// 複製したものは同じコネクションを共有する。
+/// アダプターやデバイスなどは複製したセッションを保持するため、スレッドに渡したり構造体に保持したりできる。
+///
+/// 送受信は専用のスレッドで行い、応答はシリアル番号で呼び出し元に振り分けるため、
+/// 複数のスレッドから同時にメソッドを呼び出しても、時間のかかる呼び出し(`Device::pair`など)が
+/// 他の呼び出しを待たせることはない。
+#[derive(Clone)]
 pub struct Session {
-    // 複数スレッドでも使えるように`Mutex`を使用している
-    // その分性能を犠牲にしている。
-    conn: Arc<Mutex<Connection>>,
+    // 独自の通信路を使用する場合は`None`
+    conn: Option<Arc<Conn>>,
+    // D-Busのコネクションを使用する場合は`conn`と同じもの
+    transport: Arc<dyn Transport>,
+    handlers: Arc<Mutex<Handlers>>,
+    cache: Option<Arc<Mutex<ObjectCache>>>,
+    service: Arc<str>,
+    timeout: Duration,
+    metrics: Option<Arc<dyn Metrics>>,
+    capabilities: Arc<Mutex<Option<Capabilities>>>,
+    #[cfg(feature = "record")]
+    recorder: Option<Arc<Recorder>>,
 }
 
 impl Debug for Session {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let conn = self.conn.lock().unwrap();
-        write!(f, "Session {{ conn: {} }}", conn.unique_name())
+        let conn = match &self.conn {
+            Some(conn) => conn.unique_name(),
+            None => "(custom transport)".to_string(),
+        };
+        write!(
+            f,
+            "Session {{ conn: {}, service: {}, timeout: {:?} }}",
+            conn, self.service, self.timeout
+        )
+    }
+}
+
+/// `Session`の作成
+///
+/// 接続先のバスやタイムアウトなどを指定してセッションを作成する。
+///
+/// ```no_run
+/// use bluez_dbus::blocking::SessionBuilder;
+/// use bluez_dbus::Bus;
+/// use std::time::Duration;
+///
+/// let session = SessionBuilder::new()
+///     .bus(Bus::Address("unix:path=/tmp/bluez-test".to_string()))
+///     .timeout(Duration::from_secs(30))
+///     .build()?;
+/// # Ok::<(), bluez_dbus::BluezError>(())
+/// ```
+#[derive(Default)]
+pub struct SessionBuilder {
+    bus: Bus,
+    channel: Option<Channel>,
+    transport: Option<Arc<dyn Transport>>,
+    service: Option<String>,
+    timeout: Option<Duration>,
+    cache: bool,
+    metrics: Option<Arc<dyn Metrics>>,
+    #[cfg(feature = "record")]
+    recorder: Option<Recorder>,
+}
+
+impl SessionBuilder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// 接続するバスを指定(既定値はシステムバス)
+    pub fn bus(mut self, bus: Bus) -> Self {
+        self.bus = bus;
+        self
+    }
+
+    /// 接続済みのチャネルを使用する
+    ///
+    /// 指定した場合は`bus`の指定は無視される。
+    /// チャネルの送受信はセッションが作成するスレッドで行う。
+    pub fn channel(mut self, channel: Channel) -> Self {
+        self.channel = Some(channel);
+        self
+    }
+
+    /// D-Busのコネクションの代わりに使用する通信路を指定する
+    ///
+    /// 指定した場合は`bus`、`channel`の指定は無視される。
+    /// シグナルの受信(`events`やコールバックの登録など)は`BluezError::Transport`で失敗し、
+    /// キャッシュは使用しない。
+    pub fn transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.transport = Some(transport);
+        self
+    }
+
+    /// BlueZのサービス名を指定(既定値は`org.bluez`)
+    pub fn service(mut self, service: &str) -> Self {
+        self.service = Some(service.to_string());
+        self
+    }
+
+    /// メソッド呼び出しのタイムアウトを指定(既定値は10秒)
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// オブジェクトのキャッシュを使用する
+    ///
+    /// 有効にすると`GetManagedObjects`の結果をキャッシュし、シグナルを受信して最新の状態に保つ。
+    /// アダプターやデバイスなどの一覧の取得で毎回`GetManagedObjects`を呼び出さなくなる。
+    /// キャッシュは最初の一覧の取得時に作成し、その後に受信したシグナルは受信した時点で反映する。
+    pub fn cache(mut self, enabled: bool) -> Self {
+        self.cache = enabled;
+        self
+    }
+
+    /// BlueZの呼び出しの計測を行う
+    ///
+    /// メソッド呼び出し、プロパティの取得・設定、`GetManagedObjects`の完了ごとに`Metrics::record`を呼ぶ。
+    /// キャッシュから応答した場合は呼ばれない。
+    pub fn metrics(mut self, metrics: Arc<dyn Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// D-Busの通信を記録する
+    ///
+    /// メソッド呼び出し、応答、エラー、受信したシグナルを書き出す。
+    /// 独自の通信路を指定した場合はメソッド呼び出しと応答のみを記録する。
+    #[cfg(feature = "record")]
+    pub fn record(mut self, recorder: Recorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// セッションの作成
+    pub fn build(self) -> Result<Session, BluezError> {
+        let service: Arc<str> = self.service.as_deref().unwrap_or(BLUEZ_SERVICE).into();
+        let timeout = self.timeout.unwrap_or(DEFAULT_TIMEOUT);
+        let handlers = Arc::new(Mutex::new(Handlers::default()));
+        #[cfg(feature = "record")]
+        let recorder = self.recorder.map(Arc::new);
+        if let Some(transport) = self.transport {
+            return Ok(Session {
+                conn: None,
+                transport,
+                handlers,
+                cache: None,
+                service,
+                timeout,
+                metrics: self.metrics,
+                capabilities: Default::default(),
+                #[cfg(feature = "record")]
+                recorder,
+            });
+        }
+
+        let channel = match self.channel {
+            Some(channel) => channel,
+            None => self.bus.open_channel()?,
+        };
+        let conn = Arc::new(Conn::new(channel)?);
+        {
+            let handlers = handlers.clone();
+            conn.start_receive(Handlers::rule(), move |msg| {
+                Handlers::dispatch(&handlers, msg)
+            });
+        }
+        #[cfg(feature = "record")]
+        if let Some(recorder) = &recorder {
+            let recorder = recorder.clone();
+            handlers
+                .lock()
+                .unwrap()
+                .tap(move |msg| recorder.signal(msg));
+        }
+        let cache = if self.cache {
+            let cache = Arc::new(Mutex::new(ObjectCache::new(&service)));
+            let rules = cache.lock().unwrap().match_rules();
+            for rule in rules {
+                conn.add_match(&rule, timeout)?;
+                let cache = cache.clone();
+                handlers
+                    .lock()
+                    .unwrap()
+                    .add(rule, move |msg| cache.lock().unwrap().handle(msg));
+            }
+            Some(cache)
+        } else {
+            None
+        };
+        Ok(Session {
+            conn: Some(conn.clone()),
+            transport: conn,
+            handlers,
+            cache,
+            service,
+            timeout,
+            metrics: self.metrics,
+            capabilities: Default::default(),
+            #[cfg(feature = "record")]
+            recorder,
+        })
     }
 }
 
 /// BlueZとの通信を行うセッション
 impl Session {
     /// BlueZとの通信を行うセッションの作成
-    pub fn new() -> Result<Self, BoxError> {
-        let conn = Connection::new_system()?;
-        Ok(Session {
-            conn: Arc::new(Mutex::new(conn)),
-        })
+    ///
+    /// システムバスの`org.bluez`に接続する。
+    /// 接続先などを変更する場合は`SessionBuilder`を使用する。
+    pub fn new() -> Result<Self, BluezError> {
+        SessionBuilder::new().build()
+    }
+
+    /// `SessionBuilder`の作成
+    pub fn builder() -> SessionBuilder {
+        SessionBuilder::new()
     }
 
     /// bluetoothアダプターの一覧を取得
-    pub fn get_adapters(&self) -> Result<Option<Vec<String>>, BoxError> {
-        let objects = self.get_managed_objects()?;
+    ///
+    /// パスの順に並べる。アダプターが無い場合は空の`Vec`を返す。
+    pub fn get_adapters(&self) -> Result<Vec<Adapter>, BluezError> {
+        let paths = match self.with_cache(None, |cache| cache.objects_with(ADAPTER_INTERFACE))? {
+            Some(paths) => paths,
+            None => self
+                .get_managed_objects(None)?
+                .iter()
+                .filter(|(_, value)| value.contains_key(ADAPTER_INTERFACE))
+                .map(|(key, _)| key.to_string())
+                .collect(),
+        };
+        Ok(parse_sorted(paths)
+            .iter()
+            .map(|path| Adapter::new(self, path))
+            .collect())
+    }
 
-        let adapters: Vec<String> = objects
+    /// 既定のアダプターを取得
+    ///
+    /// パスの順で最初のアダプター(通常は`/org/bluez/hci0`)を返す。
+    /// アダプターが無い場合は`BluezError::DoesNotExist`を返す。
+    pub fn default_adapter(&self) -> Result<Adapter, BluezError> {
+        self.get_adapters()?
+            .into_iter()
+            .next()
+            .ok_or_else(|| BluezError::DoesNotExist("no bluetooth adapter".to_string()))
+    }
+
+    /// 接続先のBlueZが提供するインターフェース、メソッド、プロパティを調べる
+    ///
+    /// `/org/bluez`と、`GetManagedObjects`で得たオブジェクトのうち未知のインターフェースを持つものを
+    /// イントロスペクションし、存在するメンバーからBlueZのバージョンを推定する。
+    /// アダプターが存在する場合は結果をセッション(と複製したもの)で保持し、二回目以降は問い合わせを行わない。
+    pub fn capabilities(&self) -> Result<Capabilities, BluezError> {
+        if let Some(capabilities) = self.capabilities.lock().unwrap().clone() {
+            return Ok(capabilities);
+        }
+        let objects = self.get_managed_objects(None)?;
+        let xmls = Capabilities::introspection_paths(&objects)
             .iter()
-            .filter_map(|(key, value)| {
-                if value.contains_key(ADAPTER_INTERFACE) {
-                    return Some(key.to_string());
-                }
-                None
+            .map(|path| {
+                let (xml,): (String,) =
+                    self.method_call(path, INTROSPECTABLE_INTERFACE, "Introspect", (), None)?;
+                Ok(xml)
             })
-            .collect();
+            .collect::<Result<Vec<_>, BluezError>>()?;
+        let capabilities = Capabilities::from_introspection(&xmls, &objects)?;
+        if capabilities.has_interface(ADAPTER_INTERFACE) {
+            *self.capabilities.lock().unwrap() = Some(capabilities.clone());
+        }
+        Ok(capabilities)
+    }
 
-        if adapters.is_empty() {
-            Ok(None)
-        } else {
-            Ok(Some(adapters))
+    /// オブジェクトの追加・削除のイベントを受信する
+    ///
+    /// 返されたイテレーターが破棄されるまで受信を続ける。
+    pub fn events(&self) -> Result<Events, BluezError> {
+        let (tx, rx) = mpsc::channel();
+        let token = self.add_match(Event::match_rule(&self.service), move |msg| {
+            for event in Event::from_message(msg) {
+                let _ = tx.send(event);
+            }
+        })?;
+        Ok(Events::new(self.clone(), token, rx))
+    }
+
+    /// オブジェクトの追加・削除のイベントを受信するコールバックを登録する
+    ///
+    /// コールバックは送受信を行うスレッドで呼ばれる。
+    /// コールバック内で`remove_callback`を呼び出して登録を解除できるが、
+    /// コールバックが終わるまで応答を受信できないため、応答を待つメソッド(`events`やプロパティの取得など)を
+    /// 呼び出してはならない(タイムアウトまで待たされる)。
+    /// 返された`Token`を`remove_callback`に渡すと登録を解除する。
+    pub fn on_event<F>(&self, mut f: F) -> Result<Token, BluezError>
+    where
+        F: FnMut(Event) + Send + 'static,
+    {
+        self.add_match(Event::match_rule(&self.service), move |msg| {
+            Event::from_message(msg).into_iter().for_each(&mut f)
+        })
+    }
+
+    /// 登録したコールバックを解除する
+    ///
+    /// 登録されていない場合は何もしない。
+    pub fn remove_callback(&self, token: Token) -> Result<(), BluezError> {
+        let conn = self.connection()?;
+        let rule = self.handlers.lock().unwrap().remove(token);
+        if let Some(rule) = rule {
+            conn.remove_match(&rule)?;
         }
+        Ok(())
+    }
+
+    /// 指定のオブジェクトのプロパティの変更を受信する
+    pub(in crate) fn property_changes<P: PropertyChange>(
+        &self,
+        path: &str,
+    ) -> Result<PropertyChanges<P>, BluezError> {
+        let (tx, rx) = mpsc::channel();
+        let token = self.add_match(P::match_rule(&self.service, path), move |msg| {
+            for change in P::from_message(msg) {
+                let _ = tx.send(change);
+            }
+        })?;
+        Ok(PropertyChanges::new(self.clone(), token, rx))
+    }
+
+    /// 指定のオブジェクトのプロパティの変更を受信するコールバックを登録する
+    pub(in crate) fn on_property_change<P, F>(&self, path: &str, mut f: F) -> Result<Token, BluezError>
+    where
+        P: PropertyChange,
+        F: FnMut(P) + Send + 'static,
+    {
+        self.add_match(P::match_rule(&self.service, path), move |msg| {
+            P::from_message(msg).into_iter().for_each(&mut f)
+        })
     }
 
-    pub(in crate) fn get_property<A: for<'z> Get<'z>>(
+    /// コネクションが切断されていれば切断理由を返す
+    ///
+    /// 独自の通信路を使用する場合は常に`None`を返す。
+    pub(in crate) fn lost(&self) -> Option<String> {
+        self.conn.as_ref().and_then(|conn| conn.lost())
+    }
+
+    pub(in crate) fn get_property<A: FromRefArg>(
         &self,
         path: &str,
         interface: &str,
         property: &str,
-    ) -> Result<A, BoxError> {
-        let (value,): (Variant<A>,) = self.method_call(
-            path,
-            "org.freedesktop.DBus.Properties",
-            "Get",
-            (interface, property.to_string()),
-        )?;
-        Ok(value.0)
+        timeout: Option<Duration>,
+    ) -> Result<A, BluezError> {
+        let (value,): (Variant<Box<dyn RefArg>>,) =
+            self.traced(path, interface, "Get", Some(property), || {
+                self.call(
+                    path,
+                    PROPERTIES_INTERFACE,
+                    "Get",
+                    (interface, property.to_string()),
+                    timeout,
+                )
+            })?;
+        A::from_refarg(&*value.0).map_err(|e| {
+            BluezError::TypeMismatch(e.describe(&format!("{}.{}", interface, property)))
+        })
+    }
+
+    /// インターフェースの全てのプロパティを取得
+    ///
+    /// キャッシュが有効な場合はキャッシュから作成し、BlueZへの問い合わせは行わない。
+    pub(in crate) fn get_all<I: PropertyInfo>(
+        &self,
+        path: &str,
+        timeout: Option<Duration>,
+    ) -> Result<I, BluezError> {
+        if let Some(cached) = self.with_cache(timeout, |cache| {
+            cache.properties(path, I::INTERFACE).map(I::from_properties)
+        })? {
+            return cached.unwrap_or_else(|| Err(BluezError::ObjectVanished(path.to_string())));
+        }
+        let (properties,): (HashMap<String, Variant<Box<dyn RefArg>>>,) =
+            self.traced(path, I::INTERFACE, "GetAll", None, || {
+                self.call(
+                    path,
+                    PROPERTIES_INTERFACE,
+                    "GetAll",
+                    (I::INTERFACE,),
+                    timeout,
+                )
+            })?;
+        I::from_properties(&Properties::from(properties))
     }
 
     pub(in crate) fn set_property<A: Append + Arg>(
@@ -74,15 +420,18 @@ impl Session {
         interface: &str,
         property: &str,
         value: A,
-    ) -> Result<(), BoxError> {
+        timeout: Option<Duration>,
+    ) -> Result<(), BluezError> {
         let value = Variant(value);
-        let _ = self.method_call(
-            path,
-            "org.freedesktop.DBus.Properties",
-            "Set",
-            (interface, property.to_string(), value),
-        )?;
-        Ok(())
+        self.traced(path, interface, "Set", Some(property), || {
+            self.call(
+                path,
+                PROPERTIES_INTERFACE,
+                "Set",
+                (interface, property.to_string(), value),
+                timeout,
+            )
+        })
     }
 
     /// BlueZに対するメソッド実行
@@ -92,48 +441,191 @@ impl Session {
         interface: &str,
         method: &str,
         arg: A,
-    ) -> Result<R, dbus::Error> {
-        let conn = self.conn.lock().unwrap();
-        let proxy = conn.with_proxy(BLUEZ_SERVICE, path, Duration::from_secs(10));
-        proxy.method_call(interface, method, arg)
+        timeout: Option<Duration>,
+    ) -> Result<R, BluezError> {
+        self.traced(path, interface, method, None, || {
+            self.call(path, interface, method, arg, timeout)
+        })
     }
 
-    /// 指定のパス配下の子要素の一覧を取得
-    pub(in crate) fn get_children(
+    /// メソッドが存在しない場合は`BluezError::Unsupported`で失敗する
+    pub(in crate) fn require_method(&self, interface: &str, method: &str) -> Result<(), BluezError> {
+        self.capabilities()?.require_method(interface, method)
+    }
+
+    /// 計測を行いながら`f`を実行する
+    fn traced<R>(
         &self,
         path: &str,
-        prop: &str,
-    ) -> Result<Option<Vec<String>>, BoxError> {
-        let objects = self.get_managed_objects()?;
+        interface: &str,
+        member: &str,
+        property: Option<&str>,
+        f: impl FnOnce() -> Result<R, BluezError>,
+    ) -> Result<R, BluezError> {
+        let call = Call::start(self.metrics.clone(), path, interface, member, property);
+        let result = call.in_scope(f);
+        call.finish(&result);
+        result
+    }
 
-        let devices: Vec<String> = objects
-            .iter()
-            .filter_map(|(key, value)| {
-                if is_match(path, prop, value) {
-                    Some(key.to_string())
-                } else {
-                    None
-                }
-            })
-            .collect();
-        if devices.is_empty() {
-            Ok(None)
-        } else {
-            Ok(Some(devices))
+    /// 計測を行わずにメソッドを実行する
+    fn call<R: ReadAll, A: AppendAll>(
+        &self,
+        path: &str,
+        interface: &str,
+        method: &str,
+        arg: A,
+        timeout: Option<Duration>,
+    ) -> Result<R, BluezError> {
+        let mut msg = Message::new_method_call(&*self.service, path, interface, method)
+            .map_err(|e| BluezError::DBus(dbus::Error::new_failed(&e)))?;
+        msg.append_all(arg);
+        #[cfg(feature = "record")]
+        let id = self.recorder.as_ref().map(|recorder| recorder.call(&msg));
+        let reply = self.transport.call(msg, timeout.unwrap_or(self.timeout));
+        #[cfg(feature = "record")]
+        if let (Some(recorder), Some(id)) = (&self.recorder, id) {
+            recorder.reply(id, &reply);
         }
+        Ok(reply?.read_all()?)
     }
 
-    pub(in crate) fn get_managed_objects(&self) -> Result<ManagedObject, BoxError> {
-        let (managed_objects,): (ManagedObject,) =
-            self.method_call("/", MANAGED_OBJECT_INTERFACE, MANAGED_OBJECT_METHOD, ())?;
+    /// 指定のパス配下の子要素のパスの一覧を取得
+    ///
+    /// パスの順に並べる。子要素が無い場合は空の`Vec`を返す。
+    pub(in crate) fn get_children<P: std::str::FromStr>(
+        &self,
+        path: &str,
+        prop: &str,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<P>, BluezError> {
+        let paths = match self.with_cache(timeout, |cache| cache.children(path, prop))? {
+            Some(paths) => paths,
+            None => self
+                .get_managed_objects(timeout)?
+                .iter()
+                .filter(|(_, value)| is_match(path, prop, value))
+                .map(|(key, _)| key.to_string())
+                .collect(),
+        };
+        Ok(parse_sorted(paths))
+    }
+
+    pub(in crate) fn get_managed_objects(
+        &self,
+        timeout: Option<Duration>,
+    ) -> Result<ManagedObject, BluezError> {
+        if let Some(objects) = self.with_cache(timeout, |cache| cache.managed_objects())? {
+            return Ok(objects);
+        }
+        let (managed_objects,): (ManagedObject,) = self.method_call(
+            "/",
+            MANAGED_OBJECT_INTERFACE,
+            MANAGED_OBJECT_METHOD,
+            (),
+            timeout,
+        )?;
         Ok(managed_objects)
     }
+
+    /// シグナルを受信するルールを追加する
+    fn add_match<F>(&self, rule: MatchRule<'static>, f: F) -> Result<Token, BluezError>
+    where
+        F: FnMut(&Message) + Send + 'static,
+    {
+        let conn = self.connection()?;
+        conn.add_match(&rule, self.timeout)?;
+        Ok(self.handlers.lock().unwrap().add(rule, f))
+    }
+
+    /// シグナルの受信に使用するコネクションを取得する
+    ///
+    /// 独自の通信路を使用する場合は`BluezError::Transport`を返す。
+    fn connection(&self) -> Result<&Conn, BluezError> {
+        match &self.conn {
+            Some(conn) => Ok(conn),
+            None => Err(BluezError::Transport(dbus::Error::new_failed(
+                "signals are not available with a custom transport",
+            ))),
+        }
+    }
+
+    /// キャッシュを最新の状態にしてから参照する
+    ///
+    /// キャッシュを使用しない場合は`None`を返す。
+    fn with_cache<R>(
+        &self,
+        timeout: Option<Duration>,
+        f: impl FnOnce(&ObjectCache) -> R,
+    ) -> Result<Option<R>, BluezError> {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return Ok(None),
+        };
+        let conn = self.connection()?;
+        if !cache.lock().unwrap().is_seeded() {
+            let msg = Message::new_method_call(
+                &*self.service,
+                "/",
+                MANAGED_OBJECT_INTERFACE,
+                MANAGED_OBJECT_METHOD,
+            )
+            .map_err(|e| BluezError::DBus(dbus::Error::new_failed(&e)))?;
+            // シグナルより先に反映するため、応答を受信したスレッドで格納する
+            let seed = cache.clone();
+            self.send_with_reply(conn, msg, timeout, move |reply| {
+                let (objects,): (ManagedObject,) = reply.read_all()?;
+                let serial = reply.get_serial().unwrap_or(0);
+                seed.lock().unwrap().seed(serial, objects);
+                Ok(())
+            })?;
+        }
+        let cache = cache.lock().unwrap();
+        Ok(Some(f(&cache)))
+    }
+
+    /// メソッド呼び出しのメッセージを送信し、応答を待つ
+    ///
+    /// `f`は応答を受信したスレッドで呼ばれる。
+    fn send_with_reply<T, F>(
+        &self,
+        conn: &Conn,
+        msg: Message,
+        timeout: Option<Duration>,
+        f: F,
+    ) -> Result<T, BluezError>
+    where
+        T: Send + 'static,
+        F: FnOnce(Message) -> Result<T, BluezError> + Send + 'static,
+    {
+        #[cfg(feature = "record")]
+        let record = self
+            .recorder
+            .as_ref()
+            .map(|recorder| (recorder.clone(), recorder.call(&msg)));
+        #[cfg(feature = "record")]
+        let on_reply = record.clone();
+        let result = conn.call_with(msg, timeout.unwrap_or(self.timeout), move |reply| {
+            let reply = Ok(reply);
+            #[cfg(feature = "record")]
+            if let Some((recorder, id)) = on_reply {
+                recorder.reply(id, &reply);
+            }
+            // 応答の処理の失敗は記録済みの応答から再現できるため、エラーとして記録しない
+            reply.map(f)
+        });
+        #[cfg(feature = "record")]
+        if let (Some((recorder, id)), Err(err)) = (record, &result) {
+            recorder.error(id, err);
+        }
+        result?
+    }
 }
 
 fn is_match(path: &str, prop: &str, info: &ManagedObjectInterfaces) -> bool {
     info.iter().any(|(_key, value)| {
-        if let Some(s) = value.get_str(prop) {
-            return s == path;
+        if let Some(s) = value.get_as::<dbus::Path>(prop) {
+            return &*s == path;
         }
         false
     })
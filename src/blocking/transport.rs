@@ -0,0 +1,37 @@
+use crate::*;
+use dbus::blocking::{BlockingSender, Connection};
+use dbus::Message;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// BlueZとの通信路
+///
+/// `Session`のメソッド呼び出し(`method_call`、`get_property`、`set_property`、
+/// `get_managed_objects`)は全てこのトレイトを通して行う。
+/// 既定ではD-Busのコネクションを使用し、`FakeBluez`などに差し替えるとD-Busなしでテストできる。
+///
+/// ```
+/// use bluez_dbus::blocking::{Adapter, Session};
+/// use bluez_dbus::FakeBluez;
+/// use std::sync::Arc;
+///
+/// let fake = FakeBluez::new();
+/// fake.set_property("/org/bluez/hci0", "org.bluez.Adapter1", "Powered", true);
+/// let s = Session::builder().transport(Arc::new(fake.clone())).build()?;
+/// let adapter = Adapter::create(&s, &"/org/bluez/hci0".parse()?)?.unwrap();
+/// assert!(adapter.is_powered()?);
+/// # Ok::<(), bluez_dbus::BluezError>(())
+/// ```
+pub trait Transport: Send + Sync {
+    /// メソッド呼び出しのメッセージを送信し、応答を待つ
+    ///
+    /// エラーの応答は`Err`で返す。
+    fn call(&self, msg: Message, timeout: Duration) -> Result<Message, BluezError>;
+}
+
+impl Transport for Mutex<Connection> {
+    fn call(&self, msg: Message, timeout: Duration) -> Result<Message, BluezError> {
+        let conn = self.lock().unwrap();
+        Ok(conn.send_with_reply_and_block(msg, timeout)?)
+    }
+}
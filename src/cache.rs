@@ -0,0 +1,342 @@
+use crate::*;
+use dbus::arg::{RefArg, Variant};
+use dbus::message::MatchRule;
+use dbus::Message;
+use std::collections::{BTreeSet, HashMap};
+
+static DBUS_SERVICE: &str = "org.freedesktop.DBus";
+
+/// 親を持つインターフェースと、親のパスを示すプロパティ
+static PARENT_PROPERTIES: [(&str, &str); 4] = [
+    ("org.bluez.Device1", "Adapter"),
+    ("org.bluez.GattService1", "Device"),
+    ("org.bluez.GattCharacteristic1", "Service"),
+    ("org.bluez.GattDescriptor1", "Characteristic"),
+];
+
+/// `GetManagedObjects`の結果のキャッシュ
+///
+/// `InterfacesAdded`、`InterfacesRemoved`、`PropertiesChanged`のシグナルで最新の状態に保つ。
+/// 取得時の応答より前に送信されたシグナルは取得結果に含まれているため、
+/// シリアル番号が応答以前のシグナルは無視する。
+pub(in crate) struct ObjectCache {
+    service: String,
+    /// 取得時の応答のシリアル番号(未取得の場合は`None`)
+    serial: Option<u32>,
+    objects: HashMap<String, HashMap<String, Properties>>,
+    /// インターフェースごとのオブジェクト
+    interfaces: HashMap<String, BTreeSet<String>>,
+    /// 親のパスとインターフェースごとの子要素
+    children: HashMap<(String, String), BTreeSet<String>>,
+}
+
+impl ObjectCache {
+    pub(in crate) fn new(service: &str) -> Self {
+        ObjectCache {
+            service: service.to_string(),
+            serial: None,
+            objects: HashMap::new(),
+            interfaces: HashMap::new(),
+            children: HashMap::new(),
+        }
+    }
+
+    /// キャッシュの更新に必要なシグナルの一覧
+    pub(in crate) fn match_rules(&self) -> Vec<MatchRule<'static>> {
+        vec![
+            signal(&self.service, OBJECT_MANAGER_INTERFACE, "InterfacesAdded"),
+            signal(&self.service, OBJECT_MANAGER_INTERFACE, "InterfacesRemoved"),
+            signal(&self.service, PROPERTIES_INTERFACE, "PropertiesChanged"),
+            signal(DBUS_SERVICE, DBUS_SERVICE, "NameOwnerChanged"),
+        ]
+    }
+
+    /// 取得済みかどうか
+    pub(in crate) fn is_seeded(&self) -> bool {
+        self.serial.is_some()
+    }
+
+    /// `GetManagedObjects`の結果で初期化する
+    pub(in crate) fn seed(&mut self, serial: u32, objects: ManagedObject) {
+        self.reset();
+        self.serial = Some(serial);
+        for (path, interfaces) in objects {
+            self.add_interfaces(&path, interfaces);
+        }
+    }
+
+    /// 未取得の状態に戻す
+    pub(in crate) fn reset(&mut self) {
+        self.serial = None;
+        self.objects.clear();
+        self.interfaces.clear();
+        self.children.clear();
+    }
+
+    /// 受信したシグナルを反映する
+    pub(in crate) fn handle(&mut self, msg: &Message) {
+        let member = match msg.member() {
+            Some(member) => member,
+            None => return,
+        };
+        if &*member == "NameOwnerChanged" {
+            // BlueZが再起動した場合はシリアル番号が振り直されるため、取得し直す
+            if let Ok((name, _, _)) = msg.read3::<&str, &str, &str>() {
+                if name == self.service {
+                    self.reset();
+                }
+            }
+            return;
+        }
+        if !self.is_new(msg.get_serial().unwrap_or(0)) {
+            return;
+        }
+        match &*member {
+            "InterfacesAdded" => {
+                if let Ok((path, interfaces)) = msg.read2::<dbus::Path, ManagedObjectInterfaces>() {
+                    self.add_interfaces(&path, interfaces);
+                }
+            }
+            "InterfacesRemoved" => {
+                if let Ok((path, interfaces)) = msg.read2::<dbus::Path, Vec<String>>() {
+                    for interface in interfaces {
+                        self.remove_interface(&path, &interface);
+                    }
+                }
+            }
+            "PropertiesChanged" => {
+                let path = match msg.path() {
+                    Some(path) => path.to_string(),
+                    None => return,
+                };
+                if let Ok((interface, changed, invalidated)) =
+                    msg.read3::<String, HashMap<String, Variant<Box<dyn RefArg>>>, Vec<String>>()
+                {
+                    self.change_properties(&path, &interface, changed, &invalidated);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// 指定のインターフェースを持つオブジェクトの一覧
+    pub(in crate) fn objects_with(&self, interface: &str) -> Vec<String> {
+        self.interfaces
+            .get(interface)
+            .map(|paths| paths.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// 親のパスを示すプロパティが`path`である子要素の一覧
+    pub(in crate) fn children(&self, path: &str, prop: &str) -> Vec<String> {
+        PARENT_PROPERTIES
+            .iter()
+            .filter(|(_, p)| *p == prop)
+            .filter_map(|(interface, _)| {
+                self.children
+                    .get(&(path.to_string(), interface.to_string()))
+            })
+            .flatten()
+            .cloned()
+            .collect()
+    }
+
+    /// オブジェクトの指定のインターフェースのプロパティ
+    pub(in crate) fn properties(&self, path: &str, interface: &str) -> Option<&Properties> {
+        self.objects.get(path)?.get(interface)
+    }
+
+    /// `GetManagedObjects`の結果と同じ形式で取得
+    pub(in crate) fn managed_objects(&self) -> ManagedObject {
+        self.objects
+            .iter()
+            .map(|(path, interfaces)| {
+                let interfaces = interfaces
+                    .iter()
+                    .map(|(interface, props)| (interface.clone(), props.to_variants()))
+                    .collect();
+                (dbus::Path::from(path.clone()), interfaces)
+            })
+            .collect()
+    }
+
+    fn is_new(&self, serial: u32) -> bool {
+        self.serial.map(|seeded| serial > seeded).unwrap_or(false)
+    }
+
+    fn add_interfaces(&mut self, path: &str, interfaces: ManagedObjectInterfaces) {
+        for (interface, props) in interfaces {
+            self.unindex(path, &interface);
+            self.objects
+                .entry(path.to_string())
+                .or_default()
+                .insert(interface.clone(), props.into());
+            self.index(path, &interface);
+        }
+    }
+
+    fn remove_interface(&mut self, path: &str, interface: &str) {
+        self.unindex(path, interface);
+        if let Some(interfaces) = self.objects.get_mut(path) {
+            interfaces.remove(interface);
+            if interfaces.is_empty() {
+                self.objects.remove(path);
+            }
+        }
+    }
+
+    fn change_properties(
+        &mut self,
+        path: &str,
+        interface: &str,
+        changed: HashMap<String, Variant<Box<dyn RefArg>>>,
+        invalidated: &[String],
+    ) {
+        if !self
+            .objects
+            .get(path)
+            .map(|interfaces| interfaces.contains_key(interface))
+            .unwrap_or(false)
+        {
+            return;
+        }
+        self.unindex(path, interface);
+        if let Some(props) = self
+            .objects
+            .get_mut(path)
+            .and_then(|interfaces| interfaces.get_mut(interface))
+        {
+            props.update(changed, invalidated);
+        }
+        self.index(path, interface);
+    }
+
+    fn parent(&self, path: &str, interface: &str) -> Option<String> {
+        let (_, prop) = PARENT_PROPERTIES.iter().find(|(i, _)| *i == interface)?;
+        let props = self.objects.get(path)?.get(interface)?;
+        props.get_str(prop).map(|s| s.to_string())
+    }
+
+    fn index(&mut self, path: &str, interface: &str) {
+        self.interfaces
+            .entry(interface.to_string())
+            .or_default()
+            .insert(path.to_string());
+        if let Some(parent) = self.parent(path, interface) {
+            self.children
+                .entry((parent, interface.to_string()))
+                .or_default()
+                .insert(path.to_string());
+        }
+    }
+
+    fn unindex(&mut self, path: &str, interface: &str) {
+        if let Some(paths) = self.interfaces.get_mut(interface) {
+            paths.remove(path);
+            if paths.is_empty() {
+                self.interfaces.remove(interface);
+            }
+        }
+        if let Some(parent) = self.parent(path, interface) {
+            let key = (parent, interface.to_string());
+            if let Some(children) = self.children.get_mut(&key) {
+                children.remove(path);
+                if children.is_empty() {
+                    self.children.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interfaces(interface: &str, props: &[(&str, &str)]) -> ManagedObjectInterfaces {
+        let props = props
+            .iter()
+            .map(|(name, value)| {
+                let value: Box<dyn RefArg> = Box::new(dbus::Path::from(value.to_string()));
+                (name.to_string(), Variant(value))
+            })
+            .collect();
+        let mut interfaces = HashMap::new();
+        interfaces.insert(interface.to_string(), props);
+        interfaces
+    }
+
+    fn signal(serial: u32, member: &str) -> Message {
+        let mut msg = Message::signal(
+            &"/".into(),
+            &OBJECT_MANAGER_INTERFACE.into(),
+            &member.into(),
+        );
+        msg.set_serial(serial);
+        msg
+    }
+
+    fn seeded() -> ObjectCache {
+        let mut objects = ManagedObject::new();
+        objects.insert("/org/bluez/hci0".into(), interfaces(ADAPTER_INTERFACE, &[]));
+        objects.insert(
+            "/org/bluez/hci0/dev_00".into(),
+            interfaces("org.bluez.Device1", &[("Adapter", "/org/bluez/hci0")]),
+        );
+        let mut cache = ObjectCache::new(BLUEZ_SERVICE);
+        cache.seed(10, objects);
+        cache
+    }
+
+    #[test]
+    fn index_children() {
+        let mut cache = seeded();
+        assert_eq!(
+            cache.objects_with(ADAPTER_INTERFACE),
+            vec!["/org/bluez/hci0"]
+        );
+        assert_eq!(
+            cache.children("/org/bluez/hci0", "Adapter"),
+            vec!["/org/bluez/hci0/dev_00"]
+        );
+
+        let msg = signal(11, "InterfacesAdded").append2(
+            dbus::Path::from("/org/bluez/hci0/dev_01"),
+            interfaces("org.bluez.Device1", &[("Adapter", "/org/bluez/hci0")]),
+        );
+        cache.handle(&msg);
+        assert_eq!(cache.children("/org/bluez/hci0", "Adapter").len(), 2);
+
+        let msg = signal(12, "InterfacesRemoved").append2(
+            dbus::Path::from("/org/bluez/hci0/dev_00"),
+            vec!["org.bluez.Device1".to_string()],
+        );
+        cache.handle(&msg);
+        assert_eq!(
+            cache.children("/org/bluez/hci0", "Adapter"),
+            vec!["/org/bluez/hci0/dev_01"]
+        );
+        assert_eq!(cache.managed_objects().len(), 2);
+    }
+
+    #[test]
+    fn ignore_old_signals() {
+        let mut cache = seeded();
+        let msg = signal(9, "InterfacesRemoved").append2(
+            dbus::Path::from("/org/bluez/hci0/dev_00"),
+            vec!["org.bluez.Device1".to_string()],
+        );
+        cache.handle(&msg);
+        assert_eq!(cache.children("/org/bluez/hci0", "Adapter").len(), 1);
+
+        let mut msg = Message::signal(
+            &"/org/freedesktop/DBus".into(),
+            &DBUS_SERVICE.into(),
+            &"NameOwnerChanged".into(),
+        )
+        .append3(BLUEZ_SERVICE, ":1.1", "");
+        msg.set_serial(1);
+        cache.handle(&msg);
+        assert!(!cache.is_seeded());
+    }
+}
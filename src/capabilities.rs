@@ -0,0 +1,266 @@
+use crate::xml::{tags, Tag};
+use crate::*;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+static BLUEZ_INTERFACE_PREFIX: &str = "org.bluez.";
+static BLUEZ_ROOT_PATH: &str = "/org/bluez";
+
+/// バージョンの推定に使用するメンバー(インターフェース、メンバー、追加されたバージョン)
+///
+/// メンバーが空の場合はインターフェース自体の有無で判定する。
+/// 実験的な機能として先に提供されていたものは、既定で有効になったバージョンとする。
+const VERSION_MARKERS: [(&str, &str, BluezVersion); 8] = [
+    (
+        "org.bluez.Adapter1",
+        "ConnectDevice",
+        BluezVersion::new(5, 49),
+    ),
+    ("org.bluez.Device1", "WakeAllowed", BluezVersion::new(5, 55)),
+    ("org.bluez.Adapter1", "Roles", BluezVersion::new(5, 56)),
+    (
+        "org.bluez.Adapter1",
+        "ExperimentalFeatures",
+        BluezVersion::new(5, 56),
+    ),
+    (
+        "org.bluez.AdvertisementMonitorManager1",
+        "",
+        BluezVersion::new(5, 56),
+    ),
+    (
+        "org.bluez.GattCharacteristic1",
+        "MTU",
+        BluezVersion::new(5, 62),
+    ),
+    (
+        "org.bluez.Adapter1",
+        "Manufacturer",
+        BluezVersion::new(5, 66),
+    ),
+    ("org.bluez.Adapter1", "PowerState", BluezVersion::new(5, 66)),
+];
+
+/// BlueZのバージョン
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BluezVersion {
+    pub major: u16,
+    pub minor: u16,
+}
+
+impl BluezVersion {
+    pub const fn new(major: u16, minor: u16) -> Self {
+        BluezVersion { major, minor }
+    }
+}
+
+impl fmt::Display for BluezVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// インターフェースが持つメソッドとプロパティ
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct InterfaceCapabilities {
+    pub methods: BTreeSet<String>,
+    pub properties: BTreeSet<String>,
+}
+
+/// 接続先のBlueZが提供する機能
+///
+/// `Session::capabilities`で取得する。`org.bluez.`で始まるインターフェースのみを扱う。
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Capabilities {
+    /// インターフェース名ごとのメソッドとプロパティ
+    pub interfaces: BTreeMap<String, InterfaceCapabilities>,
+    /// 存在するメンバーから推定したBlueZのバージョン(下限)
+    ///
+    /// BlueZはバージョンをD-Busで公開していないため、各バージョンで追加されたメンバーの有無から推定する。
+    /// 推定の手がかりが無い場合(5.49より前、またはアダプターが無い場合など)は`None`となる。
+    pub version: Option<BluezVersion>,
+}
+
+impl Capabilities {
+    pub fn has_interface(&self, interface: &str) -> bool {
+        self.interfaces.contains_key(interface)
+    }
+
+    pub fn has_method(&self, interface: &str, method: &str) -> bool {
+        self.interfaces
+            .get(interface)
+            .map(|i| i.methods.contains(method))
+            .unwrap_or(false)
+    }
+
+    pub fn has_property(&self, interface: &str, property: &str) -> bool {
+        self.interfaces
+            .get(interface)
+            .map(|i| i.properties.contains(property))
+            .unwrap_or(false)
+    }
+
+    /// メソッドが存在しない場合は`BluezError::Unsupported`を返す
+    ///
+    /// インターフェースを持つオブジェクトが一つも無く判断できない場合はエラーとしない。
+    pub fn require_method(&self, interface: &str, method: &str) -> Result<(), BluezError> {
+        match self.interfaces.get(interface) {
+            Some(i) if !i.methods.contains(method) => Err(BluezError::Unsupported(format!(
+                "{}.{} is not available in {}",
+                interface,
+                method,
+                self.version
+                    .map(|version| format!("detected BlueZ {}", version))
+                    .unwrap_or_else(|| "BlueZ of unknown version".to_string())
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// イントロスペクションを行うオブジェクトパス
+    ///
+    /// `/org/bluez`と、それまでのオブジェクトに無いインターフェースを持つオブジェクトをパスの順に選ぶ。
+    pub(in crate) fn introspection_paths(objects: &ManagedObject) -> Vec<String> {
+        let mut objects: Vec<_> = objects.iter().collect();
+        objects.sort_unstable_by_key(|(path, _)| path.to_string());
+        let mut seen = BTreeSet::new();
+        let mut selected = vec![BLUEZ_ROOT_PATH.to_string()];
+        for (path, interfaces) in objects {
+            let mut new = false;
+            for interface in interfaces.keys() {
+                if interface.starts_with(BLUEZ_INTERFACE_PREFIX) && seen.insert(interface) {
+                    new = true;
+                }
+            }
+            if new {
+                selected.push(path.to_string());
+            }
+        }
+        selected
+    }
+
+    /// イントロスペクションの結果と`GetManagedObjects`の結果から作成する
+    ///
+    /// イントロスペクションで得られなかったインターフェースは、`GetManagedObjects`のプロパティ名で補う。
+    pub(in crate) fn from_introspection(
+        xmls: &[String],
+        objects: &ManagedObject,
+    ) -> Result<Self, BluezError> {
+        let mut capabilities = Capabilities::default();
+        for xml in xmls {
+            capabilities.add_introspection(xml)?;
+        }
+        for interfaces in objects.values() {
+            for (interface, properties) in interfaces {
+                if !interface.starts_with(BLUEZ_INTERFACE_PREFIX)
+                    || capabilities.has_interface(interface)
+                {
+                    continue;
+                }
+                let entry = capabilities
+                    .interfaces
+                    .entry(interface.clone())
+                    .or_default();
+                entry.properties.extend(properties.keys().cloned());
+            }
+        }
+        capabilities.version = VERSION_MARKERS
+            .iter()
+            .filter(|(interface, member, _)| {
+                capabilities.has_interface(interface)
+                    && (member.is_empty()
+                        || capabilities.has_method(interface, member)
+                        || capabilities.has_property(interface, member))
+            })
+            .map(|(_, _, version)| *version)
+            .max();
+        Ok(capabilities)
+    }
+
+    /// イントロスペクションのXMLのインターフェースを追加する
+    ///
+    /// 子ノードは読み飛ばす(子ノードのインターフェースはそれぞれのイントロスペクションで得る)。
+    fn add_introspection(&mut self, xml: &str) -> Result<(), BluezError> {
+        let tags =
+            tags(xml).map_err(|e| BluezError::TypeMismatch(format!("introspection: {}", e)))?;
+        let mut interface: Option<String> = None;
+        for tag in tags {
+            match tag {
+                Tag::Start(name, attrs) => {
+                    let value = attrs
+                        .into_iter()
+                        .find(|(key, _)| key == "name")
+                        .map(|(_, value)| value);
+                    match (name.as_str(), value, &interface) {
+                        ("interface", Some(value), _)
+                            if value.starts_with(BLUEZ_INTERFACE_PREFIX) =>
+                        {
+                            self.interfaces.entry(value.clone()).or_default();
+                            interface = Some(value);
+                        }
+                        ("method", Some(value), Some(interface)) => {
+                            self.interfaces
+                                .get_mut(interface)
+                                .unwrap()
+                                .methods
+                                .insert(value);
+                        }
+                        ("property", Some(value), Some(interface)) => {
+                            self.interfaces
+                                .get_mut(interface)
+                                .unwrap()
+                                .properties
+                                .insert(value);
+                        }
+                        _ => {}
+                    }
+                }
+                Tag::End(name) if name == "interface" => interface = None,
+                Tag::End(_) => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADAPTER_XML: &str = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+"http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node><interface name="org.freedesktop.DBus.Introspectable"><method name="Introspect"><arg name="xml" type="s" direction="out"/>
+</method></interface><interface name="org.bluez.Adapter1"><method name="StartDiscovery"></method><method name="ConnectDevice"><arg name="properties" type="a{sv}" direction="in"/>
+</method><property name="Address" type="s" access="read"></property><property name="Roles" type="as" access="read"></property>
+</interface><node name="dev_00_11_22_33_44_55"/></node>"#;
+
+    #[test]
+    fn introspect_adapter() {
+        let capabilities =
+            Capabilities::from_introspection(&[ADAPTER_XML.to_string()], &Default::default())
+                .unwrap();
+        assert_eq!(
+            capabilities.interfaces.keys().collect::<Vec<_>>(),
+            vec!["org.bluez.Adapter1"]
+        );
+        assert!(capabilities.has_method("org.bluez.Adapter1", "ConnectDevice"));
+        assert!(capabilities.has_property("org.bluez.Adapter1", "Roles"));
+        assert_eq!(capabilities.version, Some(BluezVersion::new(5, 56)));
+        assert!(capabilities
+            .require_method("org.bluez.Adapter1", "ConnectDevice")
+            .is_ok());
+        match capabilities.require_method("org.bluez.Adapter1", "SetFilterPolicy") {
+            Err(BluezError::Unsupported(m)) => assert_eq!(
+                m,
+                "org.bluez.Adapter1.SetFilterPolicy is not available in detected BlueZ 5.56"
+            ),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(capabilities
+            .require_method("org.bluez.Battery1", "Refresh")
+            .is_ok());
+    }
+}
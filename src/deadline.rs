@@ -0,0 +1,105 @@
+use crate::BluezError;
+use std::time::{Duration, Instant};
+
+/// 複数の呼び出しにまたがる期限
+///
+/// 接続、サービス解決、読み込みのような一連の操作を一つの時間枠で行う場合に使用する。
+///
+/// ```no_run
+/// use bluez_dbus::blocking::{Device, Session};
+/// use bluez_dbus::Deadline;
+/// use std::time::Duration;
+///
+/// let s = Session::new()?;
+/// let deadline = Deadline::after(Duration::from_secs(30));
+/// let dev = Device::new(&s, &"/org/bluez/hci0/dev_00_11_22_33_44_55".parse()?).with_deadline(deadline);
+/// dev.connect()?;
+/// let _ = dev.get_gatt_services()?;
+/// # Ok::<(), bluez_dbus::BluezError>(())
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Deadline {
+    at: Instant,
+}
+
+impl Deadline {
+    /// 現在から指定時間後の期限を作成
+    pub fn after(timeout: Duration) -> Self {
+        Deadline {
+            at: Instant::now() + timeout,
+        }
+    }
+
+    /// 指定時刻の期限を作成
+    pub fn at(at: Instant) -> Self {
+        Deadline { at }
+    }
+
+    /// 期限の時刻
+    pub fn instant(&self) -> Instant {
+        self.at
+    }
+
+    /// 期限を過ぎているかどうか
+    pub fn is_expired(&self) -> bool {
+        Instant::now() >= self.at
+    }
+
+    /// 期限までの残り時間
+    ///
+    /// 期限を過ぎている場合は`BluezError::Timeout`を返す。
+    pub fn remaining(&self) -> Result<Duration, BluezError> {
+        let now = Instant::now();
+        if now >= self.at {
+            return Err(BluezError::Timeout("deadline exceeded".to_string()));
+        }
+        Ok(self.at - now)
+    }
+}
+
+/// オブジェクト単位のタイムアウト指定
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(in crate) enum Timeout {
+    Duration(Duration),
+    Deadline(Deadline),
+}
+
+impl Timeout {
+    /// 呼び出し時点のタイムアウト値を求める
+    pub(in crate) fn resolve(timeout: Option<Timeout>) -> Result<Option<Duration>, BluezError> {
+        match timeout {
+            Some(Timeout::Duration(d)) => Ok(Some(d)),
+            Some(Timeout::Deadline(deadline)) => deadline.remaining().map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expired_deadline() {
+        let deadline = Deadline::at(Instant::now() - Duration::from_millis(1));
+        assert!(deadline.is_expired());
+        assert!(matches!(
+            Timeout::resolve(Some(Timeout::Deadline(deadline))),
+            Err(BluezError::Timeout(_))
+        ));
+    }
+
+    #[test]
+    fn remaining_time() {
+        let deadline = Deadline::after(Duration::from_secs(60));
+        let remaining = Timeout::resolve(Some(Timeout::Deadline(deadline)))
+            .unwrap()
+            .unwrap();
+        assert!(remaining <= Duration::from_secs(60));
+        assert!(remaining > Duration::from_secs(50));
+        assert_eq!(
+            Timeout::resolve(Some(Timeout::Duration(Duration::from_secs(1)))).unwrap(),
+            Some(Duration::from_secs(1))
+        );
+    }
+}
@@ -0,0 +1,282 @@
+use crate::*;
+use dbus::arg::{Arg, ArgType, RefArg, Variant};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::hash::Hash;
+
+/// D-Busの値(`RefArg`)から変換できる型
+///
+/// プロパティの取得、`info()`、`PropertiesChanged`、`GetManagedObjects`の結果の読み込みは全てこの変換を使う。
+/// Variantは中身を取り出してから変換するため、`a{qv}`を`HashMap<u16, Vec<u8>>`として読み込める。
+///
+/// ```
+/// use bluez_dbus::FromRefArg;
+/// use dbus::arg::{RefArg, Variant};
+/// use std::collections::HashMap;
+///
+/// let mut data: HashMap<u16, Variant<Box<dyn RefArg>>> = HashMap::new();
+/// data.insert(0x004c, Variant(Box::new(vec![0x02u8, 0x15])));
+/// let decoded = HashMap::<u16, Vec<u8>>::from_refarg(&data)?;
+/// assert_eq!(decoded[&0x004c], vec![0x02, 0x15]);
+///
+/// let err = HashMap::<u16, String>::from_refarg(&data).unwrap_err();
+/// assert_eq!(err.to_string(), "[0x004c]: expected s, found ay");
+/// # Ok::<(), bluez_dbus::DecodeError>(())
+/// ```
+pub trait FromRefArg: Sized {
+    /// エラーメッセージに使う、期待する型のD-Busのシグネチャ
+    fn expected() -> String;
+
+    /// 値を変換する
+    ///
+    /// 型が一致しない場合は、一致しなかった箇所(辞書のキーや配列の位置)を含む`DecodeError`を返す。
+    fn from_refarg(value: &dyn RefArg) -> Result<Self, DecodeError>;
+}
+
+/// 値の型が期待した型と一致しない
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DecodeError {
+    /// 一致しなかった箇所(例: `[0x004c][2]`、最上位の場合は空)
+    pub at: String,
+    pub expected: String,
+    pub found: String,
+}
+
+impl DecodeError {
+    fn new<T: FromRefArg>(found: &dyn RefArg) -> Self {
+        DecodeError {
+            at: String::new(),
+            expected: T::expected(),
+            found: found.signature().to_string(),
+        }
+    }
+
+    /// 外側の要素の位置を先頭に加える
+    fn within(mut self, at: String) -> Self {
+        self.at.insert_str(0, &at);
+        self
+    }
+
+    /// プロパティ名などを先頭に付けた説明
+    pub(in crate) fn describe(&self, name: &str) -> String {
+        format!(
+            "{}{}: expected {}, found {}",
+            name, self.at, self.expected, self.found
+        )
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.at.is_empty() {
+            write!(f, "expected {}, found {}", self.expected, self.found)
+        } else {
+            write!(f, "{}", self.describe(""))
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl From<DecodeError> for BluezError {
+    fn from(err: DecodeError) -> Self {
+        BluezError::TypeMismatch(err.to_string())
+    }
+}
+
+/// Variantの中身を取り出す(入れ子の場合は全て)
+fn unwrap_variant(mut value: &dyn RefArg) -> &dyn RefArg {
+    while value.arg_type() == ArgType::Variant {
+        match value.as_iter().and_then(|mut inner| inner.next()) {
+            Some(inner) => value = inner,
+            None => break,
+        }
+    }
+    value
+}
+
+macro_rules! from_refarg {
+    ($($t: ty => |$value: ident| $convert: expr;)*) => {
+        $(impl FromRefArg for $t {
+            fn expected() -> String {
+                <$t as Arg>::signature().to_string()
+            }
+
+            fn from_refarg(value: &dyn RefArg) -> Result<Self, DecodeError> {
+                let $value = unwrap_variant(value);
+                if $value.arg_type() != <$t as Arg>::ARG_TYPE {
+                    return Err(DecodeError::new::<$t>($value));
+                }
+                $convert.ok_or_else(|| DecodeError::new::<$t>($value))
+            }
+        })*
+    };
+}
+
+from_refarg! {
+    bool => |value| value.as_i64().map(|b| b != 0);
+    u8 => |value| value.as_u64().and_then(|n| u8::try_from(n).ok());
+    u16 => |value| value.as_u64().and_then(|n| u16::try_from(n).ok());
+    u32 => |value| value.as_u64().and_then(|n| u32::try_from(n).ok());
+    u64 => |value| value.as_u64();
+    i16 => |value| value.as_i64().and_then(|n| i16::try_from(n).ok());
+    i32 => |value| value.as_i64().and_then(|n| i32::try_from(n).ok());
+    i64 => |value| value.as_i64();
+    f64 => |value| value.as_f64();
+    String => |value| value.as_str().map(|s| s.to_string());
+    dbus::Path<'static> => |value| value.as_str().and_then(|path| dbus::Path::new(path.to_string()).ok());
+}
+
+/// BlueZのオブジェクトパスの型
+///
+/// 形式が正しくない場合は、見つかった値としてパスを示す。
+macro_rules! from_refarg_path {
+    ($($t: ident),*) => {
+        $(impl FromRefArg for $t {
+            fn expected() -> String {
+                format!("o ({})", stringify!($t))
+            }
+
+            fn from_refarg(value: &dyn RefArg) -> Result<Self, DecodeError> {
+                let value = unwrap_variant(value);
+                if value.arg_type() != ArgType::ObjectPath {
+                    return Err(DecodeError::new::<$t>(value));
+                }
+                let path = value.as_str().unwrap_or_default();
+                path.parse().map_err(|_| DecodeError {
+                    found: format!("o ({})", path),
+                    ..DecodeError::new::<$t>(value)
+                })
+            }
+        })*
+    };
+}
+
+from_refarg_path!(
+    AdapterPath,
+    DevicePath,
+    ServicePath,
+    CharacteristicPath,
+    DescriptorPath
+);
+
+impl<T: FromRefArg> FromRefArg for Vec<T> {
+    fn expected() -> String {
+        format!("a{}", T::expected())
+    }
+
+    fn from_refarg(value: &dyn RefArg) -> Result<Self, DecodeError> {
+        let value = unwrap_variant(value);
+        let signature = value.signature();
+        if value.arg_type() != ArgType::Array || signature.starts_with("a{") {
+            return Err(DecodeError::new::<Self>(value));
+        }
+        let items = value
+            .as_iter()
+            .ok_or_else(|| DecodeError::new::<Self>(value))?;
+        items
+            .enumerate()
+            .map(|(i, item)| T::from_refarg(item).map_err(|e| e.within(format!("[{}]", i))))
+            .collect()
+    }
+}
+
+/// 辞書のキーとして使用できる型
+///
+/// エラーメッセージでキーを示すための表記を持つ。
+pub trait DictKey: FromRefArg + Eq + Hash {
+    fn label(&self) -> String;
+}
+
+impl DictKey for u8 {
+    fn label(&self) -> String {
+        format!("0x{:02x}", self)
+    }
+}
+
+impl DictKey for u16 {
+    fn label(&self) -> String {
+        format!("0x{:04x}", self)
+    }
+}
+
+impl DictKey for String {
+    fn label(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
+impl<K: DictKey, V: FromRefArg> FromRefArg for HashMap<K, V> {
+    fn expected() -> String {
+        format!("a{{{}{}}}", K::expected(), V::expected())
+    }
+
+    fn from_refarg(value: &dyn RefArg) -> Result<Self, DecodeError> {
+        let value = unwrap_variant(value);
+        if !value.signature().starts_with("a{") {
+            return Err(DecodeError::new::<Self>(value));
+        }
+        // 辞書の`as_iter`はキーと値を交互に返す
+        let mut items = value
+            .as_iter()
+            .ok_or_else(|| DecodeError::new::<Self>(value))?;
+        let mut map = HashMap::new();
+        while let (Some(key), Some(item)) = (items.next(), items.next()) {
+            let key = K::from_refarg(key).map_err(|e| e.within("(key)".to_string()))?;
+            let item = V::from_refarg(item).map_err(|e| e.within(format!("[{}]", key.label())))?;
+            map.insert(key, item);
+        }
+        Ok(map)
+    }
+}
+
+/// 型を決めずにそのまま取り出す(値の型が混在する辞書など)
+impl FromRefArg for Variant<Box<dyn RefArg>> {
+    fn expected() -> String {
+        "v".to_string()
+    }
+
+    fn from_refarg(value: &dyn RefArg) -> Result<Self, DecodeError> {
+        Ok(Variant(unwrap_variant(value).box_clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_nested_values() {
+        let mut service_data: HashMap<String, Variant<Box<dyn RefArg>>> = HashMap::new();
+        service_data.insert(
+            "0000feaa-0000-1000-8000-00805f9b34fb".to_string(),
+            Variant(Box::new(vec![0x10u8, 0x00])),
+        );
+        let decoded = HashMap::<String, Vec<u8>>::from_refarg(&service_data).unwrap();
+        assert_eq!(
+            decoded["0000feaa-0000-1000-8000-00805f9b34fb"],
+            vec![0x10, 0x00]
+        );
+
+        let paths = vec![
+            dbus::Path::from("/org/bluez/hci0/dev_00/service0001"),
+            dbus::Path::from("/org/bluez/hci0"),
+        ];
+        let err = Vec::<ServicePath>::from_refarg(&paths).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "[1]: expected o (ServicePath), found o (/org/bluez/hci0)"
+        );
+
+        let wrapped =
+            Variant(Box::new(Variant(Box::new(-60i16) as Box<dyn RefArg>)) as Box<dyn RefArg>);
+        assert_eq!(i16::from_refarg(&wrapped), Ok(-60));
+        assert_eq!(
+            u16::from_refarg(&-60i16).unwrap_err().to_string(),
+            "expected q, found n"
+        );
+        assert!(Vec::<u8>::from_refarg(&service_data).is_err());
+    }
+}
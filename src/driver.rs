@@ -0,0 +1,119 @@
+use crate::*;
+use dbus::channel::Channel;
+use dbus::nonblock::{NonblockReply, Process, SyncConnection};
+use futures_timer::Delay;
+use std::future::Future;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::pin::Pin;
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::Instant;
+use tokio::sync::oneshot;
+
+/// 専用スレッドでD-Busの送受信を行うコネクションを作成する
+///
+/// 非同期ランタイムに依存しないため、blockingのセッション、tokio以外のエグゼキューター、
+/// `dbus-tokio`が対応していない任意アドレスのバスで使用する。
+/// 送信待ちのメッセージがある場合はパイプへの書き込みでスレッドを起こす。
+/// スレッドが終了すると、返された`Receiver`に切断理由が送られる。
+pub(in crate) fn spawn(
+    mut channel: Channel,
+) -> Result<(Arc<SyncConnection>, oneshot::Receiver<String>), BluezError> {
+    channel.set_watch_enabled(true);
+    let (wake_read, wake_write) = pipe()?;
+
+    let mut conn = SyncConnection::from(channel);
+    conn.set_timeout_maker(Some(make_timeout));
+    conn.set_waker(Some(Box::new(move || {
+        let byte = 1u8;
+        let fd = wake_write.as_raw_fd();
+        let r = unsafe { libc::write(fd, &byte as *const u8 as *const libc::c_void, 1) };
+        // パイプが一杯の場合は既に起こされているので成功とみなす
+        if r < 0 && std::io::Error::last_os_error().kind() != std::io::ErrorKind::WouldBlock {
+            Err(())
+        } else {
+            Ok(())
+        }
+    })));
+    let conn = Arc::new(conn);
+
+    let weak: Weak<SyncConnection> = Arc::downgrade(&conn);
+    let (tx, rx) = oneshot::channel();
+    thread::Builder::new()
+        .name("bluez-dbus-io".to_string())
+        .spawn(move || {
+            let reason = run(weak, wake_read);
+            let _ = tx.send(reason);
+        })
+        .map_err(|e| BluezError::Runtime(e.to_string()))?;
+    Ok((conn, rx))
+}
+
+/// 送受信ループ
+///
+/// コネクションが破棄されるか、切断されるまで処理を続け、終了理由を返す。
+fn run(weak: Weak<SyncConnection>, wake_read: OwnedFd) -> String {
+    loop {
+        let watch = match weak.upgrade() {
+            Some(conn) => {
+                let channel: &Channel = (*conn).as_ref();
+                if channel.read_write(Some(Default::default())).is_err() {
+                    return "read/write failed".to_string();
+                }
+                conn.process_all();
+                if !channel.is_connected() {
+                    return "disconnected".to_string();
+                }
+                let mut watch = channel.watch();
+                watch.write = channel.has_messages_to_send();
+                watch
+            }
+            None => return "connection dropped".to_string(),
+        };
+
+        let mut events = if watch.read { libc::POLLIN } else { 0 };
+        if watch.write {
+            events |= libc::POLLOUT;
+        }
+        let mut fds = [
+            libc::pollfd {
+                fd: watch.fd,
+                events,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: wake_read.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        // コネクションの破棄を検出するため、一定間隔で起きる
+        let r = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, 1000) };
+        if r < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() != std::io::ErrorKind::Interrupted {
+                return err.to_string();
+            }
+        }
+        if fds[1].revents & libc::POLLIN != 0 {
+            let mut buf = [0u8; 64];
+            let fd = wake_read.as_raw_fd();
+            unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        }
+    }
+}
+
+fn pipe() -> Result<(OwnedFd, OwnedFd), BluezError> {
+    let mut fds = [0 as RawFd; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) } < 0 {
+        let err = std::io::Error::last_os_error();
+        return Err(BluezError::DBus(dbus::Error::new_failed(&err.to_string())));
+    }
+    Ok(unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) })
+}
+
+fn make_timeout(timeout: Instant) -> Pin<Box<dyn Future<Output = ()> + Send + Sync + 'static>> {
+    Box::pin(Delay::new(
+        timeout.saturating_duration_since(Instant::now()),
+    ))
+}
@@ -0,0 +1,327 @@
+use dbus::arg::TypeMismatchError;
+use std::error::Error;
+use std::fmt;
+
+static BLUEZ_ERROR_PREFIX: &str = "org.bluez.Error.";
+static TYPE_MISMATCH_MESSAGE: &str = "D-Bus argument type mismatch";
+static INVALID_ARGS_ERROR: &str = "org.freedesktop.DBus.Error.InvalidArgs";
+/// 接続の確立前にローカル側で中断された場合のメッセージ(電源投入直後に起こりやすい)
+static LE_CONNECTION_ABORT: &str = "le-connection-abort-by-local";
+
+/// BlueZとの通信で発生するエラー
+///
+/// `org.bluez.Error.*`の各エラーはそれぞれのバリアントに変換され、
+/// BlueZから返されたメッセージを保持する。
+#[derive(Debug)]
+pub enum BluezError {
+    // org.bluez.Error.*
+    Failed(String),
+    InProgress(String),
+    NotReady(String),
+    InvalidArguments(String),
+    NotAuthorized(String),
+    NotPermitted(String),
+    NotSupported(String),
+    NotAvailable(String),
+    AlreadyExists(String),
+    DoesNotExist(String),
+    AlreadyConnected(String),
+    NotConnected(String),
+    ConnectionAttemptFailed(String),
+    AuthenticationCanceled(String),
+    AuthenticationFailed(String),
+    AuthenticationRejected(String),
+    AuthenticationTimeout(String),
+    InvalidValueLength(String),
+    InvalidOffset(String),
+    Rejected(String),
+    Canceled(String),
+    /// 上記以外の`org.bluez.Error.*`
+    Bluez {
+        name: String,
+        message: String,
+    },
+
+    /// メソッド呼び出しがタイムアウトした
+    Timeout(String),
+    /// 応答の型が期待した型と一致しない
+    TypeMismatch(String),
+    /// 対象のオブジェクトが存在しない(削除された)
+    ObjectVanished(String),
+    /// D-Busとの接続に関するエラー
+    Transport(dbus::Error),
+    /// D-Busとの接続が切れた(切断理由)
+    ConnectionLost(String),
+    /// 接続先のBlueZが対応していない機能(インターフェースやメソッドが存在しない)
+    Unsupported(String),
+    /// 非同期ランタイムに関するエラー
+    Runtime(String),
+    /// その他のD-Busエラー
+    DBus(dbus::Error),
+}
+
+impl BluezError {
+    /// D-Busのエラー名を取得
+    pub fn name(&self) -> Option<&str> {
+        use BluezError::*;
+        let name = match self {
+            Failed(_) => "org.bluez.Error.Failed",
+            InProgress(_) => "org.bluez.Error.InProgress",
+            NotReady(_) => "org.bluez.Error.NotReady",
+            InvalidArguments(_) => "org.bluez.Error.InvalidArguments",
+            NotAuthorized(_) => "org.bluez.Error.NotAuthorized",
+            NotPermitted(_) => "org.bluez.Error.NotPermitted",
+            NotSupported(_) => "org.bluez.Error.NotSupported",
+            NotAvailable(_) => "org.bluez.Error.NotAvailable",
+            AlreadyExists(_) => "org.bluez.Error.AlreadyExists",
+            DoesNotExist(_) => "org.bluez.Error.DoesNotExist",
+            AlreadyConnected(_) => "org.bluez.Error.AlreadyConnected",
+            NotConnected(_) => "org.bluez.Error.NotConnected",
+            ConnectionAttemptFailed(_) => "org.bluez.Error.ConnectionAttemptFailed",
+            AuthenticationCanceled(_) => "org.bluez.Error.AuthenticationCanceled",
+            AuthenticationFailed(_) => "org.bluez.Error.AuthenticationFailed",
+            AuthenticationRejected(_) => "org.bluez.Error.AuthenticationRejected",
+            AuthenticationTimeout(_) => "org.bluez.Error.AuthenticationTimeout",
+            InvalidValueLength(_) => "org.bluez.Error.InvalidValueLength",
+            InvalidOffset(_) => "org.bluez.Error.InvalidOffset",
+            Rejected(_) => "org.bluez.Error.Rejected",
+            Canceled(_) => "org.bluez.Error.Canceled",
+            Bluez { name, .. } => name,
+            Transport(e) | DBus(e) => return e.name(),
+            Timeout(_) | TypeMismatch(_) | ObjectVanished(_) | ConnectionLost(_)
+            | Unsupported(_) | Runtime(_) => return None,
+        };
+        Some(name)
+    }
+
+    /// エラーメッセージを取得
+    pub fn message(&self) -> &str {
+        use BluezError::*;
+        match self {
+            Failed(m)
+            | InProgress(m)
+            | NotReady(m)
+            | InvalidArguments(m)
+            | NotAuthorized(m)
+            | NotPermitted(m)
+            | NotSupported(m)
+            | NotAvailable(m)
+            | AlreadyExists(m)
+            | DoesNotExist(m)
+            | AlreadyConnected(m)
+            | NotConnected(m)
+            | ConnectionAttemptFailed(m)
+            | AuthenticationCanceled(m)
+            | AuthenticationFailed(m)
+            | AuthenticationRejected(m)
+            | AuthenticationTimeout(m)
+            | InvalidValueLength(m)
+            | InvalidOffset(m)
+            | Rejected(m)
+            | Canceled(m)
+            | Timeout(m)
+            | TypeMismatch(m)
+            | ObjectVanished(m)
+            | ConnectionLost(m)
+            | Unsupported(m)
+            | Runtime(m) => m,
+            Bluez { message, .. } => message,
+            Transport(e) | DBus(e) => e.message().unwrap_or(""),
+        }
+    }
+
+    /// D-Busのエラーとして送る場合の名前
+    ///
+    /// D-Bus由来でないエラーは、`From<dbus::Error>`で同じ種類に戻る名前にする。
+    #[cfg(any(feature = "mock", feature = "record"))]
+    pub(in crate) fn dbus_name(&self) -> &str {
+        match self {
+            BluezError::Timeout(_) => "org.freedesktop.DBus.Error.NoReply",
+            BluezError::ObjectVanished(_) => "org.freedesktop.DBus.Error.UnknownObject",
+            BluezError::ConnectionLost(_) => "org.freedesktop.DBus.Error.Disconnected",
+            BluezError::Unsupported(_) => "org.bluez.Error.NotSupported",
+            _ => self.name().unwrap_or("org.freedesktop.DBus.Error.Failed"),
+        }
+    }
+
+    /// `org.bluez.Error.*`のエラーかどうか
+    pub fn is_bluez_error(&self) -> bool {
+        self.name()
+            .map(|name| name.starts_with(BLUEZ_ERROR_PREFIX))
+            .unwrap_or(false)
+    }
+
+    /// 時間を置いて再試行すれば成功する見込みのあるエラーかどうか
+    ///
+    /// 他の操作の実行中(`InProgress`)、アダプターの準備中(`NotReady`)、
+    /// 接続の確立前のローカル側での中断(`le-connection-abort-by-local`)を一時的なエラーとする。
+    pub fn is_transient(&self) -> bool {
+        match self {
+            BluezError::InProgress(_) | BluezError::NotReady(_) => true,
+            BluezError::Failed(m) | BluezError::ConnectionAttemptFailed(m) => {
+                m.contains(LE_CONNECTION_ABORT)
+            }
+            _ => false,
+        }
+    }
+
+    fn from_bluez(kind: &str, message: String) -> Self {
+        use BluezError::*;
+        match kind {
+            "Failed" => Failed(message),
+            "InProgress" => InProgress(message),
+            "NotReady" => NotReady(message),
+            "InvalidArguments" => InvalidArguments(message),
+            "NotAuthorized" => NotAuthorized(message),
+            "NotPermitted" => NotPermitted(message),
+            "NotSupported" => NotSupported(message),
+            "NotAvailable" => NotAvailable(message),
+            "AlreadyExists" => AlreadyExists(message),
+            "DoesNotExist" => DoesNotExist(message),
+            "AlreadyConnected" => AlreadyConnected(message),
+            "NotConnected" => NotConnected(message),
+            "ConnectionAttemptFailed" => ConnectionAttemptFailed(message),
+            "AuthenticationCanceled" => AuthenticationCanceled(message),
+            "AuthenticationFailed" => AuthenticationFailed(message),
+            "AuthenticationRejected" => AuthenticationRejected(message),
+            "AuthenticationTimeout" => AuthenticationTimeout(message),
+            "InvalidValueLength" => InvalidValueLength(message),
+            "InvalidOffset" => InvalidOffset(message),
+            "Rejected" => Rejected(message),
+            "Canceled" => Canceled(message),
+            _ => Bluez {
+                name: format!("{}{}", BLUEZ_ERROR_PREFIX, kind),
+                message,
+            },
+        }
+    }
+}
+
+/// 存在しないプロパティの取得によるエラーを`None`に変換する
+///
+/// `Properties.Get`は値を持たないプロパティに対して`org.freedesktop.DBus.Error.InvalidArgs`を返す。
+/// `get_property!`マクロの展開先から呼び出すため公開している。
+#[doc(hidden)]
+pub fn absent_as_none<T>(result: Result<T, BluezError>) -> Result<Option<T>, BluezError> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(BluezError::DBus(e)) if e.name() == Some(INVALID_ARGS_ERROR) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+impl fmt::Display for BluezError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BluezError::Timeout(m) => write!(f, "timeout: {}", m),
+            BluezError::TypeMismatch(m) => write!(f, "type mismatch: {}", m),
+            BluezError::ObjectVanished(m) => write!(f, "object vanished: {}", m),
+            BluezError::ConnectionLost(m) => write!(f, "connection lost: {}", m),
+            BluezError::Unsupported(m) => write!(f, "unsupported by this BlueZ: {}", m),
+            BluezError::Runtime(m) => write!(f, "runtime: {}", m),
+            BluezError::Transport(e) | BluezError::DBus(e) => write!(
+                f,
+                "{}: {}",
+                e.name().unwrap_or("unknown"),
+                e.message().unwrap_or("")
+            ),
+            _ => write!(f, "{}: {}", self.name().unwrap_or(""), self.message()),
+        }
+    }
+}
+
+impl Error for BluezError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BluezError::Transport(e) | BluezError::DBus(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<dbus::Error> for BluezError {
+    fn from(err: dbus::Error) -> Self {
+        let name = err.name().unwrap_or("");
+        let message = err.message().unwrap_or("").to_string();
+        if let Some(kind) = name.strip_prefix(BLUEZ_ERROR_PREFIX) {
+            return BluezError::from_bluez(kind, message);
+        }
+        match name {
+            "org.freedesktop.DBus.Error.NoReply"
+            | "org.freedesktop.DBus.Error.Timeout"
+            | "org.freedesktop.DBus.Error.TimedOut" => BluezError::Timeout(message),
+            "org.freedesktop.DBus.Error.UnknownObject"
+            | "org.freedesktop.DBus.Error.UnknownInterface" => BluezError::ObjectVanished(message),
+            "org.freedesktop.DBus.Error.Failed" if message.starts_with(TYPE_MISMATCH_MESSAGE) => {
+                BluezError::TypeMismatch(message)
+            }
+            "org.freedesktop.DBus.Error.Disconnected"
+            | "org.freedesktop.DBus.Error.NoServer"
+            | "org.freedesktop.DBus.Error.NoNetwork"
+            | "org.freedesktop.DBus.Error.IOError"
+            | "org.freedesktop.DBus.Error.BadAddress"
+            | "org.freedesktop.DBus.Error.ServiceUnknown"
+            | "org.freedesktop.DBus.Error.NameHasNoOwner"
+            | "org.freedesktop.DBus.Error.NoMemory" => BluezError::Transport(err),
+            _ => BluezError::DBus(err),
+        }
+    }
+}
+
+impl From<TypeMismatchError> for BluezError {
+    fn from(err: TypeMismatchError) -> Self {
+        BluezError::TypeMismatch(err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bluez_error_names() {
+        let err: BluezError = dbus::Error::new_custom(
+            "org.bluez.Error.InProgress",
+            "Operation already in progress",
+        )
+        .into();
+        assert!(matches!(err, BluezError::InProgress(_)));
+        assert_eq!(err.name(), Some("org.bluez.Error.InProgress"));
+        assert_eq!(err.message(), "Operation already in progress");
+        assert!(err.is_bluez_error());
+        assert!(err.is_transient());
+
+        let err: BluezError =
+            dbus::Error::new_custom("org.bluez.Error.Failed", "le-connection-abort-by-local")
+                .into();
+        assert!(err.is_transient());
+        assert!(!BluezError::Failed("Operation failed".to_string()).is_transient());
+
+        let err: BluezError = dbus::Error::new_custom("org.bluez.Error.Unknown", "x").into();
+        assert!(matches!(err, BluezError::Bluez { .. }));
+        assert_eq!(err.name(), Some("org.bluez.Error.Unknown"));
+    }
+
+    #[test]
+    fn dbus_error_names() {
+        let err: BluezError =
+            dbus::Error::new_custom("org.freedesktop.DBus.Error.NoReply", "no reply").into();
+        assert!(matches!(err, BluezError::Timeout(_)));
+
+        let err: BluezError =
+            dbus::Error::new_custom("org.freedesktop.DBus.Error.UnknownObject", "gone").into();
+        assert!(matches!(err, BluezError::ObjectVanished(_)));
+
+        let err: BluezError =
+            dbus::Error::new_custom("org.freedesktop.DBus.Error.Disconnected", "bye").into();
+        assert!(matches!(err, BluezError::Transport(_)));
+        assert!(!err.is_bluez_error());
+
+        let err: BluezError = dbus::Error::new_custom(
+            "org.freedesktop.DBus.Error.Failed",
+            "D-Bus argument type mismatch at position 0: expected b, found s",
+        )
+        .into();
+        assert!(matches!(err, BluezError::TypeMismatch(_)));
+    }
+}
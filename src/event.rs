@@ -0,0 +1,254 @@
+use crate::*;
+use dbus::message::MatchRule;
+use dbus::Message;
+
+static DEVICE_INTERFACE: &str = "org.bluez.Device1";
+static GATT_SERVICE_INTERFACE: &str = "org.bluez.GattService1";
+static CHARACTERISTIC_INTERFACE: &str = "org.bluez.GattCharacteristic1";
+static DESCRIPTOR_INTERFACE: &str = "org.bluez.GattDescriptor1";
+
+/// オブジェクトの追加・削除のイベント
+///
+/// `InterfacesAdded`、`InterfacesRemoved`のシグナルから作成する。
+/// 追加のイベントは親のパスと追加時点のプロパティを持つ。
+/// BlueZのオブジェクトパスの形式に一致しないオブジェクトのイベントは作成しない。
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Event {
+    AdapterAdded {
+        adapter: AdapterPath,
+        properties: Properties,
+    },
+    AdapterRemoved {
+        adapter: AdapterPath,
+    },
+    DeviceAdded {
+        adapter: AdapterPath,
+        device: DevicePath,
+        properties: Properties,
+    },
+    DeviceRemoved {
+        device: DevicePath,
+    },
+    GattServiceAdded {
+        device: DevicePath,
+        service: ServicePath,
+        properties: Properties,
+    },
+    GattServiceRemoved {
+        service: ServicePath,
+    },
+    CharacteristicAdded {
+        service: ServicePath,
+        characteristic: CharacteristicPath,
+        properties: Properties,
+    },
+    CharacteristicRemoved {
+        characteristic: CharacteristicPath,
+    },
+    DescriptorAdded {
+        characteristic: CharacteristicPath,
+        descriptor: DescriptorPath,
+        properties: Properties,
+    },
+    DescriptorRemoved {
+        descriptor: DescriptorPath,
+    },
+}
+
+impl Event {
+    /// 追加・削除されたオブジェクトのパス
+    pub fn path(&self) -> &str {
+        use Event::*;
+        match self {
+            AdapterAdded { adapter, .. } | AdapterRemoved { adapter } => adapter,
+            DeviceAdded { device, .. } | DeviceRemoved { device } => device,
+            GattServiceAdded { service, .. } | GattServiceRemoved { service } => service,
+            CharacteristicAdded { characteristic, .. }
+            | CharacteristicRemoved { characteristic } => characteristic,
+            DescriptorAdded { descriptor, .. } | DescriptorRemoved { descriptor } => descriptor,
+        }
+    }
+
+    /// イベントの作成に必要なシグナル(`InterfacesAdded`、`InterfacesRemoved`)を受信するルール
+    pub(in crate) fn match_rule(service: &str) -> MatchRule<'static> {
+        let mut rule = MatchRule::new();
+        rule.msg_type = Some(dbus::MessageType::Signal);
+        rule.interface = Some(OBJECT_MANAGER_INTERFACE.into());
+        rule.sender = Some(service.to_string().into());
+        rule
+    }
+
+    /// 受信したシグナルからイベントを作成する
+    ///
+    /// 一つのシグナルで複数のインターフェースが追加・削除される場合があるため、複数のイベントを返す。
+    pub(in crate) fn from_message(msg: &Message) -> Vec<Event> {
+        match msg.member().as_deref() {
+            Some("InterfacesAdded") => match msg.read2::<dbus::Path, ManagedObjectInterfaces>() {
+                Ok((path, interfaces)) => interfaces
+                    .into_iter()
+                    .filter_map(|(interface, props)| Event::added(&path, &interface, props.into()))
+                    .collect(),
+                Err(_) => vec![],
+            },
+            Some("InterfacesRemoved") => match msg.read2::<dbus::Path, Vec<String>>() {
+                Ok((path, interfaces)) => interfaces
+                    .iter()
+                    .filter_map(|interface| Event::removed(&path, interface))
+                    .collect(),
+                Err(_) => vec![],
+            },
+            _ => vec![],
+        }
+    }
+
+    fn added(path: &str, interface: &str, properties: Properties) -> Option<Event> {
+        let event = if interface == ADAPTER_INTERFACE {
+            Event::AdapterAdded {
+                adapter: AdapterPath::new(path).ok()?,
+                properties,
+            }
+        } else if interface == DEVICE_INTERFACE {
+            let device = DevicePath::new(path).ok()?;
+            Event::DeviceAdded {
+                adapter: device.adapter(),
+                device,
+                properties,
+            }
+        } else if interface == GATT_SERVICE_INTERFACE {
+            let service = ServicePath::new(path).ok()?;
+            Event::GattServiceAdded {
+                device: service.device(),
+                service,
+                properties,
+            }
+        } else if interface == CHARACTERISTIC_INTERFACE {
+            let characteristic = CharacteristicPath::new(path).ok()?;
+            Event::CharacteristicAdded {
+                service: characteristic.service(),
+                characteristic,
+                properties,
+            }
+        } else if interface == DESCRIPTOR_INTERFACE {
+            let descriptor = DescriptorPath::new(path).ok()?;
+            Event::DescriptorAdded {
+                characteristic: descriptor.characteristic(),
+                descriptor,
+                properties,
+            }
+        } else {
+            return None;
+        };
+        Some(event)
+    }
+
+    fn removed(path: &str, interface: &str) -> Option<Event> {
+        let event = if interface == ADAPTER_INTERFACE {
+            Event::AdapterRemoved {
+                adapter: AdapterPath::new(path).ok()?,
+            }
+        } else if interface == DEVICE_INTERFACE {
+            Event::DeviceRemoved {
+                device: DevicePath::new(path).ok()?,
+            }
+        } else if interface == GATT_SERVICE_INTERFACE {
+            Event::GattServiceRemoved {
+                service: ServicePath::new(path).ok()?,
+            }
+        } else if interface == CHARACTERISTIC_INTERFACE {
+            Event::CharacteristicRemoved {
+                characteristic: CharacteristicPath::new(path).ok()?,
+            }
+        } else if interface == DESCRIPTOR_INTERFACE {
+            Event::DescriptorRemoved {
+                descriptor: DescriptorPath::new(path).ok()?,
+            }
+        } else {
+            return None;
+        };
+        Some(event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dbus::arg::{RefArg, Variant};
+    use std::collections::HashMap;
+
+    #[test]
+    fn device_added() {
+        let mut props: HashMap<String, Variant<Box<dyn RefArg>>> = HashMap::new();
+        props.insert(
+            "Adapter".to_string(),
+            Variant(Box::new(dbus::Path::from("/org/bluez/hci0"))),
+        );
+        props.insert("RSSI".to_string(), Variant(Box::new(-60i16)));
+        let mut interfaces = HashMap::new();
+        interfaces.insert(DEVICE_INTERFACE.to_string(), props);
+        interfaces.insert(
+            "org.freedesktop.DBus.Properties".to_string(),
+            HashMap::new(),
+        );
+        let msg = Message::signal(
+            &"/".into(),
+            &OBJECT_MANAGER_INTERFACE.into(),
+            &"InterfacesAdded".into(),
+        )
+        .append2(dbus::Path::from("/org/bluez/hci0/dev_00"), interfaces);
+
+        let events = Event::from_message(&msg);
+        assert_eq!(events.len(), 1);
+        match &events[0] {
+            Event::DeviceAdded {
+                adapter,
+                device,
+                properties,
+            } => {
+                assert_eq!(adapter.as_str(), "/org/bluez/hci0");
+                assert_eq!(device.as_str(), "/org/bluez/hci0/dev_00");
+                assert_eq!(properties.get("RSSI").and_then(|v| v.as_i64()), Some(-60));
+            }
+            event => panic!("unexpected event: {:?}", event),
+        }
+    }
+
+    #[test]
+    fn adapter_removed() {
+        let msg = Message::signal(
+            &"/".into(),
+            &OBJECT_MANAGER_INTERFACE.into(),
+            &"InterfacesRemoved".into(),
+        )
+        .append2(
+            dbus::Path::from("/org/bluez/hci0"),
+            vec![
+                ADAPTER_INTERFACE.to_string(),
+                "org.bluez.Media1".to_string(),
+            ],
+        );
+        let events = Event::from_message(&msg);
+        assert_eq!(events.len(), 1);
+        assert!(matches!(&events[0], Event::AdapterRemoved { .. }));
+        assert_eq!(events[0].path(), "/org/bluez/hci0");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let event = Event::DeviceRemoved {
+            device: DevicePath::new("/org/bluez/hci0/dev_00").unwrap(),
+        };
+        let json = serde_json::to_string(&event).unwrap();
+        assert_eq!(
+            json,
+            r#"{"DeviceRemoved":{"device":"/org/bluez/hci0/dev_00"}}"#
+        );
+        let loaded: Event = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.path(), "/org/bluez/hci0/dev_00");
+        assert!(serde_json::from_str::<Event>(
+            r#"{"DeviceRemoved":{"device":"/org/bluez/hci0/service0001"}}"#
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,644 @@
+use crate::*;
+use dbus::arg::{RefArg, Variant};
+use dbus::Message;
+use futures::future::{self, BoxFuture};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+static UNKNOWN_OBJECT: &str = "org.freedesktop.DBus.Error.UnknownObject";
+static INVALID_ARGS: &str = "org.freedesktop.DBus.Error.InvalidArgs";
+
+type Reply = Box<dyn FnMut(&Message) -> Result<Message, BluezError> + Send>;
+type SignalSink = Box<dyn FnMut(Message) + Send>;
+type ManagedObjectProperties = HashMap<String, Variant<Box<dyn RefArg>>>;
+
+/// D-Busを使用しないBlueZの代替
+///
+/// オブジェクトとプロパティをメモリ上に保持し、`Session`からの呼び出しに応答する。
+/// `blocking::Transport`、`nonblock::Transport`を実装しているため、
+/// `SessionBuilder::transport`に指定するとD-BusやBlueZなしでテストできる。
+///
+/// - `Properties`の`Get`、`GetAll`、`Set`は保持しているプロパティで応答する
+/// - `ObjectManager`の`GetManagedObjects`は保持している全てのオブジェクトを返す
+/// - `Introspectable`の`Introspect`は保持しているインターフェースとプロパティ、
+///   `on_call`で登録したメソッドを列挙する
+/// - それ以外のメソッドは`on_call`で登録した処理で応答し、未登録の場合は空の応答を返す
+///
+/// 存在しないオブジェクトへの呼び出しは`BluezError::ObjectVanished`で失敗する。
+/// 複製したものは同じ状態を共有する。
+///
+/// ```
+/// use bluez_dbus::blocking::{Device, Session};
+/// use bluez_dbus::{BluezError, FakeBluez};
+/// use std::sync::Arc;
+///
+/// let fake = FakeBluez::new();
+/// let path = "/org/bluez/hci0/dev_00_11_22_33_44_55";
+/// fake.set_property(path, "org.bluez.Device1", "Connected", false);
+/// fake.on_call("org.bluez.Device1", "Pair", |_| {
+///     Err(BluezError::AuthenticationFailed("Authentication Failed".to_string()))
+/// });
+///
+/// let s = Session::builder().transport(Arc::new(fake.clone())).build()?;
+/// let dev = Device::new(&s, &path.parse()?);
+/// assert!(!dev.is_connected()?);
+/// assert!(matches!(dev.pair(), Err(BluezError::AuthenticationFailed(_))));
+/// assert_eq!(fake.calls().last().unwrap().method, "Pair");
+/// # Ok::<(), BluezError>(())
+/// ```
+#[derive(Clone, Default)]
+pub struct FakeBluez {
+    state: Arc<Mutex<State>>,
+}
+
+/// `FakeBluez`が受けたメソッド呼び出し
+#[derive(Debug, Clone, PartialEq)]
+pub struct FakeCall {
+    pub path: String,
+    pub interface: String,
+    pub method: String,
+}
+
+#[derive(Default)]
+struct State {
+    objects: BTreeMap<String, BTreeMap<String, Properties>>,
+    // 応答の処理中は状態をロックしないよう、個別にロックする
+    replies: HashMap<(String, String), Arc<Mutex<Reply>>>,
+    calls: Vec<FakeCall>,
+    // 応答の作成に必要なため、呼び出しに振るシリアル番号
+    serial: u32,
+    // オブジェクトやプロパティの変更を通知するシグナルの送り先
+    signals: Option<SignalSink>,
+}
+
+impl State {
+    fn emit(&mut self, signal: Message) {
+        if let Some(sink) = &mut self.signals {
+            sink(signal);
+        }
+    }
+
+    /// インターフェースを追加し、`InterfacesAdded`を通知する
+    fn insert(&mut self, path: &str, interface: &str, props: Properties) {
+        let mut interfaces = HashMap::new();
+        interfaces.insert(interface.to_string(), props.to_variants());
+        self.objects
+            .entry(path.to_string())
+            .or_default()
+            .insert(interface.to_string(), props);
+        self.emit(
+            object_manager_signal("InterfacesAdded").append2(dbus::Path::from(path), interfaces),
+        );
+    }
+
+    /// プロパティを変更し、`PropertiesChanged`を通知する
+    ///
+    /// インターフェースが存在しない場合は追加する。
+    fn change(&mut self, path: &str, interface: &str, changed: ManagedObjectProperties) {
+        let props = match self
+            .objects
+            .get_mut(path)
+            .and_then(|interfaces| interfaces.get_mut(interface))
+        {
+            Some(props) => props,
+            None => return self.insert(path, interface, changed.into()),
+        };
+        let signal = Message::signal(
+            &dbus::Path::from(path),
+            &PROPERTIES_INTERFACE.into(),
+            &"PropertiesChanged".into(),
+        )
+        .append3(
+            interface,
+            changed
+                .iter()
+                .map(|(name, value)| (name.clone(), Variant(value.0.box_clone())))
+                .collect::<ManagedObjectProperties>(),
+            Vec::<String>::new(),
+        );
+        props.update(changed, &[]);
+        self.emit(signal);
+    }
+}
+
+fn object_manager_signal(member: &'static str) -> Message {
+    Message::signal(
+        &dbus::Path::from("/"),
+        &OBJECT_MANAGER_INTERFACE.into(),
+        &member.into(),
+    )
+}
+
+impl FakeBluez {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// オブジェクトにインターフェースを追加する
+    ///
+    /// オブジェクトが存在しない場合は作成する。
+    pub fn add_object(&self, path: &str, interface: &str) {
+        self.add_interface(path, interface, Properties::default());
+    }
+
+    /// プロパティを指定してオブジェクトにインターフェースを追加する
+    ///
+    /// 既に存在する場合は置き換える。
+    pub(in crate) fn add_interface(&self, path: &str, interface: &str, props: Properties) {
+        self.state.lock().unwrap().insert(path, interface, props);
+    }
+
+    /// オブジェクトを削除する
+    pub fn remove_object(&self, path: &str) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(interfaces) = state.objects.remove(path) {
+            let interfaces: Vec<String> = interfaces.into_keys().collect();
+            state.emit(
+                object_manager_signal("InterfacesRemoved")
+                    .append2(dbus::Path::from(path), interfaces),
+            );
+        }
+    }
+
+    /// オブジェクトが存在するかどうか
+    #[cfg_attr(not(feature = "mock"), allow(dead_code))]
+    pub(in crate) fn contains(&self, path: &str) -> bool {
+        self.state.lock().unwrap().objects.contains_key(path)
+    }
+
+    /// 全てのオブジェクトのパス
+    #[cfg_attr(not(feature = "mock"), allow(dead_code))]
+    pub(in crate) fn paths(&self) -> Vec<String> {
+        self.state.lock().unwrap().objects.keys().cloned().collect()
+    }
+
+    /// プロパティの値を設定する
+    ///
+    /// オブジェクトやインターフェースが存在しない場合は作成する。
+    pub fn set_property<V: RefArg + 'static>(
+        &self,
+        path: &str,
+        interface: &str,
+        name: &str,
+        value: V,
+    ) {
+        let mut changed: ManagedObjectProperties = HashMap::new();
+        changed.insert(name.to_string(), Variant(Box::new(value)));
+        self.state.lock().unwrap().change(path, interface, changed);
+    }
+
+    /// プロパティの値を取得する
+    ///
+    /// `Set`で変更された値の確認などに使用する。
+    pub fn property(&self, path: &str, interface: &str, name: &str) -> Option<Box<dyn RefArg>> {
+        let state = self.state.lock().unwrap();
+        state
+            .objects
+            .get(path)?
+            .get(interface)?
+            .get(name)
+            .map(|value| value.box_clone())
+    }
+
+    /// メソッド呼び出しへの応答を登録する
+    ///
+    /// `f`は呼び出しのメッセージを受け取り、応答(`Message::method_return`で作成する)かエラーを返す。
+    /// 同じメソッドに登録し直した場合は置き換える。
+    /// `f`の中で`FakeBluez`のオブジェクトやプロパティを変更してもよい。
+    pub fn on_call<F>(&self, interface: &str, method: &str, f: F)
+    where
+        F: FnMut(&Message) -> Result<Message, BluezError> + Send + 'static,
+    {
+        let mut state = self.state.lock().unwrap();
+        state.replies.insert(
+            (interface.to_string(), method.to_string()),
+            Arc::new(Mutex::new(Box::new(f))),
+        );
+    }
+
+    /// これまでに受けたメソッド呼び出しの一覧
+    pub fn calls(&self) -> Vec<FakeCall> {
+        self.state.lock().unwrap().calls.clone()
+    }
+
+    /// オブジェクトやプロパティの変更を通知するシグナルの送り先を設定する
+    ///
+    /// `InterfacesAdded`、`InterfacesRemoved`、`PropertiesChanged`を送る。
+    #[cfg_attr(not(feature = "mock"), allow(dead_code))]
+    pub(in crate) fn on_signal<F>(&self, f: F)
+    where
+        F: FnMut(Message) + Send + 'static,
+    {
+        self.state.lock().unwrap().signals = Some(Box::new(f));
+    }
+
+    /// D-Busを介さずにメソッド呼び出しに応答する
+    fn call(&self, mut msg: Message) -> Result<Message, BluezError> {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.serial += 1;
+            msg.set_serial(state.serial);
+        }
+        self.reply(&msg)
+    }
+
+    /// メソッド呼び出しに応答する
+    pub(in crate) fn reply(&self, msg: &Message) -> Result<Message, BluezError> {
+        let path = msg.path().map(|p| p.to_string()).unwrap_or_default();
+        let interface = msg.interface().map(|i| i.to_string()).unwrap_or_default();
+        let method = msg.member().map(|m| m.to_string()).unwrap_or_default();
+        let mut state = self.state.lock().unwrap();
+        state.calls.push(FakeCall {
+            path: path.clone(),
+            interface: interface.clone(),
+            method: method.clone(),
+        });
+
+        if interface == OBJECT_MANAGER_INTERFACE && method == "GetManagedObjects" {
+            let objects: ManagedObject = state
+                .objects
+                .iter()
+                .map(|(path, interfaces)| {
+                    let interfaces = interfaces
+                        .iter()
+                        .map(|(interface, props)| (interface.clone(), props.to_variants()))
+                        .collect();
+                    (dbus::Path::from(path.clone()), interfaces)
+                })
+                .collect();
+            return Ok(msg.method_return().append1(objects));
+        }
+        if interface == INTROSPECTABLE_INTERFACE && method == "Introspect" {
+            return Ok(msg.method_return().append1(introspect(&path, &state)));
+        }
+        if !state.objects.contains_key(&path) {
+            return Err(error(
+                UNKNOWN_OBJECT,
+                &format!("Object {} does not exist", path),
+            ));
+        }
+        if interface == PROPERTIES_INTERFACE {
+            return properties(msg, &method, &path, &mut state);
+        }
+        let reply = state.replies.get(&(interface, method)).cloned();
+        drop(state);
+        match reply {
+            Some(reply) => (*reply.lock().unwrap())(msg),
+            None => Ok(msg.method_return()),
+        }
+    }
+}
+
+/// オブジェクトのイントロスペクションのXMLを作成する
+///
+/// メソッドは`on_call`で登録したもののみ、引数を省略して列挙する。
+/// オブジェクトが無いパス(`/org/bluez`など)は空の`node`を返す。
+fn introspect(path: &str, state: &State) -> String {
+    let mut xml = String::from("<node>");
+    for (interface, props) in state.objects.get(path).into_iter().flatten() {
+        xml.push_str(&format!("<interface name=\"{}\">", interface));
+        let mut methods: Vec<_> = state
+            .replies
+            .keys()
+            .filter(|(i, _)| i == interface)
+            .map(|(_, method)| method)
+            .collect();
+        methods.sort();
+        for method in methods {
+            xml.push_str(&format!("<method name=\"{}\"/>", method));
+        }
+        for name in props.keys() {
+            let signature = props.get(name).map(|value| value.signature());
+            xml.push_str(&format!(
+                "<property name=\"{}\" type=\"{}\" access=\"read\"/>",
+                name,
+                signature.as_deref().unwrap_or("v")
+            ));
+        }
+        xml.push_str("</interface>");
+    }
+    xml.push_str("</node>");
+    xml
+}
+
+/// `org.freedesktop.DBus.Properties`のメソッドに応答する
+fn properties(
+    msg: &Message,
+    method: &str,
+    path: &str,
+    state: &mut State,
+) -> Result<Message, BluezError> {
+    let interface = msg.read1::<&str>()?;
+    let props = state
+        .objects
+        .get(path)
+        .and_then(|interfaces| interfaces.get(interface))
+        .ok_or_else(|| error(INVALID_ARGS, &format!("No such interface '{}'", interface)))?;
+    match method {
+        "Get" => {
+            let (_, name) = msg.read2::<&str, &str>()?;
+            let value = props
+                .get(name)
+                .ok_or_else(|| error(INVALID_ARGS, &format!("No such property '{}'", name)))?;
+            Ok(msg.method_return().append1(Variant(value.box_clone())))
+        }
+        "GetAll" => Ok(msg.method_return().append1(props.to_variants())),
+        "Set" => {
+            let (_, name, value) = msg.read3::<&str, &str, Variant<Box<dyn RefArg>>>()?;
+            if !props.contains_key(name) {
+                return Err(error(INVALID_ARGS, &format!("No such property '{}'", name)));
+            }
+            let mut changed = HashMap::new();
+            changed.insert(name.to_string(), value);
+            state.change(path, interface, changed);
+            Ok(msg.method_return())
+        }
+        _ => Err(error(
+            "org.freedesktop.DBus.Error.UnknownMethod",
+            &format!("Unknown method '{}'", method),
+        )),
+    }
+}
+
+fn error(name: &str, message: &str) -> BluezError {
+    dbus::Error::new_custom(name, message).into()
+}
+
+impl blocking::Transport for FakeBluez {
+    fn call(&self, msg: Message, _timeout: Duration) -> Result<Message, BluezError> {
+        self.call(msg)
+    }
+}
+
+impl nonblock::Transport for FakeBluez {
+    fn call(
+        &self,
+        msg: Message,
+        _timeout: Duration,
+    ) -> BoxFuture<'static, Result<Message, BluezError>> {
+        Box::pin(future::ready(self.call(msg)))
+    }
+
+    fn send(&self, msg: Message) {
+        let _ = self.call(msg);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn fake() -> FakeBluez {
+        let fake = FakeBluez::new();
+        fake.set_property("/org/bluez/hci0", ADAPTER_INTERFACE, "Powered", false);
+        fake.set_property(
+            "/org/bluez/hci0/dev_00",
+            "org.bluez.Device1",
+            "Adapter",
+            dbus::Path::from("/org/bluez/hci0"),
+        );
+        fake
+    }
+
+    #[test]
+    fn blocking_session() {
+        let fake = fake();
+        let s = blocking::Session::builder()
+            .transport(Arc::new(fake.clone()))
+            .build()
+            .unwrap();
+        let adapter = blocking::Adapter::create(&s, &"/org/bluez/hci0".parse().unwrap())
+            .unwrap()
+            .unwrap();
+        let devices = adapter.get_devices().unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].get_path().as_str(), "/org/bluez/hci0/dev_00");
+        adapter.set_powered(true).unwrap();
+        assert_eq!(
+            fake.property("/org/bluez/hci0", ADAPTER_INTERFACE, "Powered")
+                .and_then(|value| value.as_i64()),
+            Some(1)
+        );
+        assert!(matches!(adapter.get_name(), Err(BluezError::DBus(_))));
+        let device = &devices[0];
+        assert_eq!(device.get_rssi().unwrap(), None);
+        fake.set_property(
+            "/org/bluez/hci0/dev_00",
+            "org.bluez.Device1",
+            "RSSI",
+            -60i16,
+        );
+        assert_eq!(device.get_rssi().unwrap(), Some(-60));
+        assert!(s.events().is_err());
+
+        fake.remove_object("/org/bluez/hci0");
+        assert!(matches!(
+            adapter.start_discovery(),
+            Err(BluezError::ObjectVanished(_))
+        ));
+    }
+
+    #[test]
+    fn operation_queue_retries() {
+        let fake = fake();
+        let mut busy = 2;
+        fake.on_call("org.bluez.Device1", "Connect", move |msg| {
+            if busy > 0 {
+                busy -= 1;
+                return Err(BluezError::InProgress("In Progress".to_string()));
+            }
+            Ok(msg.method_return())
+        });
+        fake.on_call("org.bluez.Device1", "Pair", |_| {
+            Err(BluezError::AuthenticationFailed(
+                "Authentication Failed".to_string(),
+            ))
+        });
+        let s = blocking::Session::builder()
+            .transport(Arc::new(fake.clone()))
+            .build()
+            .unwrap();
+        let queue = blocking::OperationQueue::new(RetryPolicy {
+            initial_delay: Duration::from_millis(1),
+            ..Default::default()
+        });
+        let device = blocking::Device::new(&s, &"/org/bluez/hci0/dev_00".parse().unwrap());
+        let device = queue.device(&device);
+        assert_eq!(device.connect().unwrap().attempts, 3);
+        let err = device.pair().unwrap_err();
+        assert_eq!(err.attempts, 1);
+        assert!(matches!(err.value, BluezError::AuthenticationFailed(_)));
+    }
+
+    #[test]
+    fn capabilities() {
+        let fake = fake();
+        let session = || {
+            blocking::Session::builder()
+                .transport(Arc::new(fake.clone()))
+                .build()
+                .unwrap()
+        };
+        let s = session();
+        let capabilities = s.capabilities().unwrap();
+        assert!(capabilities.has_property(ADAPTER_INTERFACE, "Powered"));
+        assert!(capabilities.has_interface("org.bluez.Device1"));
+        assert_eq!(capabilities.version, None);
+        let adapter = s.default_adapter().unwrap();
+        assert!(matches!(
+            adapter.connect_device("00:11:22:33:44:66", None),
+            Err(BluezError::Unsupported(_))
+        ));
+        assert!(!fake
+            .calls()
+            .iter()
+            .any(|call| call.method == "ConnectDevice"));
+
+        fake.on_call(ADAPTER_INTERFACE, "ConnectDevice", |msg| {
+            Ok(msg
+                .method_return()
+                .append1(dbus::Path::from("/org/bluez/hci0/dev_00_11_22_33_44_66")))
+        });
+        let s = session();
+        assert_eq!(
+            s.capabilities().unwrap().version,
+            Some(BluezVersion::new(5, 49))
+        );
+        let device = s
+            .default_adapter()
+            .unwrap()
+            .connect_device("00:11:22:33:44:66", Some("random"))
+            .unwrap();
+        assert_eq!(
+            device.get_path().as_str(),
+            "/org/bluez/hci0/dev_00_11_22_33_44_66"
+        );
+    }
+
+    #[test]
+    fn nonblock_session() {
+        let fake = fake();
+        fake.on_call(ADAPTER_INTERFACE, "StartDiscovery", |_| {
+            Err(BluezError::NotReady("Resource Not Ready".to_string()))
+        });
+        let metrics = Arc::new(CallMetrics::new());
+        let s = nonblock::Session::builder()
+            .transport(Arc::new(fake.clone()))
+            .metrics(metrics.clone())
+            .build()
+            .unwrap();
+        futures::executor::block_on(async {
+            let adapter = nonblock::Adapter::create(&s, &"/org/bluez/hci0".parse().unwrap())
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(!adapter.is_powered().await.unwrap());
+            assert!(matches!(
+                adapter.start_discovery().await,
+                Err(BluezError::NotReady(_))
+            ));
+        });
+        let methods: Vec<_> = fake.calls().into_iter().map(|call| call.method).collect();
+        assert_eq!(methods, vec!["GetManagedObjects", "Get", "StartDiscovery"]);
+        let stats = metrics.snapshot();
+        let key = |interface: &str, member: &str| (interface.to_string(), member.to_string());
+        assert_eq!(stats[&key(ADAPTER_INTERFACE, "Get")].calls, 1);
+        let start = &stats[&key(ADAPTER_INTERFACE, "StartDiscovery")];
+        assert_eq!(start.errors, 1);
+        assert_eq!(start.error_names["org.bluez.Error.NotReady"], 1);
+        assert_eq!(stats.len(), 3);
+    }
+
+    /// `Pair`と`Connect`に応答しない通信路
+    struct Unanswered(FakeBluez);
+
+    impl nonblock::Transport for Unanswered {
+        fn call(
+            &self,
+            msg: Message,
+            timeout: Duration,
+        ) -> BoxFuture<'static, Result<Message, BluezError>> {
+            match msg.member().as_deref() {
+                Some("Pair") | Some("Connect") => Box::pin(future::pending()),
+                _ => nonblock::Transport::call(&self.0, msg, timeout),
+            }
+        }
+
+        fn send(&self, msg: Message) {
+            nonblock::Transport::send(&self.0, msg)
+        }
+    }
+
+    #[test]
+    fn nonblock_cancel_on_drop() {
+        use futures::FutureExt;
+
+        let fake = fake();
+        let s = nonblock::Session::builder()
+            .transport(Arc::new(Unanswered(fake.clone())))
+            .build()
+            .unwrap();
+        let device = nonblock::Device::new(&s, &"/org/bluez/hci0/dev_00".parse().unwrap());
+        assert!(device.pair_cancel_safe().now_or_never().is_none());
+        assert!(device.connect_cancel_safe().now_or_never().is_none());
+        fake.on_call("org.bluez.Device1", "Connect", |_| {
+            Err(BluezError::Failed("Operation failed".to_string()))
+        });
+        let fake_device = nonblock::Device::new(
+            &nonblock::Session::builder()
+                .transport(Arc::new(fake.clone()))
+                .build()
+                .unwrap(),
+            &"/org/bluez/hci0/dev_00".parse().unwrap(),
+        );
+        assert!(futures::executor::block_on(fake_device.connect_cancel_safe()).is_err());
+
+        let methods: Vec<_> = fake.calls().into_iter().map(|call| call.method).collect();
+        assert_eq!(methods, vec!["CancelPairing", "Disconnect", "Connect"]);
+    }
+
+    /// 最初のポーリングでは送信せず、次のポーリングで送信して応答を返す通信路
+    struct Deferred(FakeBluez);
+
+    impl nonblock::Transport for Deferred {
+        fn call(
+            &self,
+            msg: Message,
+            _timeout: Duration,
+        ) -> BoxFuture<'static, Result<Message, BluezError>> {
+            let fake = self.0.clone();
+            let mut msg = Some(msg);
+            let mut deferred = true;
+            Box::pin(future::poll_fn(move |cx| {
+                if std::mem::take(&mut deferred) {
+                    cx.waker().wake_by_ref();
+                    return std::task::Poll::Pending;
+                }
+                std::task::Poll::Ready(fake.call(msg.take().unwrap()))
+            }))
+        }
+
+        fn send(&self, msg: Message) {
+            let _ = self.0.call(msg);
+        }
+    }
+
+    #[test]
+    fn nonblock_cancel_on_drop_with_deferred_transport() {
+        use futures::FutureExt;
+
+        let fake = fake();
+        let s = nonblock::Session::builder()
+            .transport(Arc::new(Deferred(fake.clone())))
+            .build()
+            .unwrap();
+        let device = nonblock::Device::new(&s, &"/org/bluez/hci0/dev_00".parse().unwrap());
+        assert!(device.pair_cancel_safe().now_or_never().is_none());
+        assert!(device.connect_cancel_safe().now_or_never().is_none());
+
+        let methods: Vec<_> = fake.calls().into_iter().map(|call| call.method).collect();
+        assert_eq!(methods, vec!["CancelPairing", "Disconnect"]);
+    }
+}
@@ -0,0 +1,153 @@
+use dbus::channel::Token;
+use dbus::message::{MatchRule, MessageType};
+use dbus::Message;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+// 一覧のロックを解放してから呼ぶため、個別に共有する
+type Handler = Arc<Mutex<dyn FnMut(&Message) + Send>>;
+
+/// シグナルの受信者の一覧
+///
+/// dbusクレートのコネクションは一つのメッセージを最初に一致した受信者にしか渡さないため、
+/// 全てのシグナルを一つの受信者で受け取り、ここから一致する受信者全てに配る。
+/// イベントの受信とキャッシュの更新のように、同じシグナルを複数の受信者が必要とする場合に使用する。
+#[derive(Default)]
+pub(in crate) struct Handlers {
+    next: usize,
+    list: BTreeMap<usize, (MatchRule<'static>, Handler)>,
+    tap: Option<Handler>,
+}
+
+impl Handlers {
+    /// コネクションに登録する、全てのシグナルを受け取るルール
+    pub(in crate) fn rule() -> MatchRule<'static> {
+        let mut rule = MatchRule::new();
+        rule.msg_type = Some(MessageType::Signal);
+        rule
+    }
+
+    pub(in crate) fn add<F>(&mut self, rule: MatchRule<'static>, f: F) -> Token
+    where
+        F: FnMut(&Message) + Send + 'static,
+    {
+        self.next += 1;
+        self.list.insert(self.next, (rule, Arc::new(Mutex::new(f))));
+        Token(self.next)
+    }
+
+    /// 登録されている受信者のルール(D-Busに登録する形式)
+    pub(in crate) fn rules(&self) -> Vec<String> {
+        self.list
+            .values()
+            .map(|(rule, _)| rule.match_str())
+            .collect()
+    }
+
+    /// ルールに関わらず全てのシグナルを受け取る受信者(記録用)を設定する
+    ///
+    /// D-Busにはルールを登録しないため、他の受信者のルールに一致したシグナルのみが届く。
+    #[cfg_attr(not(feature = "record"), allow(dead_code))]
+    pub(in crate) fn tap<F>(&mut self, f: F)
+    where
+        F: FnMut(&Message) + Send + 'static,
+    {
+        self.tap = Some(Arc::new(Mutex::new(f)));
+    }
+
+    /// 受信者を削除し、登録時のルールを返す
+    pub(in crate) fn remove(&mut self, token: Token) -> Option<MatchRule<'static>> {
+        self.list.remove(&token.0).map(|(rule, _)| rule)
+    }
+
+    /// 全ての受信者を削除する
+    pub(in crate) fn clear(&mut self) {
+        self.list.clear();
+    }
+
+    /// ルールが一致する全ての受信者にメッセージを渡す
+    ///
+    /// 受信者は一覧のロックを解放してから呼ぶため、受信者の中で受信者の登録や削除を行える。
+    /// 別のスレッドで削除した受信者は、削除の直前に受信したメッセージで呼ばれることがある。
+    pub(in crate) fn dispatch(handlers: &Mutex<Handlers>, msg: &Message) {
+        let matched: Vec<Handler> = {
+            let handlers = handlers.lock().unwrap();
+            let list = handlers
+                .list
+                .values()
+                .filter(|(rule, _)| rule.matches(msg))
+                .map(|(_, f)| f);
+            handlers.tap.iter().chain(list).cloned().collect()
+        };
+        for f in matched {
+            (f.lock().unwrap())(msg);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    fn changed() -> Message {
+        Message::signal(&"/".into(), &"org.bluez.Test".into(), &"Changed".into())
+    }
+
+    #[test]
+    fn dispatch_to_all_matching() {
+        let handlers = Mutex::new(Handlers::default());
+        let (tx, rx) = mpsc::channel();
+        let rule = MatchRule::new_signal("org.bluez.Test", "Changed");
+        let first = {
+            let tx = tx.clone();
+            handlers
+                .lock()
+                .unwrap()
+                .add(rule.clone(), move |_| tx.send(1).unwrap())
+        };
+        handlers
+            .lock()
+            .unwrap()
+            .add(rule, move |_| tx.send(2).unwrap());
+        handlers
+            .lock()
+            .unwrap()
+            .add(MatchRule::new_signal("org.bluez.Test", "Other"), |_| {
+                panic!("should not be called")
+            });
+
+        Handlers::dispatch(&handlers, &changed());
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![1, 2]);
+
+        assert!(handlers.lock().unwrap().remove(first).is_some());
+        assert!(handlers.lock().unwrap().remove(first).is_none());
+        Handlers::dispatch(&handlers, &changed());
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![2]);
+    }
+
+    #[test]
+    fn modify_within_handler() {
+        let handlers = Arc::new(Mutex::new(Handlers::default()));
+        let (tx, rx) = mpsc::channel();
+        let rule = MatchRule::new_signal("org.bluez.Test", "Changed");
+        let token = Arc::new(Mutex::new(None));
+        let first = {
+            let (inner, token) = (handlers.clone(), token.clone());
+            handlers.lock().unwrap().add(rule.clone(), move |_| {
+                // 自身を削除し、代わりの受信者を登録する
+                let mut handlers = inner.lock().unwrap();
+                handlers.remove(token.lock().unwrap().take().unwrap());
+                let replaced = tx.clone();
+                handlers.add(rule.clone(), move |_| replaced.send(2).unwrap());
+                tx.send(1).unwrap();
+            })
+        };
+        *token.lock().unwrap() = Some(first);
+
+        Handlers::dispatch(&handlers, &changed());
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![1]);
+        Handlers::dispatch(&handlers, &changed());
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![2]);
+    }
+}
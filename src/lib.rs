@@ -1,40 +1,134 @@
 use dbus::arg;
-use dbus::arg::RefArg;
+use dbus::channel::{BusType, Channel};
+use dbus::message::MatchRule;
 use std::collections::HashMap;
-use std::error::Error;
+use std::time::Duration;
+
+// インターフェースごとのマクロ(build.rsがinterfaces/*.xmlから生成する)
+include!(concat!(env!("OUT_DIR"), "/interfaces.rs"));
 
 pub mod blocking;
+mod cache;
+use cache::ObjectCache;
+mod capabilities;
+pub use capabilities::{BluezVersion, Capabilities, InterfaceCapabilities};
+mod deadline;
+pub use deadline::Deadline;
+use deadline::Timeout;
+mod decode;
+pub use decode::{DecodeError, DictKey, FromRefArg};
+mod driver;
+mod error;
+pub use error::BluezError;
+#[doc(hidden)]
+pub use error::absent_as_none;
+mod event;
+pub use event::Event;
+mod fake;
+pub use fake::{FakeBluez, FakeCall};
+mod handlers;
+use handlers::Handlers;
+mod metrics;
+pub use metrics::{CallMetrics, CallRecord, CallStats, Metrics, LATENCY_BUCKETS};
+use metrics::Call;
+#[cfg(feature = "mock")]
+pub mod mock;
 pub mod nonblock;
+mod path;
+pub use path::{AdapterPath, CharacteristicPath, DescriptorPath, DevicePath, ServicePath};
+mod properties;
+pub use properties::Properties;
+mod property;
+pub use property::{
+    AdapterInfo, AdapterProperty, CharacteristicInfo, CharacteristicProperty, DescriptorInfo,
+    DescriptorProperty, DeviceInfo, DeviceProperty, GattServiceInfo, GattServiceProperty,
+};
+use property::{PropertyChange, PropertyInfo};
+#[cfg(feature = "record")]
+mod record;
+#[cfg(feature = "record")]
+pub use record::{Recorder, Replay};
+mod retry;
+pub use retry::{Retried, RetryPolicy};
+mod value;
+mod xml;
 
 type ManagedObjectInterfaces =
     HashMap<String, HashMap<String, arg::Variant<Box<dyn arg::RefArg + 'static>>>>;
 type ManagedObject = HashMap<dbus::Path<'static>, ManagedObjectInterfaces>;
 
-type BoxError = Box<dyn Error + 'static>;
-
 static BLUEZ_SERVICE: &str = "org.bluez";
+static OBJECT_MANAGER_INTERFACE: &str = "org.freedesktop.DBus.ObjectManager";
+static PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+static INTROSPECTABLE_INTERFACE: &str = "org.freedesktop.DBus.Introspectable";
 static ADAPTER_INTERFACE: &str = "org.bluez.Adapter1";
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 接続するD-Busの種類
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Bus {
+    /// システムバス(既定値)
+    #[default]
+    System,
+    /// セッションバス
+    Session,
+    /// 指定アドレスのバス(例: `unix:path=/tmp/bluez-test`)
+    Address(String),
+}
+
+impl Bus {
+    /// バスとのチャネルを開く
+    fn open_channel(&self) -> Result<Channel, BluezError> {
+        let channel = match self {
+            Bus::System => Channel::get_private(BusType::System)?,
+            Bus::Session => Channel::get_private(BusType::Session)?,
+            Bus::Address(address) => {
+                let mut channel = Channel::open_private(address)?;
+                channel.register()?;
+                channel
+            }
+        };
+        Ok(channel)
+    }
+}
+
+/// 指定の送信元からのシグナルを受信するルール
+fn signal(sender: &str, interface: &'static str, member: &'static str) -> MatchRule<'static> {
+    let mut rule = MatchRule::new_signal(interface, member);
+    rule.sender = Some(sender.to_string().into());
+    rule
+}
 
 /// BlueZの`managed object`から値を取得する
 trait TypeUtil {
-    fn get_str(&self, key: &str) -> Option<String>;
+    /// 型を指定して取得する(存在しないか、型が一致しない場合は`None`)
+    fn get_as<T: FromRefArg>(&self, key: &str) -> Option<T>;
 }
 
 impl TypeUtil for HashMap<String, arg::Variant<Box<dyn arg::RefArg + 'static>>> {
-    fn get_str(&self, key: &str) -> Option<String> {
-        if let Some(value) = self.get(key) {
-            if let Some(s) = value.as_str() {
-                return Some(s.to_string());
-            }
-        }
-        None
+    fn get_as<T: FromRefArg>(&self, key: &str) -> Option<T> {
+        self.get(key).and_then(|value| T::from_refarg(&*value.0).ok())
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    fn assert_send_sync<T: Send + Sync + 'static>() {}
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn blocking_handles_are_send_sync() {
+        assert_send_sync::<blocking::Session>();
+        assert_send_sync::<blocking::Adapter>();
+        assert_send_sync::<blocking::Device>();
+        assert_send_sync::<blocking::GattService>();
+        assert_send_sync::<blocking::Characteristic>();
+        assert_send_sync::<blocking::Descriptor>();
+    }
 }
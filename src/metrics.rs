@@ -0,0 +1,260 @@
+use crate::*;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// BlueZの呼び出し一回分の記録
+///
+/// プロパティの取得・設定は、`interface`に対象のインターフェース、`member`に`Get`、`Set`、`GetAll`、
+/// `property`にプロパティ名が入る。
+#[derive(Debug, Clone)]
+pub struct CallRecord {
+    pub path: String,
+    pub interface: String,
+    pub member: String,
+    pub property: Option<String>,
+    /// 送信から応答(またはエラー)までの時間
+    pub duration: Duration,
+    /// 失敗した場合のエラー名(D-Busのエラー名、またはD-Bus由来でないエラーの種類)
+    pub error: Option<String>,
+}
+
+/// BlueZの呼び出しを計測するフック
+///
+/// `SessionBuilder::metrics`に指定すると、メソッド呼び出し、プロパティの取得・設定、
+/// `GetManagedObjects`の完了ごとに呼ばれる。
+/// 呼び出し元のスレッドで呼ばれるため、時間のかかる処理は行わないこと。
+pub trait Metrics: Send + Sync {
+    fn record(&self, call: &CallRecord);
+}
+
+/// 所要時間の分布の区切り(この値以下の件数を数える)
+pub const LATENCY_BUCKETS: [Duration; 8] = [
+    Duration::from_millis(1),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_secs(5),
+];
+
+/// インターフェースとメソッドごとの集計
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CallStats {
+    pub calls: u64,
+    pub errors: u64,
+    /// エラー名ごとの件数
+    pub error_names: BTreeMap<String, u64>,
+    pub total: Duration,
+    pub max: Duration,
+    /// `LATENCY_BUCKETS`の各区切り以下の件数(最後の要素は全ての区切りを超えた件数)
+    pub buckets: [u64; LATENCY_BUCKETS.len() + 1],
+}
+
+/// インターフェースとメソッドごとに回数と所要時間の分布を集計する`Metrics`
+///
+/// 集計結果は`snapshot`で取得し、アプリケーションの監視の仕組みに書き出す。
+///
+/// ```no_run
+/// use bluez_dbus::blocking::Session;
+/// use bluez_dbus::CallMetrics;
+/// use std::sync::Arc;
+///
+/// let metrics = Arc::new(CallMetrics::new());
+/// let s = Session::builder().metrics(metrics.clone()).build()?;
+/// let _ = s.get_adapters()?;
+/// for ((interface, member), stats) in metrics.snapshot() {
+///     println!("{}.{}: {} calls, {:?}", interface, member, stats.calls, stats.total);
+/// }
+/// # Ok::<(), bluez_dbus::BluezError>(())
+/// ```
+#[derive(Debug, Default)]
+pub struct CallMetrics {
+    stats: Mutex<BTreeMap<(String, String), CallStats>>,
+}
+
+impl CallMetrics {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// `(インターフェース, メソッド)`ごとの集計結果
+    pub fn snapshot(&self) -> BTreeMap<(String, String), CallStats> {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// 集計結果を消去する
+    pub fn reset(&self) {
+        self.stats.lock().unwrap().clear();
+    }
+}
+
+impl Metrics for CallMetrics {
+    fn record(&self, call: &CallRecord) {
+        let mut stats = self.stats.lock().unwrap();
+        let stats = stats
+            .entry((call.interface.clone(), call.member.clone()))
+            .or_default();
+        stats.calls += 1;
+        if let Some(error) = &call.error {
+            stats.errors += 1;
+            *stats.error_names.entry(error.clone()).or_default() += 1;
+        }
+        stats.total += call.duration;
+        stats.max = std::cmp::max(stats.max, call.duration);
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| call.duration <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        stats.buckets[bucket] += 1;
+    }
+}
+
+/// エラー名(D-Bus由来でないエラーは種類を表す名前)
+fn error_name(err: &BluezError) -> String {
+    match err.name() {
+        Some(name) => name.to_string(),
+        None => match err {
+            BluezError::Timeout(_) => "timeout",
+            BluezError::TypeMismatch(_) => "type_mismatch",
+            BluezError::ObjectVanished(_) => "object_vanished",
+            BluezError::ConnectionLost(_) => "connection_lost",
+            BluezError::Unsupported(_) => "unsupported",
+            _ => "runtime",
+        }
+        .to_string(),
+    }
+}
+
+/// 計測中の呼び出し
+///
+/// `tracing`が有効な場合は呼び出しごとにスパンを作成し、終了時に所要時間とエラー名を記録する。
+pub(in crate) struct Call {
+    metrics: Option<Arc<dyn Metrics>>,
+    path: String,
+    interface: String,
+    member: String,
+    property: Option<String>,
+    start: Instant,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+impl Call {
+    pub(in crate) fn start(
+        metrics: Option<Arc<dyn Metrics>>,
+        path: &str,
+        interface: &str,
+        member: &str,
+        property: Option<&str>,
+    ) -> Self {
+        Call {
+            metrics,
+            path: path.to_string(),
+            interface: interface.to_string(),
+            member: member.to_string(),
+            property: property.map(|property| property.to_string()),
+            start: Instant::now(),
+            #[cfg(feature = "tracing")]
+            span: tracing::debug_span!(
+                "bluez_call",
+                path,
+                interface,
+                member,
+                property,
+                duration_ms = tracing::field::Empty,
+                error = tracing::field::Empty,
+            ),
+        }
+    }
+
+    /// スパンの中で`f`を実行する
+    pub(in crate) fn in_scope<T>(&self, f: impl FnOnce() -> T) -> T {
+        #[cfg(feature = "tracing")]
+        return self.span.in_scope(f);
+        #[cfg(not(feature = "tracing"))]
+        f()
+    }
+
+    /// スパンの中で`future`を実行する
+    #[cfg(feature = "tracing")]
+    pub(in crate) fn instrument<F: Future>(&self, future: F) -> impl Future<Output = F::Output> {
+        tracing::Instrument::instrument(future, self.span.clone())
+    }
+
+    /// スパンの中で`future`を実行する
+    #[cfg(not(feature = "tracing"))]
+    pub(in crate) fn instrument<F: Future>(&self, future: F) -> impl Future<Output = F::Output> {
+        future
+    }
+
+    /// 所要時間と結果を記録する
+    pub(in crate) fn finish<T>(self, result: &Result<T, BluezError>) {
+        let duration = self.start.elapsed();
+        let error = result.as_ref().err().map(error_name);
+        #[cfg(feature = "tracing")]
+        {
+            self.span
+                .record("duration_ms", duration.as_secs_f64() * 1000.0);
+            if let Some(error) = &error {
+                self.span.record("error", error.as_str());
+            }
+            self.span.in_scope(|| match &error {
+                Some(error) => tracing::debug!(?duration, error = error.as_str(), "call failed"),
+                None => tracing::trace!(?duration, "call completed"),
+            });
+        }
+        if let Some(metrics) = &self.metrics {
+            metrics.record(&CallRecord {
+                path: self.path,
+                interface: self.interface,
+                member: self.member,
+                property: self.property,
+                duration,
+                error,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(member: &str, duration: Duration, error: Option<&str>) -> CallRecord {
+        CallRecord {
+            path: "/org/bluez/hci0/dev_00".to_string(),
+            interface: "org.bluez.Device1".to_string(),
+            member: member.to_string(),
+            property: None,
+            duration,
+            error: error.map(|error| error.to_string()),
+        }
+    }
+
+    #[test]
+    fn aggregate_per_method() {
+        let metrics = CallMetrics::new();
+        metrics.record(&record("Connect", Duration::from_millis(3), None));
+        metrics.record(&record(
+            "Connect",
+            Duration::from_secs(10),
+            Some("org.bluez.Error.Failed"),
+        ));
+        metrics.record(&record("Get", Duration::from_micros(200), None));
+
+        let snapshot = metrics.snapshot();
+        let connect = &snapshot[&("org.bluez.Device1".to_string(), "Connect".to_string())];
+        assert_eq!(connect.calls, 2);
+        assert_eq!(connect.errors, 1);
+        assert_eq!(connect.error_names["org.bluez.Error.Failed"], 1);
+        assert_eq!(connect.max, Duration::from_secs(10));
+        assert_eq!(connect.buckets[1], 1);
+        assert_eq!(connect.buckets[LATENCY_BUCKETS.len()], 1);
+        let get = &snapshot[&("org.bluez.Device1".to_string(), "Get".to_string())];
+        assert_eq!(get.buckets[0], 1);
+    }
+}
@@ -0,0 +1,983 @@
+use crate::*;
+use dbus::arg::{RefArg, Variant};
+use dbus::blocking::Connection;
+use dbus::channel::{MatchingReceiver, Sender};
+use dbus::message::MatchRule;
+use dbus::Message;
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::ffi::CString;
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::process::{self, Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+static MOCK_ADAPTER: &str = "/org/bluez/hci0";
+static DEVICE_INTERFACE: &str = "org.bluez.Device1";
+static GATT_SERVICE_INTERFACE: &str = "org.bluez.GattService1";
+static CHARACTERISTIC_INTERFACE: &str = "org.bluez.GattCharacteristic1";
+static DESCRIPTOR_INTERFACE: &str = "org.bluez.GattDescriptor1";
+
+/// メソッド呼び出しとシグナルの送信を処理する間隔
+const PROCESS_INTERVAL: Duration = Duration::from_millis(20);
+
+/// メソッドのオプションの辞書(`a{sv}`)
+type Options = HashMap<String, Variant<Box<dyn RefArg>>>;
+
+/// D-Busで公開する、BlueZの動作を模倣するサービス
+///
+/// アダプター`/org/bluez/hci0`を一つ持ち、`Adapter1`、`Device1`、`GattService1`、
+/// `GattCharacteristic1`、`GattDescriptor1`のメソッドに応答する。
+/// オブジェクトやプロパティの変更は`InterfacesAdded`、`InterfacesRemoved`、
+/// `PropertiesChanged`のシグナルで通知する。
+///
+/// - `StartDiscovery`で`add_discoverable`に指定したデバイスが指定時間後に現れる
+/// - `Connect`でGATTのオブジェクトが現れ、`ServicesResolved`が`true`になる
+/// - `MockDevice::connect_error`を指定したデバイスの`Connect`は失敗する
+/// - `notify`でキャラクタリスティックの値の変更を通知する
+///
+/// `serve`でプライベートなバスに`org.bluez`として公開すると、
+/// Bluetoothのハードウェアがない環境でも既存のアプリケーションをそのまま動かせる。
+///
+/// ```no_run
+/// use bluez_dbus::blocking::{Adapter, Device, Session};
+/// use bluez_dbus::mock::{MockBluez, MockDevice, PrivateBus};
+/// use std::time::Duration;
+///
+/// let bus = PrivateBus::spawn()?;
+/// let mock = MockBluez::new();
+/// mock.add_discoverable(
+///     MockDevice::new("00:11:22:33:44:55").name("sensor"),
+///     Duration::from_millis(500),
+/// );
+/// let _server = mock.serve(&bus.bus())?;
+///
+/// let s = Session::builder().bus(bus.bus()).build()?;
+/// let adapter = Adapter::create(&s, &"/org/bluez/hci0".parse()?)?.unwrap();
+/// adapter.start_discovery()?;
+/// std::thread::sleep(Duration::from_secs(1));
+/// let device = Device::new(&s, &"/org/bluez/hci0/dev_00_11_22_33_44_55".parse()?);
+/// device.connect()?;
+/// # Ok::<(), bluez_dbus::BluezError>(())
+/// ```
+#[derive(Clone)]
+pub struct MockBluez {
+    fake: FakeBluez,
+    state: Arc<Mutex<MockState>>,
+}
+
+#[derive(Default)]
+struct MockState {
+    /// デバイスのパスごとの定義
+    devices: HashMap<String, MockDevice>,
+    /// 探索で現れるデバイスと、探索開始から現れるまでの時間
+    discoverable: Vec<(String, Duration)>,
+    /// 探索を開始した時刻(探索中でない場合は`None`)
+    discovery: Option<Instant>,
+}
+
+/// `MockBluez`のデバイスの定義
+#[derive(Debug, Clone)]
+pub struct MockDevice {
+    address: String,
+    name: Option<String>,
+    rssi: Option<i16>,
+    connect_error: Option<(String, String)>,
+    services: Vec<MockService>,
+}
+
+/// `MockDevice`のGATTサービスの定義
+#[derive(Debug, Clone)]
+pub struct MockService {
+    uuid: String,
+    characteristics: Vec<MockCharacteristic>,
+}
+
+/// `MockService`のキャラクタリスティックの定義
+#[derive(Debug, Clone)]
+pub struct MockCharacteristic {
+    uuid: String,
+    flags: Vec<String>,
+    value: Vec<u8>,
+    descriptors: Vec<(String, Vec<u8>)>,
+}
+
+impl MockDevice {
+    pub fn new(address: &str) -> Self {
+        MockDevice {
+            address: address.to_string(),
+            name: None,
+            rssi: None,
+            connect_error: None,
+            services: vec![],
+        }
+    }
+
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    pub fn rssi(mut self, rssi: i16) -> Self {
+        self.rssi = Some(rssi);
+        self
+    }
+
+    /// `Connect`で返すエラー(例: `org.bluez.Error.Failed`)
+    pub fn connect_error(mut self, name: &str, message: &str) -> Self {
+        self.connect_error = Some((name.to_string(), message.to_string()));
+        self
+    }
+
+    /// 接続時に現れるGATTサービスを追加
+    pub fn service(mut self, service: MockService) -> Self {
+        self.services.push(service);
+        self
+    }
+
+    /// アダプター配下のデバイスのパス
+    ///
+    /// アドレスが`XX:XX:XX:XX:XX:XX`の形式でない場合はパニックする。
+    pub fn path(&self) -> DevicePath {
+        DevicePath::new(&format!(
+            "{}/dev_{}",
+            MOCK_ADAPTER,
+            self.address.replace(':', "_")
+        ))
+        .expect("invalid device address")
+    }
+}
+
+impl MockService {
+    pub fn new(uuid: &str) -> Self {
+        MockService {
+            uuid: uuid.to_string(),
+            characteristics: vec![],
+        }
+    }
+
+    pub fn characteristic(mut self, characteristic: MockCharacteristic) -> Self {
+        self.characteristics.push(characteristic);
+        self
+    }
+}
+
+impl MockCharacteristic {
+    pub fn new(uuid: &str) -> Self {
+        MockCharacteristic {
+            uuid: uuid.to_string(),
+            flags: vec![],
+            value: vec![],
+            descriptors: vec![],
+        }
+    }
+
+    /// フラグ(例: `read`、`write`、`notify`)
+    pub fn flags(mut self, flags: &[&str]) -> Self {
+        self.flags = flags.iter().map(|flag| flag.to_string()).collect();
+        self
+    }
+
+    /// 値の初期値
+    pub fn value(mut self, value: Vec<u8>) -> Self {
+        self.value = value;
+        self
+    }
+
+    pub fn descriptor(mut self, uuid: &str, value: Vec<u8>) -> Self {
+        self.descriptors.push((uuid.to_string(), value));
+        self
+    }
+}
+
+impl Default for MockBluez {
+    fn default() -> Self {
+        MockBluez::new()
+    }
+}
+
+impl MockBluez {
+    /// アダプターを一つ持つモックの作成
+    pub fn new() -> Self {
+        let mock = MockBluez {
+            fake: FakeBluez::new(),
+            state: Default::default(),
+        };
+        let mut props = Props::new();
+        props.insert("Address", "00:AA:BB:CC:DD:EE".to_string());
+        props.insert("AddressType", "public".to_string());
+        props.insert("Name", "mock".to_string());
+        props.insert("Alias", "mock".to_string());
+        props.insert("Class", 0u32);
+        props.insert("Powered", true);
+        props.insert("Discoverable", false);
+        props.insert("Pairable", true);
+        props.insert("PairableTimeout", 0u32);
+        props.insert("DiscoverableTimeout", 180u32);
+        props.insert("Discovering", false);
+        props.insert("UUIDs", Vec::<String>::new());
+        props.insert("Modalias", "usb:v1D6Bp0246d0535".to_string());
+        mock.fake
+            .add_interface(MOCK_ADAPTER, ADAPTER_INTERFACE, props.into());
+        mock.script();
+        mock
+    }
+
+    /// オブジェクトとプロパティを保持している`FakeBluez`
+    ///
+    /// 任意のプロパティの変更や、メソッドへの応答の差し替えに使用する。
+    pub fn fake(&self) -> &FakeBluez {
+        &self.fake
+    }
+
+    /// 既に見つかっているデバイスを追加
+    pub fn add_device(&self, device: MockDevice) -> DevicePath {
+        let path = device.path();
+        self.state
+            .lock()
+            .unwrap()
+            .devices
+            .insert(path.to_string(), device);
+        self.export_device(&path);
+        path
+    }
+
+    /// 探索の開始から`delay`後に現れるデバイスを追加
+    pub fn add_discoverable(&self, device: MockDevice, delay: Duration) -> DevicePath {
+        let path = device.path();
+        let mut state = self.state.lock().unwrap();
+        state.devices.insert(path.to_string(), device);
+        state.discoverable.push((path.to_string(), delay));
+        path
+    }
+
+    /// キャラクタリスティックの値を変更し、`PropertiesChanged`で通知する
+    pub fn notify(&self, characteristic: &str, value: Vec<u8>) {
+        self.fake
+            .set_property(characteristic, CHARACTERISTIC_INTERFACE, "Value", value);
+    }
+
+    /// 指定のバスに`org.bluez`として公開する
+    ///
+    /// 返された`MockServer`が破棄されるまで、専用スレッドで応答する。
+    pub fn serve(&self, bus: &Bus) -> Result<MockServer, BluezError> {
+        self.serve_as(bus, BLUEZ_SERVICE)
+    }
+
+    /// 指定のバスに指定のサービス名で公開する
+    pub fn serve_as(&self, bus: &Bus, service: &str) -> Result<MockServer, BluezError> {
+        let conn = Connection::from(bus.open_channel()?);
+        conn.request_name(service, false, true, true)?;
+
+        // メソッドの処理中に発生したシグナルを応答より先に送れるよう、キューに溜める
+        let signals = Arc::new(Mutex::new(VecDeque::new()));
+        {
+            let signals = signals.clone();
+            self.fake
+                .on_signal(move |msg| signals.lock().unwrap().push_back(msg));
+        }
+        {
+            let fake = self.fake.clone();
+            let signals = signals.clone();
+            conn.start_receive(
+                MatchRule::new_method_call(),
+                Box::new(move |msg: Message, conn: &Connection| {
+                    let reply = match fake.reply(&msg) {
+                        Ok(reply) => reply,
+                        Err(err) => error_reply(&msg, &err),
+                    };
+                    flush(conn, &signals);
+                    let _ = conn.send(reply);
+                    true
+                }),
+            );
+        }
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = stop.clone();
+            let mock = self.clone();
+            thread::Builder::new()
+                .name("bluez-dbus-mock".to_string())
+                .spawn(move || {
+                    while !stop.load(Ordering::Acquire) {
+                        if conn.process(PROCESS_INTERVAL).is_err() {
+                            break;
+                        }
+                        mock.tick();
+                        flush(&conn, &signals);
+                    }
+                })
+                .map_err(|e| BluezError::Runtime(e.to_string()))?
+        };
+        Ok(MockServer {
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// 探索で現れる時刻になったデバイスを追加する
+    fn tick(&self) {
+        let ready: Vec<String> = {
+            let mut state = self.state.lock().unwrap();
+            let started = match state.discovery {
+                Some(started) => started,
+                None => return,
+            };
+            let elapsed = started.elapsed();
+            let (ready, waiting) = state
+                .discoverable
+                .drain(..)
+                .partition(|(_, delay)| *delay <= elapsed);
+            state.discoverable = waiting;
+            ready.into_iter().map(|(path, _)| path).collect()
+        };
+        for path in ready {
+            self.export_device(&path);
+        }
+    }
+
+    fn export_device(&self, path: &str) {
+        let device = match self.state.lock().unwrap().devices.get(path) {
+            Some(device) => device.clone(),
+            None => return,
+        };
+        let mut props = Props::new();
+        props.insert("Address", device.address.clone());
+        props.insert("AddressType", "public".to_string());
+        if let Some(name) = &device.name {
+            props.insert("Name", name.clone());
+        }
+        props.insert(
+            "Alias",
+            device
+                .name
+                .clone()
+                .unwrap_or_else(|| device.address.replace(':', "-")),
+        );
+        props.insert("Adapter", dbus::Path::from(MOCK_ADAPTER));
+        props.insert("Paired", false);
+        props.insert("Connected", false);
+        props.insert("Trusted", false);
+        props.insert("Blocked", false);
+        props.insert("LegacyPairing", false);
+        props.insert(
+            "UUIDs",
+            device
+                .services
+                .iter()
+                .map(|service| service.uuid.clone())
+                .collect::<Vec<_>>(),
+        );
+        if let Some(rssi) = device.rssi {
+            props.insert("RSSI", rssi);
+        }
+        props.insert("ServicesResolved", false);
+        self.fake
+            .add_interface(path, DEVICE_INTERFACE, props.into());
+    }
+
+    /// 接続したデバイスのGATTのオブジェクトを追加する
+    fn export_gatt(&self, path: &str) {
+        let device = match self.state.lock().unwrap().devices.get(path) {
+            Some(device) => device.clone(),
+            None => return,
+        };
+        // BlueZと同様に、パスにはハンドルの番号を使用する
+        let mut handle = 0u16;
+        for service in &device.services {
+            handle += 1;
+            let service_path = format!("{}/service{:04x}", path, handle);
+            let mut props = Props::new();
+            props.insert("UUID", service.uuid.clone());
+            props.insert("Primary", true);
+            props.insert("Device", dbus::Path::from(path.to_string()));
+            props.insert("Includes", Vec::<dbus::Path>::new());
+            self.fake
+                .add_interface(&service_path, GATT_SERVICE_INTERFACE, props.into());
+            for characteristic in &service.characteristics {
+                handle += 1;
+                let char_path = format!("{}/char{:04x}", service_path, handle);
+                let mut props = Props::new();
+                props.insert("UUID", characteristic.uuid.clone());
+                props.insert("Service", dbus::Path::from(service_path.clone()));
+                props.insert("Value", characteristic.value.clone());
+                props.insert("Notifying", false);
+                props.insert("Flags", characteristic.flags.clone());
+                self.fake
+                    .add_interface(&char_path, CHARACTERISTIC_INTERFACE, props.into());
+                for (uuid, value) in &characteristic.descriptors {
+                    handle += 1;
+                    let desc_path = format!("{}/desc{:04x}", char_path, handle);
+                    let mut props = Props::new();
+                    props.insert("UUID", uuid.clone());
+                    props.insert("Characteristic", dbus::Path::from(char_path.clone()));
+                    props.insert("Value", value.clone());
+                    props.insert("Flags", vec!["read".to_string(), "write".to_string()]);
+                    self.fake
+                        .add_interface(&desc_path, DESCRIPTOR_INTERFACE, props.into());
+                }
+            }
+        }
+    }
+
+    /// デバイス配下のオブジェクトを削除する
+    fn remove_children(&self, path: &str) {
+        let prefix = format!("{}/", path);
+        let mut children: Vec<String> = self
+            .fake
+            .paths()
+            .into_iter()
+            .filter(|child| child.starts_with(&prefix))
+            .collect();
+        // 子要素から削除する
+        children.sort_by(|a, b| b.cmp(a));
+        for child in children {
+            self.fake.remove_object(&child);
+        }
+    }
+
+    /// 各インターフェースのメソッドへの応答を登録する
+    fn script(&self) {
+        let fake = &self.fake;
+
+        let mock = self.clone();
+        fake.on_call(ADAPTER_INTERFACE, "StartDiscovery", move |msg| {
+            let mut state = mock.state.lock().unwrap();
+            if state.discovery.is_some() {
+                return Err(BluezError::InProgress(
+                    "Operation already in progress".into(),
+                ));
+            }
+            state.discovery = Some(Instant::now());
+            drop(state);
+            mock.fake
+                .set_property(MOCK_ADAPTER, ADAPTER_INTERFACE, "Discovering", true);
+            Ok(msg.method_return())
+        });
+        let mock = self.clone();
+        fake.on_call(ADAPTER_INTERFACE, "StopDiscovery", move |msg| {
+            if mock.state.lock().unwrap().discovery.take().is_none() {
+                return Err(BluezError::Failed("No discovery started".into()));
+            }
+            mock.fake
+                .set_property(MOCK_ADAPTER, ADAPTER_INTERFACE, "Discovering", false);
+            Ok(msg.method_return())
+        });
+        let mock = self.clone();
+        fake.on_call(ADAPTER_INTERFACE, "RemoveDevice", move |msg| {
+            let device: dbus::Path = msg.read1()?;
+            if !mock.fake.contains(&device) {
+                return Err(BluezError::DoesNotExist("Does Not Exist".into()));
+            }
+            mock.remove_children(&device);
+            mock.fake.remove_object(&device);
+            Ok(msg.method_return())
+        });
+
+        let mock = self.clone();
+        fake.on_call(DEVICE_INTERFACE, "Connect", move |msg| {
+            let path = object_path(msg);
+            let error = mock
+                .state
+                .lock()
+                .unwrap()
+                .devices
+                .get(&path)
+                .and_then(|device| device.connect_error.clone());
+            if let Some((name, message)) = error {
+                return Err(dbus::Error::new_custom(&*name, &message).into());
+            }
+            if is_true(&mock.fake, &path, DEVICE_INTERFACE, "Connected") {
+                return Err(BluezError::AlreadyConnected("Already Connected".into()));
+            }
+            mock.fake
+                .set_property(&path, DEVICE_INTERFACE, "Connected", true);
+            mock.export_gatt(&path);
+            mock.fake
+                .set_property(&path, DEVICE_INTERFACE, "ServicesResolved", true);
+            Ok(msg.method_return())
+        });
+        let mock = self.clone();
+        fake.on_call(DEVICE_INTERFACE, "Disconnect", move |msg| {
+            let path = object_path(msg);
+            if !is_true(&mock.fake, &path, DEVICE_INTERFACE, "Connected") {
+                return Err(BluezError::NotConnected("Not Connected".into()));
+            }
+            mock.fake
+                .set_property(&path, DEVICE_INTERFACE, "ServicesResolved", false);
+            mock.remove_children(&path);
+            mock.fake
+                .set_property(&path, DEVICE_INTERFACE, "Connected", false);
+            Ok(msg.method_return())
+        });
+        let mock = self.clone();
+        fake.on_call(DEVICE_INTERFACE, "Pair", move |msg| {
+            let path = object_path(msg);
+            if is_true(&mock.fake, &path, DEVICE_INTERFACE, "Paired") {
+                return Err(BluezError::AlreadyExists("Already Exists".into()));
+            }
+            mock.fake
+                .set_property(&path, DEVICE_INTERFACE, "Paired", true);
+            Ok(msg.method_return())
+        });
+
+        for interface in [CHARACTERISTIC_INTERFACE, DESCRIPTOR_INTERFACE]
+            .iter()
+            .copied()
+        {
+            let mock = self.clone();
+            fake.on_call(interface, "ReadValue", move |msg| {
+                // BlueZと同様に、オプションの辞書の無い呼び出しは受け付けない
+                let _options: Options = msg.read1()?;
+                let value: Vec<u8> = mock
+                    .fake
+                    .property(&object_path(msg), interface, "Value")
+                    .and_then(|value| {
+                        value
+                            .as_iter()?
+                            .map(|byte| byte.as_u64().map(|byte| byte as u8))
+                            .collect()
+                    })
+                    .ok_or_else(|| BluezError::NotPermitted("Read not permitted".into()))?;
+                Ok(msg.method_return().append1(value))
+            });
+            let mock = self.clone();
+            fake.on_call(interface, "WriteValue", move |msg| {
+                let (value, _options): (Vec<u8>, Options) = msg.read2()?;
+                mock.fake
+                    .set_property(&object_path(msg), interface, "Value", value);
+                Ok(msg.method_return())
+            });
+        }
+        for (method, notifying) in &[("StartNotify", true), ("StopNotify", false)] {
+            let mock = self.clone();
+            let notifying = *notifying;
+            fake.on_call(CHARACTERISTIC_INTERFACE, method, move |msg| {
+                mock.fake.set_property(
+                    &object_path(msg),
+                    CHARACTERISTIC_INTERFACE,
+                    "Notifying",
+                    notifying,
+                );
+                Ok(msg.method_return())
+            });
+        }
+    }
+}
+
+/// `MockBluez`の公開を続けるハンドル
+///
+/// 破棄すると公開を止める。
+pub struct MockServer {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// 試験用に起動したプライベートな`dbus-daemon`
+///
+/// `dbus-daemon --session`を一時ディレクトリのソケットで起動し、破棄すると終了させる。
+pub struct PrivateBus {
+    child: Child,
+    socket: PathBuf,
+    address: String,
+}
+
+impl PrivateBus {
+    /// `PATH`にある`dbus-daemon`を起動する
+    pub fn spawn() -> Result<Self, BluezError> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let socket = env::temp_dir().join(format!(
+            "bluez-dbus-{}-{}",
+            process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        // GUIDを含めないため、`restart`の後も同じアドレスで接続できる
+        let address = format!("unix:path={}", socket.display());
+        let child = start_daemon(&address)?;
+        Ok(PrivateBus {
+            child,
+            socket,
+            address,
+        })
+    }
+
+    /// 終了させ、同じアドレスで起動し直す
+    ///
+    /// 接続していたコネクションは切断される。切断と再接続の試験に使用する。
+    pub fn restart(&mut self) -> Result<(), BluezError> {
+        self.stop();
+        self.child = start_daemon(&self.address)?;
+        Ok(())
+    }
+
+    /// バスのアドレス
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// `SessionBuilder::bus`などに指定するバス
+    pub fn bus(&self) -> Bus {
+        Bus::Address(self.address.clone())
+    }
+
+    fn stop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_file(&self.socket);
+    }
+}
+
+impl Drop for PrivateBus {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// 指定のアドレスで`dbus-daemon`を起動し、接続を受け付けるまで待つ
+fn start_daemon(address: &str) -> Result<Child, BluezError> {
+    let spawn_error = |message: String| {
+        BluezError::Transport(dbus::Error::new_custom(
+            "org.freedesktop.DBus.Error.Spawn.ExecFailed",
+            &message,
+        ))
+    };
+    let mut child = Command::new("dbus-daemon")
+        .args(["--session", "--nofork", "--print-address"])
+        .arg(format!("--address={}", address))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| spawn_error(format!("dbus-daemon: {}", e)))?;
+    // アドレスが出力された時点で接続を受け付けている
+    let mut printed = String::new();
+    let read = child
+        .stdout
+        .take()
+        .map(|stdout| BufReader::new(stdout).read_line(&mut printed));
+    if printed.trim().is_empty() {
+        let _ = child.kill();
+        let _ = child.wait();
+        return Err(spawn_error(format!(
+            "dbus-daemon did not print its address: {:?}",
+            read
+        )));
+    }
+    Ok(child)
+}
+
+/// プロパティの一覧の作成
+struct Props(HashMap<String, Variant<Box<dyn RefArg>>>);
+
+impl Props {
+    fn new() -> Self {
+        Props(HashMap::new())
+    }
+
+    fn insert<V: RefArg + 'static>(&mut self, name: &str, value: V) {
+        self.0.insert(name.to_string(), Variant(Box::new(value)));
+    }
+}
+
+impl From<Props> for Properties {
+    fn from(props: Props) -> Self {
+        props.0.into()
+    }
+}
+
+fn object_path(msg: &Message) -> String {
+    msg.path().map(|path| path.to_string()).unwrap_or_default()
+}
+
+fn is_true(fake: &FakeBluez, path: &str, interface: &str, name: &str) -> bool {
+    fake.property(path, interface, name)
+        .and_then(|value| value.as_i64())
+        .map(|value| value != 0)
+        .unwrap_or(false)
+}
+
+/// エラーの応答を作成する
+fn error_reply(msg: &Message, err: &BluezError) -> Message {
+    let message = CString::new(err.message().replace('\0', "")).unwrap_or_default();
+    msg.error(&err.dbus_name().into(), &message)
+}
+
+/// 溜まっているシグナルを送信する
+fn flush(conn: &Connection, signals: &Mutex<VecDeque<Message>>) {
+    let signals: Vec<Message> = signals.lock().unwrap().drain(..).collect();
+    for signal in signals {
+        let _ = conn.send(signal);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocking::{Device, Session};
+    use std::thread;
+
+    #[test]
+    fn discover_connect_and_read() {
+        let bus = PrivateBus::spawn().unwrap();
+        let mock = MockBluez::new();
+        let sensor = mock.add_discoverable(
+            MockDevice::new("00:11:22:33:44:55").service(
+                MockService::new("0000181a-0000-1000-8000-00805f9b34fb").characteristic(
+                    MockCharacteristic::new("00002a6e-0000-1000-8000-00805f9b34fb")
+                        .flags(&["read"])
+                        .value(vec![1, 2]),
+                ),
+            ),
+            Duration::from_millis(100),
+        );
+        let broken = mock.add_device(
+            MockDevice::new("66:77:88:99:AA:BB")
+                .connect_error("org.bluez.Error.Failed", "Page Timeout"),
+        );
+        let _server = mock.serve(&bus.bus()).unwrap();
+
+        let s = Session::builder().bus(bus.bus()).build().unwrap();
+        let adapter = s.default_adapter().unwrap();
+        assert_eq!(adapter.get_path().as_str(), MOCK_ADAPTER);
+        let devices: Vec<DevicePath> = adapter
+            .get_devices()
+            .unwrap()
+            .iter()
+            .map(|device| device.get_path())
+            .collect();
+        assert_eq!(devices, vec![broken.clone()]);
+        adapter.start_discovery().unwrap();
+        assert!(matches!(
+            adapter.start_discovery(),
+            Err(BluezError::InProgress(_))
+        ));
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(adapter.get_devices().unwrap().len(), 2);
+
+        let dev = Device::new(&s, &sensor);
+        dev.connect().unwrap();
+        let info = dev.info().unwrap();
+        assert_eq!(info.address, "00:11:22:33:44:55");
+        assert!(info.connected);
+        assert_eq!(info.adapter, adapter.get_path());
+        assert_eq!(info.tx_power, None);
+        let services = dev.get_gatt_services().unwrap();
+        let chars = services[0].get_characteristics().unwrap();
+        assert_eq!(chars[0].read_value(HashMap::new()).unwrap(), vec![1, 2]);
+        chars[0].write_value(vec![3], HashMap::new()).unwrap();
+        let mut options: HashMap<&str, Variant<Box<dyn RefArg>>> = HashMap::new();
+        options.insert("offset", Variant(Box::new(0u16)));
+        assert_eq!(chars[0].read_value(options).unwrap(), vec![3]);
+        dev.disconnect().unwrap();
+        assert!(dev.get_gatt_services().unwrap().is_empty());
+
+        assert!(matches!(
+            Device::new(&s, &broken).connect(),
+            Err(BluezError::Failed(_))
+        ));
+    }
+
+    #[test]
+    fn remove_callback_within_callback() {
+        let bus = PrivateBus::spawn().unwrap();
+        let mock = MockBluez::new();
+        let _server = mock.serve(&bus.bus()).unwrap();
+        let s = Session::builder().bus(bus.bus()).build().unwrap();
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let token = Arc::new(Mutex::new(None));
+        let first = {
+            let (s, token) = (s.clone(), token.clone());
+            s.clone()
+                .on_event(move |event| {
+                    if let Some(token) = token.lock().unwrap().take() {
+                        s.remove_callback(token).unwrap();
+                    }
+                    tx.send(event).unwrap();
+                })
+                .unwrap()
+        };
+        *token.lock().unwrap() = Some(first);
+
+        mock.add_device(MockDevice::new("00:11:22:33:44:55"));
+        let event = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert!(matches!(event, Event::DeviceAdded { .. }));
+        mock.add_device(MockDevice::new("66:77:88:99:AA:BB"));
+        thread::sleep(Duration::from_millis(300));
+        assert!(rx.try_recv().is_err());
+    }
+
+    /// 一定時間内に完了しなければ失敗させる
+    #[cfg(not(feature = "local"))]
+    async fn within<T>(future: impl std::future::Future<Output = T>) -> T {
+        use futures::future::{self, Either};
+        futures::pin_mut!(future);
+        let timeout = futures_timer::Delay::new(Duration::from_secs(10));
+        match future::select(future, timeout).await {
+            Either::Left((value, _)) => value,
+            Either::Right(_) => panic!("timed out"),
+        }
+    }
+
+    #[cfg(not(feature = "local"))]
+    #[test]
+    fn nonblock_streams_survive_reconnect() {
+        use crate::nonblock::{self, Driver, ReconnectPolicy};
+        use futures::StreamExt;
+
+        let mut bus = PrivateBus::spawn().unwrap();
+        let mock = MockBluez::new();
+        let device = mock.add_device(MockDevice::new("00:11:22:33:44:55"));
+        let server = mock.serve(&bus.bus()).unwrap();
+        let s = nonblock::Session::builder()
+            .bus(bus.bus())
+            .driver(Driver::Thread)
+            .reconnect(ReconnectPolicy {
+                initial_delay: Duration::from_millis(50),
+                max_delay: Duration::from_millis(200),
+                max_attempts: None,
+            })
+            .build()
+            .unwrap();
+        futures::executor::block_on(async {
+            let mut events = s.events().await.unwrap();
+            let mut changes = nonblock::Device::new(&s, &device)
+                .property_changes()
+                .await
+                .unwrap();
+
+            let mut state = s.watch_connection_state();
+            // 最初は現在の状態(接続中)が返る
+            state.recv().await;
+            drop(server);
+            bus.restart().unwrap();
+            let _server = mock.serve(&bus.bus()).unwrap();
+            // 切断後は再接続が完了するまで接続中にならない
+            within(async {
+                while let Some(state) = state.recv().await {
+                    if state.is_connected() {
+                        break;
+                    }
+                }
+            })
+            .await;
+
+            mock.fake()
+                .set_property(device.as_str(), DEVICE_INTERFACE, "RSSI", -50i16);
+            assert_eq!(
+                within(changes.next()).await,
+                Some(crate::DeviceProperty::Rssi(-50))
+            );
+            let added = mock.add_device(MockDevice::new("66:77:88:99:AA:BB"));
+            match within(events.next()).await {
+                Some(Event::DeviceAdded { device, .. }) => assert_eq!(device, added),
+                other => panic!("unexpected event: {:?}", other),
+            }
+        });
+    }
+
+    #[cfg(all(feature = "rt-tokio", not(feature = "local")))]
+    #[test]
+    fn nonblock_monitor_ends_with_session() {
+        use crate::nonblock::{self, Driver};
+        use futures::future::BoxFuture;
+
+        let bus = PrivateBus::spawn().unwrap();
+        // dbus-tokioは任意アドレスのバスに対応していないため、セッションバスとして接続する
+        if let Bus::Address(address) = bus.bus() {
+            std::env::set_var("DBUS_SESSION_BUS_ADDRESS", address);
+        }
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let handle = rt.handle().clone();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let tx = Mutex::new(tx);
+        let s = rt.enter(|| {
+            nonblock::Session::builder()
+                .bus(Bus::Session)
+                .driver(Driver::Tokio)
+                .spawner(Arc::new(move |monitor: BoxFuture<'static, ()>| {
+                    let tx = tx.lock().unwrap().clone();
+                    handle.spawn(async move {
+                        monitor.await;
+                        let _ = tx.send(());
+                    });
+                }))
+                .build()
+                .unwrap()
+        });
+        rt.block_on(async {
+            s.default_adapter().await.err();
+        });
+        assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+        // 送受信を行うタスクがコネクションを保持していても、セッションを破棄すれば終了する
+        drop(s);
+        rx.recv_timeout(Duration::from_secs(10)).unwrap();
+    }
+
+    #[cfg(all(feature = "rt-tokio", not(feature = "local")))]
+    #[test]
+    fn nonblock_tokio_driver_rejects_address_bus() {
+        use crate::nonblock::{self, Driver};
+
+        let bus = PrivateBus::spawn().unwrap();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let result = rt.enter(|| {
+            nonblock::Session::builder()
+                .bus(bus.bus())
+                .driver(Driver::Tokio)
+                .build()
+        });
+        assert!(matches!(
+            result,
+            Err(BluezError::DBus(ref e)) if e.name() == Some("org.freedesktop.DBus.Error.NotSupported")
+        ));
+        // 自動で選択する場合は専用スレッドに切り替える
+        assert!(rt
+            .enter(|| nonblock::Session::builder().bus(bus.bus()).build())
+            .is_ok());
+    }
+
+    #[cfg(not(feature = "local"))]
+    #[test]
+    fn nonblock_streams_end_on_connection_loss() {
+        use crate::nonblock::{self, Driver};
+        use futures::StreamExt;
+
+        let bus = PrivateBus::spawn().unwrap();
+        let mock = MockBluez::new();
+        let device = mock.add_device(MockDevice::new("00:11:22:33:44:55"));
+        let _server = mock.serve(&bus.bus()).unwrap();
+        let s = nonblock::Session::builder()
+            .bus(bus.bus())
+            .driver(Driver::Thread)
+            .build()
+            .unwrap();
+        futures::executor::block_on(async {
+            let mut events = s.events().await.unwrap();
+            let mut changes = nonblock::Device::new(&s, &device)
+                .property_changes()
+                .await
+                .unwrap();
+            drop(bus);
+            assert!(within(events.next()).await.is_none());
+            assert!(within(changes.next()).await.is_none());
+            assert!(matches!(
+                s.connection_state(),
+                nonblock::ConnectionState::Lost(_)
+            ));
+        });
+    }
+}
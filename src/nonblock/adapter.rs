@@ -1,107 +1,151 @@
-use crate::nonblock::Session;
+use crate::nonblock::{Device, PropertyStream, Session};
 use crate::*;
-use dbus::arg::{Append, Arg, Get};
-use std::error::Error;
+use dbus::arg::{Append, AppendAll, Arg, ReadAll, Variant};
+use std::collections::HashMap;
+use std::time::Duration;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Adapter {
     session: Session,
-    path: String,
+    path: AdapterPath,
+    timeout: Option<Timeout>,
 }
 
 impl Adapter {
-    fn new(session: &Session, path: &str) -> Self {
+    pub(in crate) fn new(session: &Session, path: &AdapterPath) -> Self {
         Adapter {
             session: session.clone(),
-            path: path.to_string(),
+            path: path.clone(),
+            timeout: None,
         }
     }
 
-    /// bluetoothアダプターの作成
+    /// タイムアウトを指定したアダプターを作成
     ///
-    /// 指定されたパスの存在を確認してアダプターを作成する。
-    /// 存在しない場合は`Ok(None)`を返す。
-    pub async fn create(
-        session: &Session,
-        path: &str,
-    ) -> Result<Option<Self>, Box<dyn Error + 'static>> {
-        if let Some(adapters) = session.get_adapters().await? {
-            if adapters.contains(&path.to_string()) {
-                return Ok(Some(Adapter::new(session, path)));
-            }
+    /// 作成したアダプターからの呼び出しにはセッションの既定値の代わりにこの値を使用する。
+    pub fn with_timeout(&self, timeout: Duration) -> Self {
+        Adapter {
+            session: self.session.clone(),
+            path: self.path.clone(),
+            timeout: Some(Timeout::Duration(timeout)),
         }
-        Ok(None)
     }
 
-    /// デバイスリスト取得
+    /// 期限を指定したアダプターを作成
     ///
-    /// アダプターに登録されているデバイスのパスのリストを取得する
-    pub async fn get_devices(&self) -> Result<Option<Vec<String>>, BoxError> {
-        self.session.get_children(&self.path, "Adapter").await
+    /// 各呼び出しのタイムアウトは期限までの残り時間となる。
+    /// 期限を過ぎている場合は`BluezError::Timeout`を返す。
+    pub fn with_deadline(&self, deadline: Deadline) -> Self {
+        Adapter {
+            session: self.session.clone(),
+            path: self.path.clone(),
+            timeout: Some(Timeout::Deadline(deadline)),
+        }
     }
 
-    /// デバイスの検索を開始する
-    pub async fn start_discovery(&self) -> Result<(), BoxError> {
-        self.sub_discovery("StartDiscovery").await
+    /// アダプターのオブジェクトパスを取得
+    pub fn get_path(&self) -> AdapterPath {
+        self.path.clone()
     }
 
-    /// デバイスの検索を停止する
-    pub async fn stop_discovery(&self) -> Result<(), BoxError> {
-        self.sub_discovery("StopDiscovery").await
+    /// アダプターの全てのプロパティを一度に取得
+    ///
+    /// `GetAll`を一度だけ呼び出す。セッションのキャッシュが有効な場合は問い合わせを行わない。
+    pub async fn info(&self) -> Result<AdapterInfo, BluezError> {
+        self.session
+            .get_all(&self.path, Timeout::resolve(self.timeout)?)
+            .await
     }
 
-    pub async fn remove_device(&self, device: &str) -> Result<(), BoxError> {
-        self.session
-            .method_call(&self.path, ADAPTER_INTERFACE, "RemoveDevice", (device,))
-            .await?;
-        Ok(())
+    /// bluetoothアダプターの作成
+    ///
+    /// 指定されたパスの存在を確認してアダプターを作成する。
+    /// 存在しない場合は`Ok(None)`を返す。
+    pub async fn create(session: &Session, path: &AdapterPath) -> Result<Option<Self>, BluezError> {
+        Ok(session
+            .get_adapters()
+            .await?
+            .into_iter()
+            .find(|adapter| adapter.path == *path))
     }
 
-    // TODO: SetDiscoveryFilter
+    /// デバイスリスト取得
+    ///
+    /// アダプターに登録されているデバイスの一覧を取得する。
+    /// デバイスが無い場合は空の`Vec`を返す。
+    pub async fn get_devices(&self) -> Result<Vec<Device>, BluezError> {
+        let paths: Vec<DevicePath> = self
+            .session
+            .get_children(&self.path, "Adapter", Timeout::resolve(self.timeout)?)
+            .await?;
+        Ok(paths
+            .iter()
+            .map(|path| Device::new(&self.session, path))
+            .collect())
+    }
 
-    async fn sub_discovery(&self, method: &str) -> Result<(), BoxError> {
+    /// アドレスを指定してデバイスに接続する
+    ///
+    /// 検索で見つかっていないデバイスにも接続できる。`address_type`には`"public"`か`"random"`を指定する
+    /// (省略した場合はBlueZが判断する)。
+    /// BlueZ 5.49以降の機能のため、接続先のBlueZに`ConnectDevice`が無い場合は呼び出しを行わずに
+    /// `BluezError::Unsupported`を返す。
+    pub async fn connect_device(
+        &self,
+        address: &str,
+        address_type: Option<&str>,
+    ) -> Result<Device, BluezError> {
         self.session
-            .method_call(&self.path, ADAPTER_INTERFACE, method, ())
+            .require_method(ADAPTER_INTERFACE, "ConnectDevice")
             .await?;
-        Ok(())
+        let mut properties = HashMap::new();
+        properties.insert("Address", Variant(address));
+        if let Some(address_type) = address_type {
+            properties.insert("AddressType", Variant(address_type));
+        }
+        let (path,): (DevicePath,) = self.method_call("ConnectDevice", (properties,)).await?;
+        Ok(Device::new(&self.session, &path))
+    }
+
+    /// アダプターのプロパティの変更を受信する
+    pub async fn property_changes(&self) -> Result<PropertyStream<AdapterProperty>, BluezError> {
+        self.session.property_changes(&self.path).await
     }
 
-    async fn get_property<A: for<'z> Get<'z> + 'static>(
+    async fn method_call<R: ReadAll + 'static, A: AppendAll>(
         &self,
-        property: &str,
-    ) -> Result<A, BoxError> {
-        Ok(self
-            .session
-            .get_property(&self.path, ADAPTER_INTERFACE, property)
-            .await?)
+        method: &str,
+        arg: A,
+    ) -> Result<R, BluezError> {
+        let timeout = Timeout::resolve(self.timeout)?;
+        self.session
+            .method_call(&self.path, ADAPTER_INTERFACE, method, arg, timeout)
+            .await
     }
-    async fn set_property<T: Append + Arg>(&self, prop: &str, value: T) -> Result<(), BoxError> {
-        Ok(self
-            .session
-            .set_property(&self.path, ADAPTER_INTERFACE, prop, value)
-            .await?)
+
+    async fn get_property<A: FromRefArg>(&self, property: &str) -> Result<A, BluezError> {
+        self.session
+            .get_property(
+                &self.path,
+                ADAPTER_INTERFACE,
+                property,
+                Timeout::resolve(self.timeout)?,
+            )
+            .await
+    }
+    async fn set_property<T: Append + Arg>(&self, prop: &str, value: T) -> Result<(), BluezError> {
+        self.session
+            .set_property(
+                &self.path,
+                ADAPTER_INTERFACE,
+                prop,
+                value,
+                Timeout::resolve(self.timeout)?,
+            )
+            .await
     }
 
     //--------------------------------------------------------------------------------
-    // プロパティ
-    // get
-    async_get_property!(get_address, String, "Address");
-    async_get_property!(get_name, String, "Name");
-    async_get_property!(get_alias, String, "Alias");
-    async_get_property!(get_class, u32, "Class");
-    async_get_property!(is_powered, bool, "Powered");
-    async_get_property!(is_discoverable, bool, "Discoverable");
-    async_get_property!(is_pairable, bool, "Pairable");
-    async_get_property!(get_pairable_timeout, u32, "PairableTimeout");
-    async_get_property!(get_discoverable_timeout, u32, "DiscoverableTimeout");
-    async_get_property!(is_discovering, bool, "Discovering");
-    async_get_property!(get_uuids, Vec<String>, "UUIDs");
-    async_get_property!(get_modalias, String, "Modalias");
-    // set
-    async_set_property!(set_alias, String, "Alias");
-    async_set_property!(set_powered, bool, "Powered");
-    async_set_property!(set_discoverable, bool, "Discoverable");
-    async_set_property!(set_pairable, bool, "Pairable");
-    async_set_property!(set_pairable_timeout, u32, "PairableTimeout");
-    async_set_property!(set_discoverable_timeout, u32, "DiscoverableTimeout");
+    // メソッド、プロパティ(interfaces/org.bluez.Adapter1.xmlから生成)
+    org_bluez_adapter1!(members: async_call_method, async_get_property, async_set_property);
 }
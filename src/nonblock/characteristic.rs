@@ -1,81 +1,117 @@
-use crate::nonblock::Session;
+use crate::nonblock::{Descriptor, GattService, PropertyStream, Session};
 use crate::*;
-use dbus::arg::Get;
-use dbus::strings::Path;
+use dbus::arg::{AppendAll, ReadAll};
+use std::time::Duration;
 
 static CHARACTERISTIC_INTERFACE: &str = "org.bluez.GattCharacteristic1";
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Characteristic {
     session: Session,
-    path: String,
+    path: CharacteristicPath,
+    timeout: Option<Timeout>,
 }
 
 impl Characteristic {
     /// Gatt Service作成
-    pub fn new(session: &Session, path: &str) -> Self {
+    pub fn new(session: &Session, path: &CharacteristicPath) -> Self {
         Characteristic {
             session: session.clone(),
-            path: path.to_string(),
+            path: path.clone(),
+            timeout: None,
         }
     }
 
-    pub async fn get_descriptors(&self) -> Result<Option<Vec<String>>, BoxError> {
+    /// タイムアウトを指定したCharacteristicを作成
+    ///
+    /// 作成したCharacteristicからの呼び出しにはセッションの既定値の代わりにこの値を使用する。
+    pub fn with_timeout(&self, timeout: Duration) -> Self {
+        Characteristic {
+            session: self.session.clone(),
+            path: self.path.clone(),
+            timeout: Some(Timeout::Duration(timeout)),
+        }
+    }
+
+    /// 期限を指定したCharacteristicを作成
+    ///
+    /// 各呼び出しのタイムアウトは期限までの残り時間となる。
+    /// 期限を過ぎている場合は`BluezError::Timeout`を返す。
+    pub fn with_deadline(&self, deadline: Deadline) -> Self {
+        Characteristic {
+            session: self.session.clone(),
+            path: self.path.clone(),
+            timeout: Some(Timeout::Deadline(deadline)),
+        }
+    }
+
+    /// キャラクタリスティックのオブジェクトパスを取得
+    pub fn get_path(&self) -> CharacteristicPath {
+        self.path.clone()
+    }
+
+    /// キャラクタリスティックの全てのプロパティを一度に取得
+    ///
+    /// `GetAll`を一度だけ呼び出す。セッションのキャッシュが有効な場合は問い合わせを行わない。
+    pub async fn info(&self) -> Result<CharacteristicInfo, BluezError> {
         self.session
-            .get_children(&self.path, "Characteristic")
+            .get_all(&self.path, Timeout::resolve(self.timeout)?)
             .await
     }
 
-    pub async fn read_value(&self) -> Result<Vec<u8>, BoxError> {
-        let (value,): (Vec<u8>,) = self
-            .session
-            .method_call(&self.path, CHARACTERISTIC_INTERFACE, "ReadValue", ())
-            .await?;
-        Ok(value)
+    /// キャラクタリスティックが属するGATTサービスを取得
+    ///
+    /// オブジェクトパスから求めるため、BlueZへの問い合わせは行わない。
+    pub fn service(&self) -> GattService {
+        GattService::new(&self.session, &self.path.service())
     }
 
-    pub async fn write_value(&self, values: Vec<u8>) -> Result<(), BoxError> {
-        Ok(self
+    /// キャラクタリスティックに属するディスクリプターの一覧を取得
+    pub async fn get_descriptors(&self) -> Result<Vec<Descriptor>, BluezError> {
+        let paths: Vec<DescriptorPath> = self
             .session
-            .method_call(
+            .get_children(
                 &self.path,
-                CHARACTERISTIC_INTERFACE,
-                "WriteValue",
-                (values,),
+                "Characteristic",
+                Timeout::resolve(self.timeout)?,
             )
-            .await?)
+            .await?;
+        Ok(paths
+            .iter()
+            .map(|path| Descriptor::new(&self.session, path))
+            .collect())
     }
 
-    pub async fn start_notify(&self) -> Result<(), BoxError> {
-        Ok(self
-            .session
-            .method_call(&self.path, CHARACTERISTIC_INTERFACE, "StartNotify", ())
-            .await?)
+    /// キャラクタリスティックのプロパティの変更を受信する
+    pub async fn property_changes(
+        &self,
+    ) -> Result<PropertyStream<CharacteristicProperty>, BluezError> {
+        self.session.property_changes(&self.path).await
     }
 
-    pub async fn stop_notify(&self) -> Result<(), BoxError> {
-        Ok(self
-            .session
-            .method_call(&self.path, CHARACTERISTIC_INTERFACE, "StopNotify", ())
-            .await?)
+    async fn method_call<R: ReadAll + 'static, A: AppendAll>(
+        &self,
+        method: &str,
+        arg: A,
+    ) -> Result<R, BluezError> {
+        let timeout = Timeout::resolve(self.timeout)?;
+        self.session
+            .method_call(&self.path, CHARACTERISTIC_INTERFACE, method, arg, timeout)
+            .await
     }
 
-    async fn get_property<A: for<'z> Get<'z> + 'static>(
-        &self,
-        property: &str,
-    ) -> Result<A, BoxError> {
-        Ok(self
-            .session
-            .get_property(&self.path, CHARACTERISTIC_INTERFACE, property)
-            .await?)
+    async fn get_property<A: FromRefArg>(&self, property: &str) -> Result<A, BluezError> {
+        self.session
+            .get_property(
+                &self.path,
+                CHARACTERISTIC_INTERFACE,
+                property,
+                Timeout::resolve(self.timeout)?,
+            )
+            .await
     }
 
     //--------------------------------------------------------------------------------
-    // プロパティ
-    // get
-    async_get_property!(get_uuid, String, "UUID");
-    async_get_property!(get_service, Path<'_>, "Service");
-    async_get_property!(is_notifying, bool, "Notifying");
-    // TODO: Flags
-    // TODO: Descriptors
+    // メソッド、プロパティ(interfaces/org.bluez.GattCharacteristic1.xmlから生成)
+    org_bluez_gattcharacteristic1!(members: async_call_method, async_get_property, async_set_property);
 }
@@ -0,0 +1,496 @@
+#[cfg(not(feature = "local"))]
+use crate::driver;
+use crate::*;
+#[cfg(feature = "rt-tokio")]
+use dbus::channel::BusType;
+use dbus::channel::{MatchingReceiver, Token};
+#[cfg(feature = "local")]
+use dbus::nonblock::LocalConnection;
+#[cfg(not(feature = "local"))]
+use dbus::nonblock::SyncConnection;
+#[cfg(feature = "rt-tokio")]
+use dbus_tokio::connection as dbus_conn;
+#[cfg(not(feature = "local"))]
+use futures::future::BoxFuture;
+#[cfg(feature = "local")]
+use futures::future::LocalBoxFuture;
+use futures::future::{self, Either, FutureExt};
+use futures_timer::Delay;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::time::Duration;
+use tokio::sync::{oneshot, watch};
+#[cfg(feature = "rt-tokio")]
+use tokio::task;
+
+#[cfg(not(feature = "local"))]
+pub(in crate) type Conn = SyncConnection;
+#[cfg(feature = "local")]
+pub(in crate) type Conn = LocalConnection;
+
+/// 切断されるまで待つFuture(切断理由を返す)
+#[cfg(not(feature = "local"))]
+type Lost = BoxFuture<'static, String>;
+#[cfg(feature = "local")]
+type Lost = LocalBoxFuture<'static, String>;
+
+/// D-Busとの送受信を行う方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Driver {
+    /// tokioのランタイム内で作成された場合は`Tokio`、それ以外は`Thread`を使用する
+    #[default]
+    Auto,
+    /// tokioのランタイム上のタスクで送受信を行う
+    ///
+    /// tokioのランタイム外でセッションを作成すると`BluezError::Runtime`を返す。
+    /// `Bus::Address`のバスには対応しておらず、`org.freedesktop.DBus.Error.NotSupported`のエラーを返す。
+    #[cfg(feature = "rt-tokio")]
+    Tokio,
+    /// 専用スレッドで送受信を行う
+    ///
+    /// 特定の非同期ランタイムに依存しないため、async-stdやsmol、
+    /// 新しいバージョンのtokioなど任意のエグゼキューターで使用できる。
+    #[cfg(not(feature = "local"))]
+    Thread,
+}
+
+/// `Auto`を置き換えた、実際に使用する送受信の方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ResolvedDriver {
+    #[cfg(feature = "rt-tokio")]
+    Tokio,
+    #[cfg(not(feature = "local"))]
+    Thread,
+}
+
+/// 内部タスクの起動方法
+///
+/// 切断の監視と再接続を行うタスクを利用者のエグゼキューターで実行する場合に指定する。
+/// 指定しない場合は`Driver::Tokio`ではtokioのタスク、`Driver::Thread`では専用スレッドで実行する。
+#[cfg(not(feature = "local"))]
+pub trait Spawner: Send + Sync {
+    fn spawn(&self, future: BoxFuture<'static, ()>);
+}
+
+#[cfg(not(feature = "local"))]
+impl<F: Fn(BoxFuture<'static, ()>) + Send + Sync> Spawner for F {
+    fn spawn(&self, future: BoxFuture<'static, ()>) {
+        self(future)
+    }
+}
+
+/// D-Busとの接続状態
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// 接続中
+    Connected,
+    /// 再接続中(何回目の試行か)
+    Reconnecting { attempt: u32 },
+    /// 切断された(切断理由)
+    Lost(String),
+}
+
+impl ConnectionState {
+    pub fn is_connected(&self) -> bool {
+        *self == ConnectionState::Connected
+    }
+}
+
+/// 自動再接続の設定
+///
+/// 再接続に失敗するたびに待ち時間を`max_delay`まで倍にしていく。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    /// 最初の再接続までの待ち時間
+    pub initial_delay: Duration,
+    /// 待ち時間の上限
+    pub max_delay: Duration,
+    /// 再接続の試行回数の上限(`None`の場合は無制限)
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            max_attempts: None,
+        }
+    }
+}
+
+/// セッション間で共有するコネクション
+///
+/// 再接続時はコネクションを差し替えるため、既存の`Adapter`や`Device`はそのまま使用できる。
+/// シグナルの受信者の一覧もコネクションとは別に保持し、再接続時に新しいコネクションへ登録し直す。
+pub(in crate) struct Shared {
+    // 独自の通信路を使用する場合は`None`
+    conn: RwLock<Option<Arc<Conn>>>,
+    handlers: Arc<Mutex<Handlers>>,
+    listener: Mutex<Option<Listener>>,
+    state_tx: watch::Sender<ConnectionState>,
+    state_rx: watch::Receiver<ConnectionState>,
+    // 破棄されると切断を監視するタスクを終了させる
+    shutdown: Mutex<Option<oneshot::Sender<()>>>,
+}
+
+/// コネクションに登録した、全てのシグナルを受け取る受信者
+///
+/// 受信したシグナルは`Handlers`から各受信者に配る。
+struct Listener {
+    conn: Weak<Conn>,
+    token: Token,
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.upgrade() {
+            conn.stop_receive(self.token);
+        }
+    }
+}
+
+impl Shared {
+    /// 接続を開始する
+    ///
+    /// 切断を監視するタスクを起動し、`policy`が指定されていれば自動で再接続を行う。
+    pub(in crate) fn connect(
+        bus: Bus,
+        driver: Driver,
+        policy: Option<ReconnectPolicy>,
+        #[cfg(not(feature = "local"))] spawner: Option<Arc<dyn Spawner>>,
+    ) -> Result<Arc<Self>, BluezError> {
+        let driver = driver.resolve(&bus)?;
+        let (conn, lost) = connect(&bus, driver)?;
+        let shared = Shared::with_connection(conn);
+        let (shutdown_tx, shutdown) = oneshot::channel();
+        *shared.shutdown.lock().unwrap() = Some(shutdown_tx);
+        let monitor = monitor(Arc::downgrade(&shared), bus, driver, policy, lost, shutdown);
+        #[cfg(not(feature = "local"))]
+        spawn(monitor, driver, spawner)?;
+        #[cfg(feature = "local")]
+        task::spawn_local(monitor);
+        Ok(shared)
+    }
+
+    /// 既存のコネクションから作成する
+    ///
+    /// コネクションの送受信は呼び出し側で行うため、切断の監視は行わない。
+    #[cfg_attr(feature = "local", allow(clippy::arc_with_non_send_sync))]
+    pub(in crate) fn with_connection(conn: Arc<Conn>) -> Arc<Self> {
+        let (state_tx, state_rx) = watch::channel(ConnectionState::Connected);
+        Arc::new(Shared {
+            conn: RwLock::new(Some(conn)),
+            handlers: Default::default(),
+            listener: Default::default(),
+            state_tx,
+            state_rx,
+            shutdown: Default::default(),
+        })
+    }
+
+    /// コネクションを持たずに作成する
+    ///
+    /// 独自の通信路を使用するセッションで使用し、状態は常に`ConnectionState::Lost`となる。
+    #[cfg_attr(feature = "local", allow(clippy::arc_with_non_send_sync))]
+    pub(in crate) fn detached() -> Arc<Self> {
+        let state = ConnectionState::Lost("custom transport".to_string());
+        let (state_tx, state_rx) = watch::channel(state);
+        Arc::new(Shared {
+            conn: RwLock::new(None),
+            handlers: Default::default(),
+            listener: Default::default(),
+            state_tx,
+            state_rx,
+            shutdown: Default::default(),
+        })
+    }
+
+    /// 現在のコネクションを取得
+    ///
+    /// 接続中でない場合は`BluezError::ConnectionLost`を返す。
+    pub(in crate) fn connection(&self) -> Result<Arc<Conn>, BluezError> {
+        let state = self.state();
+        if !state.is_connected() {
+            return Err(connection_lost(&state));
+        }
+        self.conn
+            .read()
+            .unwrap()
+            .clone()
+            .ok_or_else(|| connection_lost(&state))
+    }
+
+    pub(in crate) fn state(&self) -> ConnectionState {
+        self.state_rx.borrow().clone()
+    }
+
+    pub(in crate) fn watch_state(&self) -> watch::Receiver<ConnectionState> {
+        self.state_rx.clone()
+    }
+
+    /// 接続中でなくなるまで待ち、その時のエラーを返す
+    pub(in crate) async fn lost(&self) -> BluezError {
+        let mut rx = self.state_rx.clone();
+        loop {
+            let state = rx.borrow().clone();
+            if !state.is_connected() {
+                return connection_lost(&state);
+            }
+            if rx.recv().await.is_none() {
+                return connection_lost(&ConnectionState::Lost("session closed".to_string()));
+            }
+        }
+    }
+
+    fn set_state(&self, state: ConnectionState) {
+        let _ = self.state_tx.broadcast(state);
+    }
+
+    /// シグナルの受信者の一覧
+    pub(in crate) fn handlers(&self) -> &Arc<Mutex<Handlers>> {
+        &self.handlers
+    }
+
+    /// コネクションで受信したシグナルを受信者の一覧に配るようにする
+    ///
+    /// 登録済みの場合は何もしない。
+    pub(in crate) fn listen(&self, conn: &Arc<Conn>) {
+        let mut listener = self.listener.lock().unwrap();
+        if let Some(listener) = &*listener {
+            if Weak::ptr_eq(&listener.conn, &Arc::downgrade(conn)) {
+                return;
+            }
+        }
+        // 受信者の一覧はセッションが破棄されるまでとし、コネクションからは参照しない
+        let handlers = Arc::downgrade(&self.handlers);
+        let token = conn.start_receive(
+            Handlers::rule(),
+            Box::new(move |msg, _| {
+                if let Some(handlers) = handlers.upgrade() {
+                    Handlers::dispatch(&handlers, &msg);
+                }
+                true
+            }),
+        );
+        *listener = Some(Listener {
+            conn: Arc::downgrade(conn),
+            token,
+        });
+    }
+
+    /// 再接続しない場合に、切断されたコネクションと受信者を破棄する
+    ///
+    /// 受信者が持つ送信側が破棄されるため、`SignalStream`は終了する。
+    /// 送受信を行うタスクがコネクションを保持していても、受信者は残らない。
+    fn close(&self) {
+        *self.listener.lock().unwrap() = None;
+        *self.conn.write().unwrap() = None;
+        self.handlers.lock().unwrap().clear();
+    }
+
+    /// 再接続したコネクションに受信者のルールを登録し直す
+    ///
+    /// それまでに一度もシグナルを受信していなければ何もしない。
+    /// 登録に失敗したルールは、そのルールの受信者にシグナルが届かなくなる。
+    async fn resubscribe(&self, conn: &Arc<Conn>) {
+        if self.listener.lock().unwrap().is_none() {
+            return;
+        }
+        self.listen(conn);
+        let rules = self.handlers.lock().unwrap().rules();
+        for rule in rules {
+            #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+            if let Err(err) = conn.add_match_no_cb(&rule).await {
+                #[cfg(feature = "tracing")]
+                tracing::warn!(%rule, error = %err, "failed to resubscribe after reconnect");
+            }
+        }
+    }
+}
+
+fn connection_lost(state: &ConnectionState) -> BluezError {
+    match state {
+        ConnectionState::Lost(reason) => BluezError::ConnectionLost(reason.clone()),
+        ConnectionState::Reconnecting { attempt } => {
+            BluezError::ConnectionLost(format!("reconnecting (attempt {})", attempt))
+        }
+        ConnectionState::Connected => BluezError::ConnectionLost("connected".to_string()),
+    }
+}
+
+/// 切断の監視と再接続
+///
+/// `Shared`が破棄されると、切断や再接続を待っている途中でも終了する。
+/// 送受信を行うタスクやスレッドは`lost`が保持しているため、終了時にコネクションも破棄される。
+async fn monitor(
+    shared: Weak<Shared>,
+    bus: Bus,
+    driver: ResolvedDriver,
+    policy: Option<ReconnectPolicy>,
+    mut lost: Lost,
+    mut shutdown: oneshot::Receiver<()>,
+) {
+    loop {
+        let reason = match future::select(lost, &mut shutdown).await {
+            Either::Left((reason, _)) => reason,
+            Either::Right(_) => return,
+        };
+        let policy = {
+            let shared = match shared.upgrade() {
+                Some(shared) => shared,
+                None => return,
+            };
+            shared.set_state(ConnectionState::Lost(reason.clone()));
+            match policy {
+                Some(policy) => policy,
+                None => return shared.close(),
+            }
+        };
+
+        // 待っている間は`Shared`を保持しない
+        let mut delay = policy.initial_delay;
+        let mut attempt = 0;
+        lost = loop {
+            attempt += 1;
+            {
+                let shared = match shared.upgrade() {
+                    Some(shared) => shared,
+                    None => return,
+                };
+                if policy
+                    .max_attempts
+                    .map(|max| attempt > max)
+                    .unwrap_or(false)
+                {
+                    shared.set_state(ConnectionState::Lost(reason));
+                    return shared.close();
+                }
+                shared.set_state(ConnectionState::Reconnecting { attempt });
+            }
+            if let Either::Right(_) = future::select(Delay::new(delay), &mut shutdown).await {
+                return;
+            }
+            if let Ok((conn, lost)) = connect(&bus, driver) {
+                let shared = match shared.upgrade() {
+                    Some(shared) => shared,
+                    None => return,
+                };
+                *shared.conn.write().unwrap() = Some(conn.clone());
+                shared.resubscribe(&conn).await;
+                shared.set_state(ConnectionState::Connected);
+                break lost;
+            }
+            delay = std::cmp::min(delay * 2, policy.max_delay);
+        };
+    }
+}
+
+impl Driver {
+    /// `Auto`を実際の方式に置き換える
+    ///
+    /// dbus-tokioは任意アドレスのバスに対応していないため、`Bus::Address`のバスは
+    /// `Auto`では専用スレッドを使用する。`Tokio`を指定した場合と、
+    /// 専用スレッドを使用できない`local`フィーチャーではエラーとする。
+    #[cfg_attr(not(feature = "rt-tokio"), allow(unused_variables))]
+    fn resolve(self, bus: &Bus) -> Result<ResolvedDriver, BluezError> {
+        match self {
+            #[cfg(feature = "rt-tokio")]
+            Driver::Auto | Driver::Tokio => {
+                if let Bus::Address(address) = bus {
+                    #[cfg(not(feature = "local"))]
+                    {
+                        if self == Driver::Auto {
+                            return Ok(ResolvedDriver::Thread);
+                        }
+                    }
+                    return Err(address_not_supported(address));
+                }
+                if tokio::runtime::Handle::try_current().is_ok() {
+                    return Ok(ResolvedDriver::Tokio);
+                }
+                #[cfg(not(feature = "local"))]
+                {
+                    if self == Driver::Auto {
+                        return Ok(ResolvedDriver::Thread);
+                    }
+                }
+                Err(BluezError::Runtime(
+                    "must be called from the context of a tokio runtime".to_string(),
+                ))
+            }
+            #[cfg(not(feature = "rt-tokio"))]
+            Driver::Auto => Ok(ResolvedDriver::Thread),
+            #[cfg(not(feature = "local"))]
+            Driver::Thread => Ok(ResolvedDriver::Thread),
+        }
+    }
+}
+
+/// バスへの接続
+fn connect(bus: &Bus, driver: ResolvedDriver) -> Result<(Arc<Conn>, Lost), BluezError> {
+    match driver {
+        #[cfg(feature = "rt-tokio")]
+        ResolvedDriver::Tokio => connect_tokio(bus),
+        #[cfg(not(feature = "local"))]
+        ResolvedDriver::Thread => {
+            let (conn, exited) = driver::spawn(bus.open_channel()?)?;
+            let lost = async move {
+                exited
+                    .await
+                    .unwrap_or_else(|_| "I/O thread exited".to_string())
+            };
+            Ok((conn, lost.boxed()))
+        }
+    }
+}
+
+#[cfg(feature = "rt-tokio")]
+fn connect_tokio(bus: &Bus) -> Result<(Arc<Conn>, Lost), BluezError> {
+    let bus_type = match bus {
+        Bus::System => BusType::System,
+        Bus::Session => BusType::Session,
+        Bus::Address(address) => return Err(address_not_supported(address)),
+    };
+    let (resource, conn) = dbus_conn::new(bus_type)?;
+    let lost = async move { resource.await.to_string() };
+    #[cfg(not(feature = "local"))]
+    return Ok((conn, lost.boxed()));
+    #[cfg(feature = "local")]
+    return Ok((conn, lost.boxed_local()));
+}
+
+/// `Driver::Tokio`で任意アドレスのバスに接続しようとした場合のエラー
+#[cfg(feature = "rt-tokio")]
+fn address_not_supported(address: &str) -> BluezError {
+    let msg = format!(
+        "address bus is not supported by the tokio driver: {}",
+        address
+    );
+    dbus::Error::new_custom("org.freedesktop.DBus.Error.NotSupported", &msg).into()
+}
+
+/// 切断を監視するタスクの起動
+#[cfg(not(feature = "local"))]
+fn spawn(
+    monitor: impl std::future::Future<Output = ()> + Send + 'static,
+    driver: ResolvedDriver,
+    spawner: Option<Arc<dyn Spawner>>,
+) -> Result<(), BluezError> {
+    if let Some(spawner) = spawner {
+        spawner.spawn(monitor.boxed());
+        return Ok(());
+    }
+    match driver {
+        #[cfg(feature = "rt-tokio")]
+        ResolvedDriver::Tokio => {
+            task::spawn(monitor);
+        }
+        _ => {
+            std::thread::Builder::new()
+                .name("bluez-dbus-monitor".to_string())
+                .spawn(move || futures::executor::block_on(monitor))
+                .map_err(|e| BluezError::Runtime(e.to_string()))?;
+        }
+    }
+    Ok(())
+}
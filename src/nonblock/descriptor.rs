@@ -1,54 +1,99 @@
-use crate::nonblock::Session;
+use crate::nonblock::{Characteristic, PropertyStream, Session};
 use crate::*;
-use dbus::arg::Get;
-use dbus::strings::Path;
+use dbus::arg::{AppendAll, ReadAll};
+use std::time::Duration;
 
 static DESCRIPTOR_INTERFACE: &str = "org.bluez.GattDescriptor1";
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Descriptor {
     session: Session,
-    path: String,
+    path: DescriptorPath,
+    timeout: Option<Timeout>,
 }
 
 impl Descriptor {
     /// Descriptor作成
-    pub fn new(session: &Session, path: &str) -> Self {
+    pub fn new(session: &Session, path: &DescriptorPath) -> Self {
         Descriptor {
             session: session.clone(),
-            path: path.to_string(),
+            path: path.clone(),
+            timeout: None,
         }
     }
-    pub async fn read_value(&self) -> Result<Vec<u8>, BoxError> {
-        let (value,): (Vec<u8>,) = self
-            .session
-            .method_call(&self.path, DESCRIPTOR_INTERFACE, "ReadValue", ())
-            .await?;
-        Ok(value)
+
+    /// タイムアウトを指定したDescriptorを作成
+    ///
+    /// 作成したDescriptorからの呼び出しにはセッションの既定値の代わりにこの値を使用する。
+    pub fn with_timeout(&self, timeout: Duration) -> Self {
+        Descriptor {
+            session: self.session.clone(),
+            path: self.path.clone(),
+            timeout: Some(Timeout::Duration(timeout)),
+        }
+    }
+
+    /// 期限を指定したDescriptorを作成
+    ///
+    /// 各呼び出しのタイムアウトは期限までの残り時間となる。
+    /// 期限を過ぎている場合は`BluezError::Timeout`を返す。
+    pub fn with_deadline(&self, deadline: Deadline) -> Self {
+        Descriptor {
+            session: self.session.clone(),
+            path: self.path.clone(),
+            timeout: Some(Timeout::Deadline(deadline)),
+        }
     }
 
-    pub async fn write_value(&self, values: Vec<u8>) -> Result<(), BoxError> {
-        Ok(self
-            .session
-            .method_call(&self.path, DESCRIPTOR_INTERFACE, "WriteValue", (values,))
-            .await?)
+    /// ディスクリプターのオブジェクトパスを取得
+    pub fn get_path(&self) -> DescriptorPath {
+        self.path.clone()
     }
 
-    async fn get_property<A: for<'z> Get<'z> + 'static>(
+    /// ディスクリプターの全てのプロパティを一度に取得
+    ///
+    /// `GetAll`を一度だけ呼び出す。セッションのキャッシュが有効な場合は問い合わせを行わない。
+    pub async fn info(&self) -> Result<DescriptorInfo, BluezError> {
+        self.session
+            .get_all(&self.path, Timeout::resolve(self.timeout)?)
+            .await
+    }
+
+    /// ディスクリプターが属するキャラクタリスティックを取得
+    ///
+    /// オブジェクトパスから求めるため、BlueZへの問い合わせは行わない。
+    pub fn characteristic(&self) -> Characteristic {
+        Characteristic::new(&self.session, &self.path.characteristic())
+    }
+
+    /// ディスクリプターのプロパティの変更を受信する
+    pub async fn property_changes(&self) -> Result<PropertyStream<DescriptorProperty>, BluezError> {
+        self.session.property_changes(&self.path).await
+    }
+
+    async fn method_call<R: ReadAll + 'static, A: AppendAll>(
         &self,
-        property: &str,
-    ) -> Result<A, BoxError> {
-        Ok(self
-            .session
-            .get_property(&self.path, DESCRIPTOR_INTERFACE, property)
-            .await?)
+        method: &str,
+        arg: A,
+    ) -> Result<R, BluezError> {
+        let timeout = Timeout::resolve(self.timeout)?;
+        self.session
+            .method_call(&self.path, DESCRIPTOR_INTERFACE, method, arg, timeout)
+            .await
+    }
+
+    async fn get_property<A: FromRefArg>(&self, property: &str) -> Result<A, BluezError> {
+        self.session
+            .get_property(
+                &self.path,
+                DESCRIPTOR_INTERFACE,
+                property,
+                Timeout::resolve(self.timeout)?,
+            )
+            .await
     }
 
     //--------------------------------------------------------------------------------
-    // プロパティ
-    // get
-    async_get_property!(get_uuid, String, "UUID");
-    async_get_property!(get_characteristic, Path<'_>, "Characteristic");
-    async_get_property!(get_value, Vec<u8>, "Value");
-    async_get_property!(get_flags, Vec<String>, "Flags");
+    // メソッド、プロパティ(interfaces/org.bluez.GattDescriptor1.xmlから生成)
+    org_bluez_gattdescriptor1!(members: async_call_method, async_get_property, async_set_property);
 }
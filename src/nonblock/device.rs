@@ -1,114 +1,196 @@
-use crate::nonblock::Session;
+use crate::nonblock::{Adapter, GattService, PropertyStream, Session};
 use crate::*;
-use dbus::arg::{Append, Arg, Get};
-use dbus::strings::Path;
+use dbus::arg::{Append, AppendAll, Arg, ReadAll};
+use std::time::Duration;
 
 static DEVICE_INTERFACE: &str = "org.bluez.Device1";
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Device {
     session: Session,
-    path: String,
+    path: DevicePath,
+    timeout: Option<Timeout>,
 }
 
 impl Device {
     /// デバイス作成
-    pub fn new(session: &Session, path: &str) -> Self {
+    pub fn new(session: &Session, path: &DevicePath) -> Self {
         Device {
             session: session.clone(),
-            path: path.to_string(),
+            path: path.clone(),
+            timeout: None,
         }
     }
 
-    /// デバイスのオブジェクトパスを取得
-    pub fn get_path(&self) -> String {
-        self.path.clone()
+    /// タイムアウトを指定したデバイスを作成
+    ///
+    /// 作成したデバイスからの呼び出しにはセッションの既定値の代わりにこの値を使用する。
+    pub fn with_timeout(&self, timeout: Duration) -> Self {
+        Device {
+            session: self.session.clone(),
+            path: self.path.clone(),
+            timeout: Some(Timeout::Duration(timeout)),
+        }
     }
 
-    /// デバイスに属するgattサービスの一覧を取得
-    pub async fn get_gatt_services(&self) -> Result<Option<Vec<String>>, BoxError> {
-        self.session.get_children(&self.path, "Device").await
+    /// 期限を指定したデバイスを作成
+    ///
+    /// 各呼び出しのタイムアウトは期限までの残り時間となる。
+    /// 期限を過ぎている場合は`BluezError::Timeout`を返す。
+    pub fn with_deadline(&self, deadline: Deadline) -> Self {
+        Device {
+            session: self.session.clone(),
+            path: self.path.clone(),
+            timeout: Some(Timeout::Deadline(deadline)),
+        }
     }
 
-    pub async fn connect(&self) -> Result<(), BoxError> {
-        Ok(self
-            .session
-            .method_call(&self.path, DEVICE_INTERFACE, "Connect", ())
-            .await?)
+    /// デバイスが属するアダプターを取得
+    ///
+    /// オブジェクトパスから求めるため、BlueZへの問い合わせは行わない。
+    pub fn adapter(&self) -> Adapter {
+        Adapter::new(&self.session, &self.path.adapter())
     }
 
-    pub async fn disconnect(&self) -> Result<(), BoxError> {
-        Ok(self
-            .session
-            .method_call(&self.path, DEVICE_INTERFACE, "Disconnect", ())
-            .await?)
+    /// デバイスのオブジェクトパスを取得
+    pub fn get_path(&self) -> DevicePath {
+        self.path.clone()
     }
 
-    pub async fn connect_profile(&self, value: &str) -> Result<(), BoxError> {
-        Ok(self
-            .session
-            .method_call(&self.path, DEVICE_INTERFACE, "ConnectProfile", (value,))
-            .await?)
+    /// デバイスの全てのプロパティを一度に取得
+    ///
+    /// `GetAll`を一度だけ呼び出す。セッションのキャッシュが有効な場合は問い合わせを行わない。
+    pub async fn info(&self) -> Result<DeviceInfo, BluezError> {
+        self.session
+            .get_all(&self.path, Timeout::resolve(self.timeout)?)
+            .await
     }
 
-    pub async fn disconnect_profile(&self, value: &str) -> Result<(), BoxError> {
-        Ok(self
+    /// デバイスに属するgattサービスの一覧を取得
+    pub async fn get_gatt_services(&self) -> Result<Vec<GattService>, BluezError> {
+        let paths: Vec<ServicePath> = self
             .session
-            .method_call(&self.path, DEVICE_INTERFACE, "DisconnectProfile", (value,))
-            .await?)
+            .get_children(&self.path, "Device", Timeout::resolve(self.timeout)?)
+            .await?;
+        Ok(paths
+            .iter()
+            .map(|path| GattService::new(&self.session, path))
+            .collect())
     }
 
-    pub async fn pair(&self) -> Result<(), BoxError> {
-        Ok(self
-            .session
-            .method_call(&self.path, DEVICE_INTERFACE, "Pair", ())
-            .await?)
+    /// ペアリングを行い、完了を待たずに中断された場合は`CancelPairing`を送る
+    ///
+    /// 後始末の保証は次の通り。
+    ///
+    /// - `Pair`の送信後、応答を受け取る前に`Future`が破棄された場合は、その場で`CancelPairing`を送る。
+    /// - `BluezError::Timeout`で失敗した場合も、BlueZ側ではペアリングが続いているため`CancelPairing`を送る。
+    /// - 一度もポーリングされずに破棄された場合は`Pair`自体を送っていないため何も送らない。
+    /// - 成功した場合と、`Timeout`以外のエラーで失敗した場合は何も送らない。
+    ///
+    /// `CancelPairing`は応答を待たずに送り、その結果(ペアリングが既に終わっていた場合のエラーなど)は無視する。
+    /// 切断中で送信できない場合は送らない。
+    pub async fn pair_cancel_safe(&self) -> Result<(), BluezError> {
+        // 期限切れで送信前に失敗する場合は後始末も不要
+        Timeout::resolve(self.timeout)?;
+        let cleanup = Cleanup::new(self, "CancelPairing");
+        cleanup.finish(self.pair().await)
     }
 
-    pub async fn cancel_pairing(&self) -> Result<(), BoxError> {
-        Ok(self
-            .session
-            .method_call(&self.path, DEVICE_INTERFACE, "CancelPairing", ())
-            .await?)
+    /// 接続を行い、完了を待たずに中断された場合は`Disconnect`を送る
+    ///
+    /// 後始末の保証は次の通り。
+    ///
+    /// - `Connect`の送信後、応答を受け取る前に`Future`が破棄された場合は、その場で`Disconnect`を送る。
+    /// - `BluezError::Timeout`で失敗した場合も、BlueZ側では接続処理が続いているため`Disconnect`を送る。
+    /// - 一度もポーリングされずに破棄された場合は`Connect`自体を送っていないため何も送らない。
+    /// - 成功した場合と、`Timeout`以外のエラーで失敗した場合は何も送らない。
+    ///
+    /// `Disconnect`はデバイスの全ての接続を切断するため、他のアプリケーションが接続していた場合も切断される。
+    /// 応答を待たずに送り、その結果は無視する。切断中で送信できない場合は送らない。
+    pub async fn connect_cancel_safe(&self) -> Result<(), BluezError> {
+        // 期限切れで送信前に失敗する場合は後始末も不要
+        Timeout::resolve(self.timeout)?;
+        let cleanup = Cleanup::new(self, "Disconnect");
+        cleanup.finish(self.connect().await)
+    }
+
+    /// デバイスのプロパティの変更を受信する
+    pub async fn property_changes(&self) -> Result<PropertyStream<DeviceProperty>, BluezError> {
+        self.session.property_changes(&self.path).await
     }
 
-    async fn get_property<A: for<'z> Get<'z> + 'static>(
+    async fn method_call<R: ReadAll + 'static, A: AppendAll>(
+        &self,
+        method: &str,
+        arg: A,
+    ) -> Result<R, BluezError> {
+        let timeout = Timeout::resolve(self.timeout)?;
+        self.session
+            .method_call(&self.path, DEVICE_INTERFACE, method, arg, timeout)
+            .await
+    }
+
+    async fn get_property<A: FromRefArg>(
         &self,
         property: &str,
-    ) -> Result<A, BoxError> {
-        Ok(self
-            .session
-            .get_property(&self.path, DEVICE_INTERFACE, property)
-            .await?)
+    ) -> Result<A, BluezError> {
+        self.session
+            .get_property(
+                &self.path,
+                DEVICE_INTERFACE,
+                property,
+                Timeout::resolve(self.timeout)?,
+            )
+            .await
     }
-    async fn set_property<T: Append + Arg>(&self, prop: &str, value: T) -> Result<(), BoxError> {
-        Ok(self
-            .session
-            .set_property(&self.path, DEVICE_INTERFACE, prop, value)
-            .await?)
+    async fn set_property<T: Append + Arg>(&self, prop: &str, value: T) -> Result<(), BluezError> {
+        self.session
+            .set_property(
+                &self.path,
+                DEVICE_INTERFACE,
+                prop,
+                value,
+                Timeout::resolve(self.timeout)?,
+            )
+            .await
     }
 
     //--------------------------------------------------------------------------------
-    // プロパティ
-    // get
-    async_get_property!(get_address, String, "Address");
-    async_get_property!(get_name, String, "Name");
-    async_get_property!(get_icon, String, "Icon");
-    async_get_property!(get_class, u32, "Class");
-    async_get_property!(get_appearance, u16, "Appearance");
-    async_get_property!(get_uuids, Vec<String>, "UUIDs");
-    async_get_property!(is_paired, bool, "Paired");
-    async_get_property!(is_connected, bool, "Connected");
-    async_get_property!(is_trusted, bool, "Trusted");
-    async_get_property!(is_blocked, bool, "Blocked");
-    async_get_property!(get_alias, String, "Alias");
-    async_get_property!(get_adapter, Path<'_>, "Adapter");
-    async_get_property!(is_legacy_pairing, bool, "LegacyPairing");
-    async_get_property!(get_modalias, String, "Modalias");
-    async_get_property!(get_rssi, i16, "RSSI");
-    async_get_property!(get_tx_power, i16, "TxPower");
-    // set
-    async_set_property!(set_trusted, bool, "Trusted");
-    async_set_property!(set_blocked, bool, "Blocked");
-    async_set_property!(set_alias, String, "Alias");
+    // メソッド、プロパティ(interfaces/org.bluez.Device1.xmlから生成)
+    org_bluez_device1!(members: async_call_method, async_get_property, async_set_property);
+}
+
+/// 破棄された時点で操作が完了していなければ後始末のメソッドを送る
+struct Cleanup<'a> {
+    device: &'a Device,
+    method: &'static str,
+    armed: bool,
+}
+
+impl<'a> Cleanup<'a> {
+    fn new(device: &'a Device, method: &'static str) -> Self {
+        Cleanup {
+            device,
+            method,
+            armed: true,
+        }
+    }
+
+    /// 操作の結果を受け取る
+    ///
+    /// タイムアウトした場合はBlueZ側で操作が続いているため、後始末を行う。
+    fn finish(mut self, result: Result<(), BluezError>) -> Result<(), BluezError> {
+        self.armed = matches!(result, Err(BluezError::Timeout(_)));
+        result
+    }
+}
+
+impl Drop for Cleanup<'_> {
+    fn drop(&mut self) {
+        if self.armed {
+            self.device
+                .session
+                .call_no_reply(&self.device.path, DEVICE_INTERFACE, self.method);
+        }
+    }
 }
@@ -0,0 +1,123 @@
+use super::connection::Shared;
+use crate::*;
+use dbus::channel::{Sender, Token};
+use dbus::Message;
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::task::{Context, Poll};
+use futures::Stream;
+use std::pin::Pin;
+use std::sync::{Arc, Weak};
+
+/// オブジェクトの追加・削除のイベントのストリーム
+///
+/// `Session::events`で作成する。
+///
+/// ```no_run
+/// use bluez_dbus::nonblock::Session;
+/// use bluez_dbus::Event;
+/// use futures::StreamExt;
+///
+/// # async fn run() -> Result<(), bluez_dbus::BluezError> {
+/// let s = Session::new()?;
+/// let mut events = s.events().await?;
+/// while let Some(event) = events.next().await {
+///     if let Event::DeviceAdded { device, .. } = event {
+///         println!("{}", device);
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub type EventStream = SignalStream<Event>;
+
+/// プロパティの変更のストリーム
+///
+/// `Device::property_changes`などで作成する。
+///
+/// ```no_run
+/// use bluez_dbus::nonblock::{Device, Session};
+/// use bluez_dbus::DeviceProperty;
+/// use futures::StreamExt;
+///
+/// # async fn run() -> Result<(), bluez_dbus::BluezError> {
+/// let s = Session::new()?;
+/// let dev = Device::new(&s, &"/org/bluez/hci0/dev_00_11_22_33_44_55".parse()?);
+/// let mut changes = dev.property_changes().await?;
+/// while let Some(change) = changes.next().await {
+///     if let DeviceProperty::Connected(connected) = change {
+///         println!("{}", connected);
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub type PropertyStream<P> = SignalStream<P>;
+
+/// シグナルのストリーム
+///
+/// D-Busとの接続が切れて再接続しない場合(`SessionBuilder::reconnect`の指定が無いか、試行回数の上限に達した場合)や、
+/// セッション(と複製したもの)が全て破棄された場合はストリームが終了する。
+/// 再接続する場合は、再接続後も受信を続ける。
+/// 破棄すると受信を止める。
+pub struct SignalStream<T> {
+    _subscriber: Subscriber,
+    rx: UnboundedReceiver<T>,
+}
+
+impl<T> SignalStream<T> {
+    pub(in crate) fn new(subscriber: Subscriber, rx: UnboundedReceiver<T>) -> Self {
+        SignalStream {
+            _subscriber: subscriber,
+            rx,
+        }
+    }
+}
+
+impl<T> Stream for SignalStream<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        Pin::new(&mut self.rx).poll_next(cx)
+    }
+}
+
+/// シグナルの受信登録
+///
+/// 再接続した場合も登録は引き継がれる。
+/// 破棄すると受信者を削除し、その時点のコネクションからD-Busのルールも削除する。
+pub(in crate) struct Subscriber {
+    shared: Weak<Shared>,
+    token: Token,
+    rule: String,
+}
+
+impl Subscriber {
+    pub(in crate) fn new(shared: &Arc<Shared>, token: Token, rule: String) -> Self {
+        Subscriber {
+            shared: Arc::downgrade(shared),
+            token,
+            rule,
+        }
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        let shared = match self.shared.upgrade() {
+            Some(shared) => shared,
+            None => return,
+        };
+        shared.handlers().lock().unwrap().remove(self.token);
+        // 応答を待てないため、送信のみ行う
+        if let Ok(conn) = shared.connection() {
+            if let Ok(msg) = Message::new_method_call(
+                "org.freedesktop.DBus",
+                "/org/freedesktop/DBus",
+                "org.freedesktop.DBus",
+                "RemoveMatch",
+            ) {
+                let _ = conn.send(msg.append1(&self.rule));
+            }
+        }
+    }
+}
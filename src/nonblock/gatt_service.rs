@@ -1,46 +1,104 @@
-use crate::nonblock::Session;
+use crate::nonblock::{Characteristic, Device, PropertyStream, Session};
 use crate::*;
-use dbus::arg::Get;
-use dbus::strings::Path;
+use std::time::Duration;
 
 static GATT_SERVICE_INTERFACE: &str = "org.bluez.GattService1";
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct GattService {
     session: Session,
-    path: String,
+    path: ServicePath,
+    timeout: Option<Timeout>,
 }
 
 impl GattService {
     /// Gatt Service作成
-    pub fn new(session: &Session, path: &str) -> Self {
+    pub fn new(session: &Session, path: &ServicePath) -> Self {
         GattService {
             session: session.clone(),
-            path: path.to_string(),
+            path: path.clone(),
+            timeout: None,
         }
     }
 
+    /// タイムアウトを指定したGatt Serviceを作成
+    ///
+    /// 作成したGatt Serviceからの呼び出しにはセッションの既定値の代わりにこの値を使用する。
+    pub fn with_timeout(&self, timeout: Duration) -> Self {
+        GattService {
+            session: self.session.clone(),
+            path: self.path.clone(),
+            timeout: Some(Timeout::Duration(timeout)),
+        }
+    }
+
+    /// 期限を指定したGatt Serviceを作成
+    ///
+    /// 各呼び出しのタイムアウトは期限までの残り時間となる。
+    /// 期限を過ぎている場合は`BluezError::Timeout`を返す。
+    pub fn with_deadline(&self, deadline: Deadline) -> Self {
+        GattService {
+            session: self.session.clone(),
+            path: self.path.clone(),
+            timeout: Some(Timeout::Deadline(deadline)),
+        }
+    }
+
+    /// GATTサービスのオブジェクトパスを取得
+    pub fn get_path(&self) -> ServicePath {
+        self.path.clone()
+    }
+
+    /// GATTサービスの全てのプロパティを一度に取得
+    ///
+    /// `GetAll`を一度だけ呼び出す。セッションのキャッシュが有効な場合は問い合わせを行わない。
+    pub async fn info(&self) -> Result<GattServiceInfo, BluezError> {
+        self.session
+            .get_all(&self.path, Timeout::resolve(self.timeout)?)
+            .await
+    }
+
+    /// GATTサービスが属するデバイスを取得
+    ///
+    /// オブジェクトパスから求めるため、BlueZへの問い合わせは行わない。
+    pub fn device(&self) -> Device {
+        Device::new(&self.session, &self.path.device())
+    }
+
     /// Gatt Serviceに属するCharacteristicの一覧を取得
-    pub async fn get_characteristics(&self) -> Result<Option<Vec<String>>, BoxError> {
-        self.session.get_children(&self.path, "Service").await
+    pub async fn get_characteristics(&self) -> Result<Vec<Characteristic>, BluezError> {
+        let paths: Vec<CharacteristicPath> = self
+            .session
+            .get_children(&self.path, "Service", Timeout::resolve(self.timeout)?)
+            .await?;
+        Ok(paths
+            .iter()
+            .map(|path| Characteristic::new(&self.session, path))
+            .collect())
     }
 
-    async fn get_property<A: for<'z> Get<'z> + 'static>(
+    /// GATTサービスのプロパティの変更を受信する
+    pub async fn property_changes(
+        &self,
+    ) -> Result<PropertyStream<GattServiceProperty>, BluezError> {
+        self.session.property_changes(&self.path).await
+    }
+
+    async fn get_property<A: FromRefArg>(
         &self,
         property: &str,
-    ) -> Result<A, BoxError> {
-        Ok(self
-            .session
-            .get_property(&self.path, GATT_SERVICE_INTERFACE, property)
-            .await?)
+    ) -> Result<A, BluezError> {
+        self.session
+            .get_property(
+                &self.path,
+                GATT_SERVICE_INTERFACE,
+                property,
+                Timeout::resolve(self.timeout)?,
+            )
+            .await
     }
 
     //--------------------------------------------------------------------------------
-    // プロパティ
-    // get
-    async_get_property!(get_uuid, String, "UUID");
-    async_get_property!(is_primary, bool, "Primary");
-    async_get_property!(get_device, Path<'_>, "Device");
-    // get_property!(get_characteristics, Vec<String>, "Characteristics");
-    async_get_property!(get_includes, Vec<Path<'_>>, "Includes");
+    // メソッド、プロパティ(interfaces/org.bluez.GattService1.xmlから生成)
+    org_bluez_gattservice1!(members: async_call_method, async_get_property, async_set_property);
 }
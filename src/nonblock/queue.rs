@@ -0,0 +1,229 @@
+use crate::nonblock::{Characteristic, Device};
+use crate::*;
+use futures_timer::Delay;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+type Lock = Arc<tokio::sync::Mutex<()>>;
+
+/// デバイスごとに操作を直列化し、一時的なエラーを再試行するキュー
+///
+/// 同じデバイスに対する操作は、このキュー(と複製したもの)を通したもの同士で一つずつ実行する。
+/// 再試行の待ち時間の間もデバイスの順番は保持したままとなる。
+/// キューを通さない呼び出しとは直列化しない。
+/// 実行中の`Future`を破棄すると、その時点で順番を解放する。
+///
+/// ```no_run
+/// use bluez_dbus::nonblock::{OperationQueue, Session};
+/// use bluez_dbus::RetryPolicy;
+///
+/// # async fn run() -> Result<(), bluez_dbus::BluezError> {
+/// let s = Session::new()?;
+/// let queue = OperationQueue::new(RetryPolicy::default());
+/// for device in s.default_adapter().await?.get_devices().await? {
+///     let connected = queue.device(&device).connect().await?;
+///     println!("{}: connected after {} attempts", device.get_path(), connected.attempts);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Default)]
+pub struct OperationQueue {
+    policy: RetryPolicy,
+    devices: Arc<Mutex<HashMap<DevicePath, Lock>>>,
+}
+
+impl OperationQueue {
+    pub fn new(policy: RetryPolicy) -> Self {
+        OperationQueue {
+            policy,
+            devices: Default::default(),
+        }
+    }
+
+    /// 再試行の方針を取得
+    pub fn policy(&self) -> &RetryPolicy {
+        &self.policy
+    }
+
+    /// キューを通して操作するデバイスを作成
+    pub fn device(&self, device: &Device) -> QueuedDevice {
+        QueuedDevice {
+            queue: self.clone(),
+            device: device.clone(),
+        }
+    }
+
+    /// キューを通して操作するキャラクタリスティックを作成
+    ///
+    /// キャラクタリスティックが属するデバイスの操作と直列化する。
+    pub fn characteristic(&self, characteristic: &Characteristic) -> QueuedCharacteristic {
+        QueuedCharacteristic {
+            queue: self.clone(),
+            characteristic: characteristic.clone(),
+        }
+    }
+
+    /// デバイスの順番を待って`f`が返す`Future`を実行する
+    ///
+    /// 一時的なエラーで失敗した場合は方針に従って待ち、`f`を再度呼び出す。
+    /// 成功・失敗のいずれも試行回数を返す。
+    pub async fn run<T, F, Fut>(
+        &self,
+        device: &DevicePath,
+        mut f: F,
+    ) -> Result<Retried<T>, Retried<BluezError>>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, BluezError>>,
+    {
+        let turn = Turn::acquire(self, device);
+        let _guard = turn.lock.lock().await;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            match f().await {
+                Ok(value) => return Ok(Retried { value, attempts }),
+                Err(err) => match self.policy.backoff(&err, attempts) {
+                    Some(delay) => Delay::new(delay).await,
+                    None => {
+                        return Err(Retried {
+                            value: err,
+                            attempts,
+                        })
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// デバイスの順番待ち
+///
+/// 破棄した時点で順番待ちをしているものが無ければロックを削除する。
+/// `Future`が途中で破棄された場合も削除されるようにするため、`Drop`で行う。
+struct Turn<'a> {
+    queue: &'a OperationQueue,
+    device: DevicePath,
+    lock: Lock,
+}
+
+impl<'a> Turn<'a> {
+    fn acquire(queue: &'a OperationQueue, device: &DevicePath) -> Self {
+        let lock = {
+            let mut devices = queue.devices.lock().unwrap();
+            devices.entry(device.clone()).or_default().clone()
+        };
+        Turn {
+            queue,
+            device: device.clone(),
+            lock,
+        }
+    }
+}
+
+impl Drop for Turn<'_> {
+    fn drop(&mut self) {
+        let mut devices = self.queue.devices.lock().unwrap_or_else(|e| e.into_inner());
+        // キューが保持するものと`self.lock`のみであれば、他に待っているものはいない
+        if Arc::strong_count(&self.lock) == 2 {
+            devices.remove(&self.device);
+        }
+    }
+}
+
+/// キューを通して操作するデバイス
+#[derive(Clone)]
+pub struct QueuedDevice {
+    queue: OperationQueue,
+    device: Device,
+}
+
+impl QueuedDevice {
+    /// 元のデバイスを取得
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    /// デバイスの順番を待って`f`が返す`Future`を実行する
+    pub async fn run<T, F, Fut>(&self, f: F) -> Result<Retried<T>, Retried<BluezError>>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, BluezError>>,
+    {
+        self.queue.run(&self.device.get_path(), f).await
+    }
+
+    pub async fn connect(&self) -> Result<Retried<()>, Retried<BluezError>> {
+        let device = &self.device;
+        self.run(|| device.connect()).await
+    }
+
+    pub async fn disconnect(&self) -> Result<Retried<()>, Retried<BluezError>> {
+        let device = &self.device;
+        self.run(|| device.disconnect()).await
+    }
+
+    pub async fn connect_profile(&self, uuid: &str) -> Result<Retried<()>, Retried<BluezError>> {
+        let device = &self.device;
+        self.run(|| device.connect_profile(uuid)).await
+    }
+
+    pub async fn disconnect_profile(&self, uuid: &str) -> Result<Retried<()>, Retried<BluezError>> {
+        let device = &self.device;
+        self.run(|| device.disconnect_profile(uuid)).await
+    }
+
+    pub async fn pair(&self) -> Result<Retried<()>, Retried<BluezError>> {
+        let device = &self.device;
+        self.run(|| device.pair()).await
+    }
+}
+
+/// キューを通して操作するキャラクタリスティック
+#[derive(Clone)]
+pub struct QueuedCharacteristic {
+    queue: OperationQueue,
+    characteristic: Characteristic,
+}
+
+impl QueuedCharacteristic {
+    /// 元のキャラクタリスティックを取得
+    pub fn characteristic(&self) -> &Characteristic {
+        &self.characteristic
+    }
+
+    /// キャラクタリスティックが属するデバイスの順番を待って`f`が返す`Future`を実行する
+    pub async fn run<T, F, Fut>(&self, f: F) -> Result<Retried<T>, Retried<BluezError>>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, BluezError>>,
+    {
+        let device = self.characteristic.get_path().service().device();
+        self.queue.run(&device, f).await
+    }
+
+    /// オプションを指定せずに呼び出す
+    pub async fn read_value(&self) -> Result<Retried<Vec<u8>>, Retried<BluezError>> {
+        let characteristic = &self.characteristic;
+        self.run(|| characteristic.read_value(HashMap::new())).await
+    }
+
+    /// オプションを指定せずに呼び出す
+    pub async fn write_value(&self, values: Vec<u8>) -> Result<Retried<()>, Retried<BluezError>> {
+        let characteristic = &self.characteristic;
+        self.run(|| characteristic.write_value(values.clone(), HashMap::new()))
+            .await
+    }
+
+    pub async fn start_notify(&self) -> Result<Retried<()>, Retried<BluezError>> {
+        let characteristic = &self.characteristic;
+        self.run(|| characteristic.start_notify()).await
+    }
+
+    pub async fn stop_notify(&self) -> Result<Retried<()>, Retried<BluezError>> {
+        let characteristic = &self.characteristic;
+        self.run(|| characteristic.stop_notify()).await
+    }
+}
@@ -1,16 +1,25 @@
-use crate::*;
-use dbus::arg::{Append, AppendAll, Arg, Get, ReadAll, Variant};
-#[cfg(feature = "local")]
-use dbus::nonblock::LocalConnection;
-use dbus::nonblock::Proxy;
 #[cfg(not(feature = "local"))]
-use dbus::nonblock::SyncConnection;
-use dbus_tokio::connection as dbus_conn;
+use super::connection::Spawner;
+use super::connection::{Conn, ConnectionState, Driver, ReconnectPolicy, Shared};
+use super::events::Subscriber;
+use super::{Adapter, EventStream, PropertyStream, Transport};
+use crate::path::parse_sorted;
+use crate::*;
+use dbus::arg::{Append, AppendAll, Arg, ReadAll, RefArg, Variant};
+use dbus::channel::Sender;
+use dbus::message::MatchRule;
+use dbus::nonblock::{NonblockReply, Proxy};
+use dbus::Message;
+use futures::channel::mpsc;
+use futures::future::{self, Either};
+use futures_timer::Delay;
+use std::collections::HashMap;
 use std::fmt;
 use std::fmt::Debug;
-use std::sync::Arc;
+use std::future::Future;
+use std::sync::{Arc, Mutex, Weak};
 use std::time::Duration;
-use tokio::task;
+use tokio::sync::{oneshot, watch};
 
 static MANAGED_OBJECT_INTERFACE: &str = "org.freedesktop.DBus.ObjectManager";
 static MANAGED_OBJECT_METHOD: &str = "GetManagedObjects";
@@ -18,80 +27,401 @@ static MANAGED_OBJECT_METHOD: &str = "GetManagedObjects";
 /// BlueZとのセッション
 #[derive(Clone)]
 pub struct Session {
-    #[cfg(not(feature = "local"))]
-    conn: Arc<SyncConnection>,
-    #[cfg(feature = "local")]
-    conn: Arc<LocalConnection>,
+    shared: Arc<Shared>,
+    transport: Option<Arc<dyn Transport>>,
+    service: Arc<str>,
+    timeout: Duration,
+    cache: Option<Arc<Cache>>,
+    metrics: Option<Arc<dyn Metrics>>,
+    capabilities: Arc<Mutex<Option<Capabilities>>>,
+    #[cfg(feature = "record")]
+    recorder: Option<Arc<Recorder>>,
+}
+
+/// セッション間で共有するオブジェクトのキャッシュ
+struct Cache {
+    subscription: tokio::sync::Mutex<Subscription>,
+    objects: Arc<Mutex<ObjectCache>>,
+}
+
+/// キャッシュの更新に使用するシグナルの受信登録
+///
+/// 再接続でコネクションが変わった場合は登録し直す。
+#[derive(Default)]
+struct Subscription {
+    conn: Weak<Conn>,
+    // 破棄すると受信を止めるため保持しておく
+    subscribers: Vec<Subscriber>,
 }
 
 impl Debug for Session {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Session {{ conn: {} }}", self.conn.unique_name())
+        write!(
+            f,
+            "Session {{ conn: {}, service: {}, timeout: {:?} }}",
+            self.shared
+                .connection()
+                .map(|conn| conn.unique_name().to_string())
+                .unwrap_or_else(|_| format!("{:?}", self.shared.state())),
+            self.service,
+            self.timeout
+        )
+    }
+}
+
+/// `Session`の作成
+///
+/// 接続先のバスやタイムアウトなどを指定してセッションを作成する。
+/// 既定ではtokioのランタイム内では`dbus-tokio`を使用し、それ以外(async-std、smolなど)や
+/// `Bus::Address`のバスには専用スレッドで送受信を行うコネクションを使用する。
+///
+/// D-Busとの接続が切れた場合、実行中の呼び出しは`BluezError::ConnectionLost`で失敗する。
+/// `reconnect`を指定すると自動で再接続を行う。
+///
+/// ```no_run
+/// use bluez_dbus::nonblock::SessionBuilder;
+/// use bluez_dbus::Bus;
+/// use std::time::Duration;
+///
+/// # async fn run() -> Result<(), bluez_dbus::BluezError> {
+/// let session = SessionBuilder::new()
+///     .bus(Bus::Session)
+///     .timeout(Duration::from_secs(30))
+///     .build()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Default)]
+pub struct SessionBuilder {
+    bus: Bus,
+    connection: Option<Arc<Conn>>,
+    transport: Option<Arc<dyn Transport>>,
+    service: Option<String>,
+    timeout: Option<Duration>,
+    reconnect: Option<ReconnectPolicy>,
+    cache: bool,
+    driver: Driver,
+    #[cfg(not(feature = "local"))]
+    spawner: Option<Arc<dyn Spawner>>,
+    metrics: Option<Arc<dyn Metrics>>,
+    #[cfg(feature = "record")]
+    recorder: Option<Recorder>,
+}
+
+impl SessionBuilder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// 接続するバスを指定(既定値はシステムバス)
+    ///
+    /// `dbus-tokio`は`Bus::Address`のバスに対応していないため、`Driver::Auto`では専用スレッドで送受信を行う。
+    /// `Driver::Tokio`を指定した場合と`local`フィーチャーでは、`build`が
+    /// `org.freedesktop.DBus.Error.NotSupported`のエラーで失敗する。
+    pub fn bus(mut self, bus: Bus) -> Self {
+        self.bus = bus;
+        self
+    }
+
+    /// 既存のコネクションを使用する
+    ///
+    /// 指定した場合は`bus`の指定は無視される。
+    /// コネクションの送受信処理は呼び出し側で行う必要がある。
+    pub fn connection(mut self, conn: Arc<Conn>) -> Self {
+        self.connection = Some(conn);
+        self
+    }
+
+    /// D-Busのコネクションの代わりに使用する通信路を指定する
+    ///
+    /// 指定した場合はD-Busに接続せず、`bus`、`connection`などの指定は無視される。
+    /// シグナルの受信(`events`など)は`BluezError::ConnectionLost`で失敗し、キャッシュは使用しない。
+    pub fn transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.transport = Some(transport);
+        self
+    }
+
+    /// BlueZのサービス名を指定(既定値は`org.bluez`)
+    pub fn service(mut self, service: &str) -> Self {
+        self.service = Some(service.to_string());
+        self
+    }
+
+    /// メソッド呼び出しのタイムアウトを指定(既定値は10秒)
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// 切断時に自動で再接続する
+    ///
+    /// 作成済みの`EventStream`や`PropertyStream`は再接続後のコネクションで受信を続ける
+    /// (切断中に送られたシグナルは受信できない)。
+    /// 既存のコネクションを指定した場合は無視される。
+    pub fn reconnect(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect = Some(policy);
+        self
+    }
+
+    /// オブジェクトのキャッシュを使用する
+    ///
+    /// 有効にすると`GetManagedObjects`の結果をキャッシュし、シグナルを受信して最新の状態に保つ。
+    /// アダプターやデバイスなどの一覧の取得で毎回`GetManagedObjects`を呼び出さなくなる。
+    /// キャッシュは最初の一覧の取得時に作成し、再接続した場合は作成し直す。
+    pub fn cache(mut self, enabled: bool) -> Self {
+        self.cache = enabled;
+        self
+    }
+
+    /// D-Busとの送受信を行う方式を指定(既定値は`Driver::Auto`)
+    ///
+    /// `Driver::Tokio`は`Bus::Address`のバスに対応しておらず、専用スレッドに切り替えずにエラーを返す。
+    /// 既存のコネクションを指定した場合は無視される。
+    pub fn driver(mut self, driver: Driver) -> Self {
+        self.driver = driver;
+        self
+    }
+
+    /// 切断の監視と再接続を行うタスクの起動方法を指定
+    ///
+    /// 既存のコネクションを指定した場合は無視される。
+    #[cfg(not(feature = "local"))]
+    pub fn spawner(mut self, spawner: Arc<dyn Spawner>) -> Self {
+        self.spawner = Some(spawner);
+        self
+    }
+
+    /// BlueZの呼び出しの計測を行う
+    ///
+    /// メソッド呼び出し、プロパティの取得・設定、`GetManagedObjects`の完了ごとに`Metrics::record`を呼ぶ。
+    /// キャッシュから応答した場合や、完了前に`Future`が破棄された場合は呼ばれない。
+    pub fn metrics(mut self, metrics: Arc<dyn Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// D-Busの通信を記録する
+    ///
+    /// メソッド呼び出し、応答、エラー、受信したシグナルを書き出す。
+    /// シグナルは受信を登録している間(`events`やキャッシュの使用中)のみ記録する。
+    #[cfg(feature = "record")]
+    pub fn record(mut self, recorder: Recorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// セッションの作成
+    #[cfg_attr(feature = "local", allow(clippy::arc_with_non_send_sync))]
+    pub fn build(self) -> Result<Session, BluezError> {
+        let shared = match (&self.transport, self.connection) {
+            (Some(_), _) => Shared::detached(),
+            (None, Some(conn)) => Shared::with_connection(conn),
+            (None, None) => Shared::connect(
+                self.bus,
+                self.driver,
+                self.reconnect,
+                #[cfg(not(feature = "local"))]
+                self.spawner,
+            )?,
+        };
+        #[cfg(feature = "record")]
+        let recorder = self.recorder.map(Arc::new);
+        #[cfg(feature = "record")]
+        if let Some(recorder) = &recorder {
+            let recorder = recorder.clone();
+            shared
+                .handlers()
+                .lock()
+                .unwrap()
+                .tap(move |msg| recorder.signal(msg));
+        }
+        let service = self.service.as_deref().unwrap_or(BLUEZ_SERVICE);
+        let cache = if self.cache && self.transport.is_none() {
+            Some(Arc::new(Cache {
+                subscription: Default::default(),
+                objects: Arc::new(Mutex::new(ObjectCache::new(service))),
+            }))
+        } else {
+            None
+        };
+        Ok(Session {
+            shared,
+            transport: self.transport,
+            service: service.into(),
+            timeout: self.timeout.unwrap_or(DEFAULT_TIMEOUT),
+            cache,
+            metrics: self.metrics,
+            capabilities: Default::default(),
+            #[cfg(feature = "record")]
+            recorder,
+        })
     }
 }
 
 /// BlueZとの通信を行うセッション
 impl Session {
     /// BlueZとの通信を行うセッションの作成
-    pub fn new() -> Result<Self, BoxError> {
-        #[cfg(not(feature = "local"))]
-        let conn = {
-            let (resource, conn) = dbus_conn::new_system_sync()?;
-            task::spawn(async move {
-                let err = resource.await;
-                panic!("Lost connection to D-Bus: {}", err);
-            });
-            conn
-        };
-        #[cfg(feature = "local")]
-        let conn = {
-            let (resource, conn) = dbus_conn::new_system_local()?;
-            task::spawn_local(async move {
-                let err = resource.await;
-                panic!("Lost connection to D-Bus: {}", err);
-            });
-            conn
-        };
+    ///
+    /// システムバスの`org.bluez`に接続する。
+    /// 接続先などを変更する場合は`SessionBuilder`を使用する。
+    pub fn new() -> Result<Self, BluezError> {
+        SessionBuilder::new().build()
+    }
 
-        Ok(Session { conn })
+    /// `SessionBuilder`の作成
+    pub fn builder() -> SessionBuilder {
+        SessionBuilder::new()
     }
 
-    /// bluetoothアダプターの一覧を取得
-    pub async fn get_adapters(&self) -> Result<Option<Vec<String>>, BoxError> {
-        let objects = self.get_managed_objects().await?;
+    /// D-Busとの接続状態を取得
+    pub fn connection_state(&self) -> ConnectionState {
+        self.shared.state()
+    }
 
-        let adapters: Vec<String> = objects
+    /// D-Busとの接続状態の変化を監視する
+    pub fn watch_connection_state(&self) -> watch::Receiver<ConnectionState> {
+        self.shared.watch_state()
+    }
+
+    /// bluetoothアダプターの一覧を取得
+    ///
+    /// パスの順に並べる。アダプターが無い場合は空の`Vec`を返す。
+    pub async fn get_adapters(&self) -> Result<Vec<Adapter>, BluezError> {
+        let paths = match self.cache(None).await? {
+            Some(cache) => cache.lock().unwrap().objects_with(ADAPTER_INTERFACE),
+            None => self
+                .get_managed_objects(None)
+                .await?
+                .iter()
+                .filter(|(_, value)| value.contains_key(ADAPTER_INTERFACE))
+                .map(|(key, _)| key.to_string())
+                .collect(),
+        };
+        Ok(parse_sorted(paths)
             .iter()
-            .filter_map(|(key, value)| {
-                if value.contains_key(ADAPTER_INTERFACE) {
-                    return Some(key.to_string());
+            .map(|path| Adapter::new(self, path))
+            .collect())
+    }
+
+    /// 既定のアダプターを取得
+    ///
+    /// パスの順で最初のアダプター(通常は`/org/bluez/hci0`)を返す。
+    /// アダプターが無い場合は`BluezError::DoesNotExist`を返す。
+    pub async fn default_adapter(&self) -> Result<Adapter, BluezError> {
+        self.get_adapters()
+            .await?
+            .into_iter()
+            .next()
+            .ok_or_else(|| BluezError::DoesNotExist("no bluetooth adapter".to_string()))
+    }
+
+    /// 接続先のBlueZが提供するインターフェース、メソッド、プロパティを調べる
+    ///
+    /// `/org/bluez`と、`GetManagedObjects`で得たオブジェクトのうち未知のインターフェースを持つものを
+    /// イントロスペクションし、存在するメンバーからBlueZのバージョンを推定する。
+    /// アダプターが存在する場合は結果をセッション(と複製したもの)で保持し、二回目以降は問い合わせを行わない。
+    pub async fn capabilities(&self) -> Result<Capabilities, BluezError> {
+        if let Some(capabilities) = self.capabilities.lock().unwrap().clone() {
+            return Ok(capabilities);
+        }
+        let objects = self.get_managed_objects(None).await?;
+        let mut xmls = vec![];
+        for path in Capabilities::introspection_paths(&objects) {
+            let (xml,): (String,) = self
+                .method_call(&path, INTROSPECTABLE_INTERFACE, "Introspect", (), None)
+                .await?;
+            xmls.push(xml);
+        }
+        let capabilities = Capabilities::from_introspection(&xmls, &objects)?;
+        if capabilities.has_interface(ADAPTER_INTERFACE) {
+            *self.capabilities.lock().unwrap() = Some(capabilities.clone());
+        }
+        Ok(capabilities)
+    }
+
+    /// オブジェクトの追加・削除のイベントを受信する
+    ///
+    /// 返されたストリームが破棄されると、次のシグナルの受信時に受信を止める。
+    pub async fn events(&self) -> Result<EventStream, BluezError> {
+        let conn = self.shared.connection()?;
+        let (tx, rx) = mpsc::unbounded();
+        let rule = Event::match_rule(&self.service);
+        let subscriber = self
+            .add_match(&conn, rule, move |msg| {
+                for event in Event::from_message(msg) {
+                    let _ = tx.unbounded_send(event);
                 }
-                None
             })
-            .collect();
+            .await?;
+        Ok(EventStream::new(subscriber, rx))
+    }
 
-        if adapters.is_empty() {
-            Ok(None)
-        } else {
-            Ok(Some(adapters))
-        }
+    /// 指定のオブジェクトのプロパティの変更を受信する
+    pub(in crate) async fn property_changes<P: PropertyChange>(
+        &self,
+        path: &str,
+    ) -> Result<PropertyStream<P>, BluezError> {
+        let conn = self.shared.connection()?;
+        let (tx, rx) = mpsc::unbounded();
+        let rule = P::match_rule(&self.service, path);
+        let subscriber = self
+            .add_match(&conn, rule, move |msg| {
+                for change in P::from_message(msg) {
+                    let _ = tx.unbounded_send(change);
+                }
+            })
+            .await?;
+        Ok(PropertyStream::new(subscriber, rx))
     }
 
-    pub(in crate) async fn get_property<A: for<'z> Get<'z> + 'static>(
+    pub(in crate) async fn get_property<A: FromRefArg>(
         &self,
         path: &str,
         interface: &str,
         property: &str,
-    ) -> Result<A, BoxError> {
-        let (value,): (Variant<A>,) = self
-            .method_call(
-                path,
-                "org.freedesktop.DBus.Properties",
-                "Get",
-                (interface, property.to_string()),
-            )
+        timeout: Option<Duration>,
+    ) -> Result<A, BluezError> {
+        let call = self.call(
+            path,
+            PROPERTIES_INTERFACE,
+            "Get",
+            (interface, property.to_string()),
+            timeout,
+        );
+        let (value,): (Variant<Box<dyn RefArg>>,) = self
+            .traced(path, interface, "Get", Some(property), call)
             .await?;
-        Ok(value.0)
+        A::from_refarg(&*value.0).map_err(|e| {
+            BluezError::TypeMismatch(e.describe(&format!("{}.{}", interface, property)))
+        })
+    }
+
+    /// インターフェースの全てのプロパティを取得
+    ///
+    /// キャッシュが有効な場合はキャッシュから作成し、BlueZへの問い合わせは行わない。
+    pub(in crate) async fn get_all<I: PropertyInfo>(
+        &self,
+        path: &str,
+        timeout: Option<Duration>,
+    ) -> Result<I, BluezError> {
+        if let Some(cache) = self.cache(timeout).await? {
+            let cache = cache.lock().unwrap();
+            return match cache.properties(path, I::INTERFACE) {
+                Some(properties) => I::from_properties(properties),
+                None => Err(BluezError::ObjectVanished(path.to_string())),
+            };
+        }
+        let call = self.call(
+            path,
+            PROPERTIES_INTERFACE,
+            "GetAll",
+            (I::INTERFACE,),
+            timeout,
+        );
+        let (properties,): (HashMap<String, Variant<Box<dyn RefArg>>>,) = self
+            .traced(path, I::INTERFACE, "GetAll", None, call)
+            .await?;
+        I::from_properties(&Properties::from(properties))
     }
 
     pub(in crate) async fn set_property<A: Append + Arg>(
@@ -100,17 +430,18 @@ impl Session {
         interface: &str,
         property: &str,
         value: A,
-    ) -> Result<(), BoxError> {
+        timeout: Option<Duration>,
+    ) -> Result<(), BluezError> {
         let value = Variant(value);
-        let _ = self
-            .method_call(
-                path,
-                "org.freedesktop.DBus.Properties",
-                "Set",
-                (interface, property.to_string(), value),
-            )
-            .await?;
-        Ok(())
+        let call = self.call(
+            path,
+            PROPERTIES_INTERFACE,
+            "Set",
+            (interface, property.to_string(), value),
+            timeout,
+        );
+        self.traced(path, interface, "Set", Some(property), call)
+            .await
     }
 
     /// BlueZに対するメソッド実行
@@ -120,49 +451,286 @@ impl Session {
         interface: &str,
         method: &str,
         arg: A,
-    ) -> Result<R, dbus::Error> {
-        let conn = self.conn.clone();
-        let proxy = Proxy::new(BLUEZ_SERVICE, path, Duration::from_secs(10), conn);
-        proxy.method_call(interface, method, arg).await
+        timeout: Option<Duration>,
+    ) -> Result<R, BluezError> {
+        let call = self.call(path, interface, method, arg, timeout);
+        self.traced(path, interface, method, None, call).await
     }
 
-    /// 指定のパス配下の子要素の一覧を取得
-    pub(in crate) async fn get_children(
+    /// 応答を待たずにメソッドを呼び出す
+    ///
+    /// `Drop`の中など`await`できない場所での後始末に使用する。
+    /// 送信できなかった場合(切断中など)は何もしない。
+    /// 独自の通信路の場合は`Transport::send`で送信する。
+    pub(in crate) fn call_no_reply(&self, path: &str, interface: &str, method: &str) {
+        let mut msg = match Message::new_method_call(&*self.service, path, interface, method) {
+            Ok(msg) => msg,
+            Err(_) => return,
+        };
+        #[cfg(feature = "tracing")]
+        tracing::debug!(path, interface, method, "sending without waiting for reply");
+        if let Some(transport) = &self.transport {
+            return transport.send(msg);
+        }
+        msg.set_no_reply(true);
+        if let Ok(conn) = self.shared.connection() {
+            let _ = conn.send(msg);
+        }
+    }
+
+    /// メソッドが存在しない場合は`BluezError::Unsupported`で失敗する
+    pub(in crate) async fn require_method(
+        &self,
+        interface: &str,
+        method: &str,
+    ) -> Result<(), BluezError> {
+        self.capabilities().await?.require_method(interface, method)
+    }
+
+    /// 計測を行いながら`call`を実行する
+    async fn traced<R>(
         &self,
         path: &str,
-        prop: &str,
-    ) -> Result<Option<Vec<String>>, BoxError> {
-        let objects = self.get_managed_objects().await?;
+        interface: &str,
+        member: &str,
+        property: Option<&str>,
+        call: impl Future<Output = Result<R, BluezError>>,
+    ) -> Result<R, BluezError> {
+        let traced = Call::start(self.metrics.clone(), path, interface, member, property);
+        let result = traced.instrument(call).await;
+        traced.finish(&result);
+        result
+    }
 
-        let devices: Vec<String> = objects
-            .iter()
-            .filter_map(|(key, value)| {
-                if is_match(path, prop, value) {
-                    Some(key.to_string())
-                } else {
-                    None
-                }
-            })
-            .collect();
-        if devices.is_empty() {
-            Ok(None)
-        } else {
-            Ok(Some(devices))
+    /// 計測を行わずにメソッドを実行する
+    async fn call<R: ReadAll + 'static, A: AppendAll>(
+        &self,
+        path: &str,
+        interface: &str,
+        method: &str,
+        arg: A,
+        timeout: Option<Duration>,
+    ) -> Result<R, BluezError> {
+        if let Some(transport) = &self.transport {
+            let mut msg = Message::new_method_call(&*self.service, path, interface, method)
+                .map_err(|e| BluezError::DBus(dbus::Error::new_failed(&e)))?;
+            msg.append_all(arg);
+            #[cfg(feature = "record")]
+            let id = self.recorder.as_ref().map(|recorder| recorder.call(&msg));
+            let reply = transport.call(msg, timeout.unwrap_or(self.timeout)).await;
+            #[cfg(feature = "record")]
+            if let (Some(recorder), Some(id)) = (&self.recorder, id) {
+                recorder.reply(id, &reply);
+            }
+            return Ok(reply?.read_all()?);
+        }
+        let conn = self.shared.connection()?;
+        #[cfg(feature = "record")]
+        if self.recorder.is_some() {
+            // 記録には応答のメッセージが必要なため、`Proxy`を使用せずに送信する
+            let mut msg = Message::new_method_call(&*self.service, path, interface, method)
+                .map_err(|e| BluezError::DBus(dbus::Error::new_failed(&e)))?;
+            msg.append_all(arg);
+            let reply = self.send_with_reply(&conn, msg, timeout, Ok).await?;
+            return Ok(reply.read_all()?);
+        }
+        let proxy = Proxy::new(&*self.service, path, timeout.unwrap_or(self.timeout), conn);
+        let call = proxy.method_call(interface, method, arg);
+        // 応答待ちの間に切断された場合は即座に失敗させる
+        let lost = self.shared.lost();
+        futures::pin_mut!(lost);
+        match future::select(call, lost).await {
+            Either::Left((result, _)) => Ok(result?),
+            Either::Right((err, _)) => Err(err),
         }
     }
 
-    pub(in crate) async fn get_managed_objects(&self) -> Result<ManagedObject, BoxError> {
+    /// 指定のパス配下の子要素のパスの一覧を取得
+    ///
+    /// パスの順に並べる。子要素が無い場合は空の`Vec`を返す。
+    pub(in crate) async fn get_children<P: std::str::FromStr>(
+        &self,
+        path: &str,
+        prop: &str,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<P>, BluezError> {
+        let paths = match self.cache(timeout).await? {
+            Some(cache) => cache.lock().unwrap().children(path, prop),
+            None => self
+                .get_managed_objects(timeout)
+                .await?
+                .iter()
+                .filter(|(_, value)| is_match(path, prop, value))
+                .map(|(key, _)| key.to_string())
+                .collect(),
+        };
+        Ok(parse_sorted(paths))
+    }
+
+    pub(in crate) async fn get_managed_objects(
+        &self,
+        timeout: Option<Duration>,
+    ) -> Result<ManagedObject, BluezError> {
+        if let Some(cache) = self.cache(timeout).await? {
+            let objects = cache.lock().unwrap().managed_objects();
+            return Ok(objects);
+        }
         let (managed_objects,): (ManagedObject,) = self
-            .method_call("/", MANAGED_OBJECT_INTERFACE, MANAGED_OBJECT_METHOD, ())
+            .method_call(
+                "/",
+                MANAGED_OBJECT_INTERFACE,
+                MANAGED_OBJECT_METHOD,
+                (),
+                timeout,
+            )
             .await?;
         Ok(managed_objects)
     }
+
+    /// 最新の状態のキャッシュを取得
+    ///
+    /// キャッシュを使用しない場合は`None`を返す。
+    async fn cache(
+        &self,
+        timeout: Option<Duration>,
+    ) -> Result<Option<Arc<Mutex<ObjectCache>>>, BluezError> {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return Ok(None),
+        };
+        let conn = self.shared.connection()?;
+        let mut subscription = cache.subscription.lock().await;
+        if !Weak::ptr_eq(&subscription.conn, &Arc::downgrade(&conn)) {
+            cache.objects.lock().unwrap().reset();
+            *subscription = Default::default();
+            let rules = cache.objects.lock().unwrap().match_rules();
+            for rule in rules {
+                let objects = cache.objects.clone();
+                let subscriber = self
+                    .add_match(&conn, rule, move |msg| objects.lock().unwrap().handle(msg))
+                    .await?;
+                subscription.subscribers.push(subscriber);
+            }
+            subscription.conn = Arc::downgrade(&conn);
+        }
+        if !cache.objects.lock().unwrap().is_seeded() {
+            self.seed(&conn, &cache.objects, timeout).await?;
+        }
+        Ok(Some(cache.objects.clone()))
+    }
+
+    /// シグナルを受信するルールを追加する
+    async fn add_match<F>(
+        &self,
+        conn: &Arc<Conn>,
+        rule: MatchRule<'static>,
+        f: F,
+    ) -> Result<Subscriber, BluezError>
+    where
+        F: FnMut(&Message) + Send + 'static,
+    {
+        self.shared.listen(conn);
+        let match_str = rule.match_str();
+        let token = self.shared.handlers().lock().unwrap().add(rule, f);
+        let subscriber = Subscriber::new(&self.shared, token, match_str.clone());
+        conn.add_match_no_cb(&match_str).await?;
+        Ok(subscriber)
+    }
+
+    /// `GetManagedObjects`の結果でキャッシュを初期化する
+    ///
+    /// 応答はシグナルと同じ順序で処理されるため、応答を受信した時点で初期化する。
+    async fn seed(
+        &self,
+        conn: &Arc<Conn>,
+        objects: &Arc<Mutex<ObjectCache>>,
+        timeout: Option<Duration>,
+    ) -> Result<(), BluezError> {
+        let msg = Message::new_method_call(
+            &*self.service,
+            "/",
+            MANAGED_OBJECT_INTERFACE,
+            MANAGED_OBJECT_METHOD,
+        )
+        .map_err(|e| BluezError::DBus(dbus::Error::new_failed(&e)))?;
+        let objects = objects.clone();
+        self.send_with_reply(conn, msg, timeout, move |reply| {
+            let managed_objects = reply.read1::<ManagedObject>()?;
+            let serial = reply.get_serial().unwrap_or(0);
+            objects.lock().unwrap().seed(serial, managed_objects);
+            Ok(())
+        })
+        .await
+    }
+
+    /// メソッド呼び出しのメッセージを送信し、応答を待つ
+    ///
+    /// `f`は応答を受信した時点で、受信処理の中で呼ばれる。
+    async fn send_with_reply<T, F>(
+        &self,
+        conn: &Arc<Conn>,
+        msg: Message,
+        timeout: Option<Duration>,
+        f: F,
+    ) -> Result<T, BluezError>
+    where
+        T: Send + 'static,
+        F: FnOnce(Message) -> Result<T, BluezError> + Send + 'static,
+    {
+        let member = msg.member().map(|m| m.to_string()).unwrap_or_default();
+        #[cfg(feature = "record")]
+        let record = self
+            .recorder
+            .as_ref()
+            .map(|recorder| (recorder.clone(), recorder.call(&msg)));
+        #[cfg(feature = "record")]
+        let on_reply = record.clone();
+        let (tx, rx) = oneshot::channel();
+        let token = conn
+            .send_with_reply(
+                msg,
+                Conn::make_f(move |mut reply: Message, _: &Conn| {
+                    let reply = reply
+                        .as_result()
+                        .map(|_| ())
+                        .map_err(BluezError::from)
+                        .map(|()| reply);
+                    #[cfg(feature = "record")]
+                    if let Some((recorder, id)) = on_reply {
+                        recorder.reply(id, &reply);
+                    }
+                    let _ = tx.send(reply.and_then(f));
+                }),
+            )
+            .map_err(|()| BluezError::ConnectionLost("failed to send message".to_string()))?;
+
+        let delay = Delay::new(timeout.unwrap_or(self.timeout));
+        let lost = self.shared.lost();
+        futures::pin_mut!(lost);
+        let err = match future::select(rx, future::select(delay, lost)).await {
+            Either::Left((result, _)) => {
+                return result.unwrap_or_else(|_| {
+                    Err(BluezError::ConnectionLost("reply dropped".to_string()))
+                })
+            }
+            Either::Right((Either::Left(_), _)) => {
+                conn.cancel_reply(token);
+                BluezError::Timeout(member)
+            }
+            Either::Right((Either::Right((err, _)), _)) => err,
+        };
+        #[cfg(feature = "record")]
+        if let Some((recorder, id)) = record {
+            recorder.error(id, &err);
+        }
+        Err(err)
+    }
 }
 
 fn is_match(path: &str, prop: &str, info: &ManagedObjectInterfaces) -> bool {
     info.iter().any(|(_key, value)| {
-        if let Some(s) = value.get_str(prop) {
-            return s == path;
+        if let Some(s) = value.get_as::<dbus::Path>(prop) {
+            return &*s == path;
         }
         false
     })
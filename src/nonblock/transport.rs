@@ -0,0 +1,42 @@
+use crate::*;
+use dbus::Message;
+use futures::future::BoxFuture;
+use std::time::Duration;
+
+/// BlueZとの通信路
+///
+/// 独自の通信路を指定した`Session`は、メソッド呼び出し(`method_call`、`get_property`、
+/// `set_property`、`get_managed_objects`)をD-Busのコネクションの代わりにこのトレイトを通して行う。
+/// 応答を待たない後始末の送信は`send`で行う。
+/// `FakeBluez`を指定するとD-Busなしでテストできる。
+///
+/// ```
+/// use bluez_dbus::nonblock::{Adapter, Session};
+/// use bluez_dbus::FakeBluez;
+/// use std::sync::Arc;
+///
+/// # async fn run() -> Result<(), bluez_dbus::BluezError> {
+/// let fake = FakeBluez::new();
+/// fake.set_property("/org/bluez/hci0", "org.bluez.Adapter1", "Powered", true);
+/// let s = Session::builder().transport(Arc::new(fake.clone())).build()?;
+/// let adapter = Adapter::create(&s, &"/org/bluez/hci0".parse()?).await?.unwrap();
+/// assert!(adapter.is_powered().await?);
+/// # Ok(())
+/// # }
+/// ```
+pub trait Transport: Send + Sync {
+    /// メソッド呼び出しのメッセージを送信し、応答を返す
+    ///
+    /// エラーの応答は`Err`で返す。
+    fn call(
+        &self,
+        msg: Message,
+        timeout: Duration,
+    ) -> BoxFuture<'static, Result<Message, BluezError>>;
+
+    /// メソッド呼び出しのメッセージを応答を待たずに送信する
+    ///
+    /// `Drop`の中など`await`できない場所での後始末(`CancelPairing`や`Disconnect`)に使用する。
+    /// 呼び出し元をブロックせずに送信を済ませるか、送信を開始すること。結果は無視してよい。
+    fn send(&self, msg: Message);
+}
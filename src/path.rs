@@ -0,0 +1,260 @@
+use crate::*;
+use dbus::arg::{Append, Arg, ArgType, Get, Iter, IterAppend};
+use dbus::strings::Signature;
+use std::convert::TryFrom;
+use std::fmt;
+use std::ops::Deref;
+use std::str::FromStr;
+
+/// BlueZのオブジェクトパスの先頭
+static BLUEZ_PATH: &str = "/org/bluez";
+
+/// アダプターより下の階層の要素の接頭辞(デバイス、サービス、キャラクタリスティック、ディスクリプターの順)
+const ELEMENT_PREFIXES: [&str; 4] = ["dev_", "service", "char", "desc"];
+
+/// オブジェクトパスの型を作成するマクロ
+///
+/// `$depth`は`/org/bluez`より下の要素の数。
+macro_rules! object_path {
+    ($(#[$meta: meta])* $name: ident, $depth: expr, $kind: expr) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        #[cfg_attr(
+            feature = "serde",
+            derive(serde::Serialize, serde::Deserialize),
+            serde(try_from = "String", into = "String")
+        )]
+        pub struct $name(String);
+
+        impl $name {
+            /// 形式を確認して作成する
+            ///
+            /// 形式が正しくない場合は`BluezError::InvalidArguments`を返す。
+            pub fn new(path: &str) -> Result<Self, BluezError> {
+                if is_valid(path, $depth) {
+                    Ok($name(path.to_string()))
+                } else {
+                    Err(BluezError::InvalidArguments(format!(
+                        "invalid {} path: {}",
+                        $kind, path
+                    )))
+                }
+            }
+
+            pub fn as_str(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl Deref for $name {
+            type Target = str;
+
+            fn deref(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl AsRef<str> for $name {
+            fn as_ref(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(&self.0)
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = BluezError;
+
+            fn from_str(path: &str) -> Result<Self, BluezError> {
+                $name::new(path)
+            }
+        }
+
+        impl TryFrom<&str> for $name {
+            type Error = BluezError;
+
+            fn try_from(path: &str) -> Result<Self, BluezError> {
+                $name::new(path)
+            }
+        }
+
+        impl TryFrom<String> for $name {
+            type Error = BluezError;
+
+            fn try_from(path: String) -> Result<Self, BluezError> {
+                $name::new(&path)
+            }
+        }
+
+        impl From<$name> for String {
+            fn from(path: $name) -> String {
+                path.0
+            }
+        }
+
+        impl From<$name> for dbus::Path<'static> {
+            fn from(path: $name) -> dbus::Path<'static> {
+                dbus::Path::from(path.0)
+            }
+        }
+
+        impl Arg for $name {
+            const ARG_TYPE: ArgType = ArgType::ObjectPath;
+
+            fn signature() -> Signature<'static> {
+                <dbus::Path as Arg>::signature()
+            }
+        }
+
+        impl Append for $name {
+            fn append_by_ref(&self, i: &mut IterAppend) {
+                dbus::Path::from(self.0.as_str()).append_by_ref(i)
+            }
+        }
+
+        impl<'a> Get<'a> for $name {
+            fn get(i: &mut Iter<'a>) -> Option<Self> {
+                let path: dbus::Path = i.get()?;
+                $name::new(&path).ok()
+            }
+        }
+    };
+}
+
+object_path!(
+    /// アダプターのオブジェクトパス(例: `/org/bluez/hci0`)
+    AdapterPath, 1, "adapter"
+);
+
+object_path!(
+    /// デバイスのオブジェクトパス(例: `/org/bluez/hci0/dev_00_11_22_33_44_55`)
+    DevicePath, 2, "device"
+);
+
+object_path!(
+    /// GATTサービスのオブジェクトパス(例: `/org/bluez/hci0/dev_00_11_22_33_44_55/service0001`)
+    ServicePath, 3, "service"
+);
+
+object_path!(
+    /// キャラクタリスティックのオブジェクトパス(例: `.../service0001/char0002`)
+    CharacteristicPath, 4, "characteristic"
+);
+
+object_path!(
+    /// ディスクリプターのオブジェクトパス(例: `.../service0001/char0002/desc0003`)
+    DescriptorPath, 5, "descriptor"
+);
+
+impl DevicePath {
+    /// デバイスが属するアダプターのパス
+    pub fn adapter(&self) -> AdapterPath {
+        AdapterPath(parent(&self.0))
+    }
+}
+
+impl ServicePath {
+    /// GATTサービスが属するデバイスのパス
+    pub fn device(&self) -> DevicePath {
+        DevicePath(parent(&self.0))
+    }
+}
+
+impl CharacteristicPath {
+    /// キャラクタリスティックが属するGATTサービスのパス
+    pub fn service(&self) -> ServicePath {
+        ServicePath(parent(&self.0))
+    }
+}
+
+impl DescriptorPath {
+    /// ディスクリプターが属するキャラクタリスティックのパス
+    pub fn characteristic(&self) -> CharacteristicPath {
+        CharacteristicPath(parent(&self.0))
+    }
+}
+
+/// `/org/bluez`より下に`depth`個の要素を持ち、各要素が階層に応じた接頭辞を持つかどうか
+fn is_valid(path: &str, depth: usize) -> bool {
+    let rest = match path.strip_prefix(BLUEZ_PATH) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let elements: Vec<&str> = match rest.strip_prefix('/') {
+        Some(rest) => rest.split('/').collect(),
+        None => return false,
+    };
+    elements.len() == depth
+        && elements.iter().enumerate().all(|(i, element)| {
+            let valid = !element.is_empty()
+                && element
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_');
+            valid && (i == 0 || element.starts_with(ELEMENT_PREFIXES[i - 1]))
+        })
+}
+
+/// パスの一覧を型付きのパスに変換し、パスの順に並べる
+///
+/// `hci2`が`hci10`より前になるよう、短いパスを先にする。形式に一致しないパスは除く。
+pub(in crate) fn parse_sorted<P: FromStr>(mut paths: Vec<String>) -> Vec<P> {
+    paths.sort_by(|a, b| a.len().cmp(&b.len()).then_with(|| a.cmp(b)));
+    paths.iter().filter_map(|path| path.parse().ok()).collect()
+}
+
+/// 最後の要素を除いたパス
+fn parent(path: &str) -> String {
+    path[..path.rfind('/').unwrap_or(0)].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_shape() {
+        assert!(AdapterPath::new("/org/bluez/hci0").is_ok());
+        assert!(AdapterPath::new("/org/bluez/hci0/dev_00").is_err());
+        assert!(AdapterPath::new("/org/bluez").is_err());
+        assert!(DevicePath::new("/org/bluez/hci0/dev_00_11_22_33_44_55").is_ok());
+        assert!(DevicePath::new("/org/bluez/hci0/service0001").is_err());
+        assert!(DevicePath::new("/org/bluez/hci0/dev_00/").is_err());
+        assert!(CharacteristicPath::new("/org/bluez/hci0/dev_00/service0001").is_err());
+        assert!(
+            DescriptorPath::new("/org/bluez/hci0/dev_00/service0001/char0002/desc0003").is_ok()
+        );
+        assert!("/org/bluez/hci0/dev_00/service0001/char0002"
+            .parse::<DevicePath>()
+            .is_err());
+    }
+
+    #[test]
+    fn navigate_to_parent() {
+        let descriptor =
+            DescriptorPath::new("/org/bluez/hci0/dev_00/service0001/char0002/desc0003").unwrap();
+        let characteristic = descriptor.characteristic();
+        assert_eq!(
+            characteristic.as_str(),
+            "/org/bluez/hci0/dev_00/service0001/char0002"
+        );
+        let device = characteristic.service().device();
+        assert_eq!(device.as_str(), "/org/bluez/hci0/dev_00");
+        assert_eq!(device.adapter().as_str(), "/org/bluez/hci0");
+    }
+
+    #[test]
+    fn sort_parsed_paths() {
+        let paths = vec![
+            "/org/bluez/hci10".to_string(),
+            "/org/bluez/hci2/dev_00".to_string(),
+            "/org/bluez/hci2".to_string(),
+        ];
+        let adapters: Vec<AdapterPath> = parse_sorted(paths);
+        let adapters: Vec<&str> = adapters.iter().map(|path| path.as_str()).collect();
+        assert_eq!(adapters, vec!["/org/bluez/hci2", "/org/bluez/hci10"]);
+    }
+}
@@ -0,0 +1,161 @@
+use crate::value::Value;
+use crate::{BluezError, FromRefArg};
+use dbus::arg::{RefArg, Variant};
+use std::collections::HashMap;
+use std::fmt;
+
+/// オブジェクトのプロパティ
+///
+/// インターフェース一つ分のプロパティを保持する。
+/// 値は所有する形式に変換して保持するため、スレッド間で受け渡すことができる。
+#[derive(Default, Clone)]
+pub struct Properties(HashMap<String, Value>);
+
+impl Properties {
+    /// プロパティの値を取得
+    pub fn get(&self, name: &str) -> Option<&(dyn RefArg + 'static)> {
+        self.0
+            .get(name)
+            .map(|value| value as &(dyn RefArg + 'static))
+    }
+
+    /// 文字列(オブジェクトパスを含む)のプロパティを取得
+    pub fn get_str(&self, name: &str) -> Option<&str> {
+        self.get(name).and_then(|value| value.as_str())
+    }
+
+    /// 型を指定してプロパティを取得
+    ///
+    /// 存在しない場合は`Ok(None)`、型が一致しない場合は`BluezError::TypeMismatch`を返す。
+    pub fn decode<T: FromRefArg>(&self, name: &str) -> Result<Option<T>, BluezError> {
+        self.get(name)
+            .map(|value| {
+                T::from_refarg(value).map_err(|e| BluezError::TypeMismatch(e.describe(name)))
+            })
+            .transpose()
+    }
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.0.contains_key(name)
+    }
+
+    /// プロパティ名の一覧
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.0.keys().map(|name| name.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// 変更されたプロパティを反映する
+    ///
+    /// 変換できない値のプロパティは削除する。
+    pub(in crate) fn update(
+        &mut self,
+        changed: HashMap<String, Variant<Box<dyn RefArg>>>,
+        invalidated: &[String],
+    ) {
+        for (name, value) in changed {
+            match Value::from_refarg(&*value.0) {
+                Ok(value) => self.0.insert(name, value),
+                Err(_) => self.0.remove(&name),
+            };
+        }
+        for name in invalidated {
+            self.0.remove(name);
+        }
+    }
+
+    /// D-Busのメッセージと同じ形式に変換する
+    pub(in crate) fn to_variants(&self) -> HashMap<String, Variant<Box<dyn RefArg>>> {
+        self.0
+            .iter()
+            .map(|(name, value)| (name.clone(), Variant(value.box_clone())))
+            .collect()
+    }
+}
+
+/// 値を所有する形式に変換する
+///
+/// 変換できない値(ファイルディスクリプタなど、BlueZのプロパティには含まれないもの)は除く。
+impl From<HashMap<String, Variant<Box<dyn RefArg>>>> for Properties {
+    fn from(props: HashMap<String, Variant<Box<dyn RefArg>>>) -> Self {
+        Properties(
+            props
+                .into_iter()
+                .filter_map(|(name, value)| Some((name, Value::from_refarg(&*value.0).ok()?)))
+                .collect(),
+        )
+    }
+}
+
+impl fmt::Debug for Properties {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.0.iter()).finish()
+    }
+}
+
+/// プロパティ名と、型ごとに区別した値の組の辞書としてシリアライズする
+#[cfg(feature = "serde")]
+impl serde::Serialize for Properties {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        let mut names: Vec<&String> = self.0.keys().collect();
+        names.sort();
+        for name in names {
+            map.serialize_entry(name, &self.0[name])?;
+        }
+        map.end()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Properties {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        serde::Deserialize::deserialize(deserializer).map(Properties)
+    }
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serde_round_trip() {
+        let mut manufacturer: HashMap<u16, Variant<Box<dyn RefArg>>> = HashMap::new();
+        manufacturer.insert(0x004c, Variant(Box::new(vec![0x02u8, 0x15])));
+        let mut props: HashMap<String, Variant<Box<dyn RefArg>>> = HashMap::new();
+        props.insert("RSSI".to_string(), Variant(Box::new(-60i16)));
+        props.insert("UUIDs".to_string(), Variant(Box::new(Vec::<String>::new())));
+        props.insert(
+            "Adapter".to_string(),
+            Variant(Box::new(dbus::Path::from("/org/bluez/hci0"))),
+        );
+        props.insert(
+            "ManufacturerData".to_string(),
+            Variant(Box::new(manufacturer)),
+        );
+        let props = Properties::from(props);
+
+        let json = serde_json::to_string(&props).unwrap();
+        let loaded: Properties = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::to_string(&loaded).unwrap(), json);
+        assert_eq!(loaded.get("RSSI").and_then(|v| v.as_i64()), Some(-60));
+        assert_eq!(loaded.get_str("Adapter"), Some("/org/bluez/hci0"));
+        for name in props.keys() {
+            assert_eq!(
+                loaded.get(name).map(|v| v.signature()),
+                props.get(name).map(|v| v.signature())
+            );
+        }
+
+        // 要素の型が一致しない配列は読み込まない
+        let invalid = r#"{"UUIDs":{"Array":{"signature":"s","items":[{"Byte":1}]}}}"#;
+        assert!(serde_json::from_str::<Properties>(invalid).is_err());
+    }
+}
@@ -0,0 +1,402 @@
+use crate::*;
+use dbus::arg::{ArgType, Iter, RefArg, Variant};
+use dbus::message::MatchRule;
+use dbus::Message;
+use std::collections::HashMap;
+
+/// プロパティの変更の型
+pub(in crate) trait PropertyChange: Sized + Send + 'static {
+    /// 対象のインターフェース
+    const INTERFACE: &'static str;
+
+    /// 変更されたプロパティの値を読み込む
+    fn decode(name: &str, value: &mut Iter) -> Option<Self>;
+
+    /// 値が送られずに無効化されたプロパティ
+    fn invalidated(name: String) -> Self;
+
+    /// 指定のオブジェクトの変更を受信するルール
+    fn match_rule(service: &str, path: &str) -> MatchRule<'static> {
+        let mut rule = signal(service, PROPERTIES_INTERFACE, "PropertiesChanged");
+        rule.path = Some(path.to_string().into());
+        rule
+    }
+
+    /// 受信した`PropertiesChanged`のシグナルから変更の一覧を作成する
+    ///
+    /// 対象外のインターフェースのシグナルの場合は空の一覧を返す。
+    fn from_message(msg: &Message) -> Vec<Self> {
+        let mut iter = msg.iter_init();
+        match iter.read::<&str>() {
+            Ok(interface) if interface == Self::INTERFACE => {}
+            _ => return vec![],
+        }
+        let mut changes = vec![];
+        if let Some(mut dict) = iter.recurse(ArgType::Array) {
+            while dict.arg_type() == ArgType::DictEntry {
+                if let Some(mut entry) = dict.recurse(ArgType::DictEntry) {
+                    if let Ok(name) = entry.read::<&str>() {
+                        if let Some(change) = entry
+                            .recurse(ArgType::Variant)
+                            .and_then(|mut value| Self::decode(name, &mut value))
+                        {
+                            changes.push(change);
+                        }
+                    }
+                }
+                dict.next();
+            }
+        }
+        iter.next();
+        if let Ok(invalidated) = iter.read::<Vec<String>>() {
+            changes.extend(invalidated.into_iter().map(Self::invalidated));
+        }
+        changes
+    }
+}
+
+/// プロパティの変更の型を作成するマクロ
+macro_rules! property_change {
+    ($(#[$meta: meta])* $name: ident, $interface: expr, { $($variant: ident($t: ty) = $prop: expr,)* }) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, PartialEq)]
+        #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+        pub enum $name {
+            $($variant($t),)*
+            /// 無効化されたプロパティ(値は送られないため、必要な場合は取得し直す)
+            Invalidated(String),
+            /// 型が定義されていないプロパティ
+            Other(String),
+            /// 値の型が定義と一致しなかったプロパティ
+            Mismatch(String, DecodeError),
+        }
+
+        impl PropertyChange for $name {
+            const INTERFACE: &'static str = $interface;
+
+            fn decode(name: &str, value: &mut Iter) -> Option<Self> {
+                match name {
+                    $($prop => Some(match value.get_refarg().map(|value| FromRefArg::from_refarg(&*value)) {
+                        Some(Ok(value)) => $name::$variant(value),
+                        Some(Err(e)) => $name::Mismatch(name.to_string(), e),
+                        None => $name::Other(name.to_string()),
+                    }),)*
+                    _ => Some($name::Other(name.to_string())),
+                }
+            }
+
+            fn invalidated(name: String) -> Self {
+                $name::Invalidated(name)
+            }
+        }
+    };
+}
+
+org_bluez_adapter1!(changes: property_change,
+    /// アダプターのプロパティの変更
+    AdapterProperty
+);
+
+org_bluez_device1!(changes: property_change,
+    /// デバイスのプロパティの変更
+    DeviceProperty
+);
+
+org_bluez_gattservice1!(changes: property_change,
+    /// GATTサービスのプロパティの変更
+    GattServiceProperty
+);
+
+org_bluez_gattcharacteristic1!(changes: property_change,
+    /// キャラクタリスティックのプロパティの変更
+    CharacteristicProperty
+);
+
+org_bluez_gattdescriptor1!(changes: property_change,
+    /// ディスクリプターのプロパティの変更
+    DescriptorProperty
+);
+
+/// プロパティの一覧の型
+pub(in crate) trait PropertyInfo: Sized {
+    /// 対象のインターフェース
+    const INTERFACE: &'static str;
+
+    fn from_properties(properties: &Properties) -> Result<Self, BluezError>;
+}
+
+/// プロパティを読み込む
+///
+/// 存在しない場合は`Ok(None)`、型が一致しない場合は`BluezError::TypeMismatch`を返す。
+fn read_property<T: FromRefArg>(
+    value: Option<&dyn RefArg>,
+    interface: &str,
+    name: &str,
+) -> Result<Option<T>, BluezError> {
+    let value = match value {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    T::from_refarg(value)
+        .map(Some)
+        .map_err(|e| BluezError::TypeMismatch(e.describe(&format!("{}.{}", interface, name))))
+}
+
+/// 必須のプロパティを読み込む
+///
+/// 存在しない場合も`BluezError::TypeMismatch`を返す。
+fn require_property<T: FromRefArg>(
+    value: Option<&dyn RefArg>,
+    interface: &str,
+    name: &str,
+) -> Result<T, BluezError> {
+    read_property(value, interface, name)?.ok_or_else(|| {
+        BluezError::TypeMismatch(format!("{}.{}: missing property", interface, name))
+    })
+}
+
+/// プロパティの一覧の型を作成するマクロ
+///
+/// 一つ目の`{}`は必須のプロパティ、二つ目の`{}`はBlueZが省略することのあるプロパティ(`Option`になる)。
+macro_rules! property_info {
+    ($(#[$meta: meta])* $name: ident, $interface: expr,
+        { $($(#[$field_meta: meta])* $field: ident: $t: ty = $prop: expr,)* },
+        { $($(#[$opt_meta: meta])* $opt_field: ident: $opt_t: ty = $opt_prop: expr,)* }) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, PartialEq)]
+        #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+        pub struct $name {
+            $($(#[$field_meta])* pub $field: $t,)*
+            $($(#[$opt_meta])* pub $opt_field: Option<$opt_t>,)*
+        }
+
+        impl $name {
+            /// プロパティの一覧(イベントの`properties`など)から作成する
+            ///
+            /// 必須のプロパティが無いか、型が一致しない場合は`BluezError::TypeMismatch`を返す。
+            pub fn from_properties(properties: &Properties) -> Result<Self, BluezError> {
+                $name::read(|name| properties.get(name))
+            }
+
+            /// `GetManagedObjects`の応答の、一つのオブジェクトのインターフェースの一覧から作成する
+            ///
+            /// 対象のインターフェースを持たない場合は`Ok(None)`を返す。
+            pub fn from_interfaces(
+                interfaces: &HashMap<String, HashMap<String, Variant<Box<dyn RefArg>>>>,
+            ) -> Result<Option<Self>, BluezError> {
+                interfaces
+                    .get($interface)
+                    .map(|properties| {
+                        $name::read(|name| properties.get(name).map(|value| &*value.0))
+                    })
+                    .transpose()
+            }
+
+            fn read<'a, F>(get: F) -> Result<Self, BluezError>
+            where
+                F: Fn(&str) -> Option<&'a (dyn RefArg + 'static)>,
+            {
+                Ok($name {
+                    $($field: require_property(get($prop), $interface, $prop)?,)*
+                    $($opt_field: read_property(get($opt_prop), $interface, $opt_prop)?,)*
+                })
+            }
+        }
+
+        impl PropertyInfo for $name {
+            const INTERFACE: &'static str = $interface;
+
+            fn from_properties(properties: &Properties) -> Result<Self, BluezError> {
+                $name::from_properties(properties)
+            }
+        }
+    };
+}
+
+org_bluez_adapter1!(info: property_info,
+    /// アダプターのプロパティの一覧
+    AdapterInfo
+);
+
+org_bluez_device1!(info: property_info,
+    /// デバイスのプロパティの一覧
+    DeviceInfo
+);
+
+org_bluez_gattservice1!(info: property_info,
+    /// GATTサービスのプロパティの一覧
+    GattServiceInfo
+);
+
+org_bluez_gattcharacteristic1!(info: property_info,
+    /// キャラクタリスティックのプロパティの一覧
+    CharacteristicInfo
+);
+
+org_bluez_gattdescriptor1!(info: property_info,
+    /// ディスクリプターのプロパティの一覧
+    DescriptorInfo
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dbus::Path;
+
+    fn properties_changed(interface: &str, invalidated: Vec<String>) -> Message {
+        let mut changed: HashMap<String, Variant<Box<dyn RefArg>>> = HashMap::new();
+        changed.insert("RSSI".to_string(), Variant(Box::new(-42i16)));
+        changed.insert("Value".to_string(), Variant(Box::new(vec![1u8, 2, 3])));
+        changed.insert("Unknown".to_string(), Variant(Box::new(0u8)));
+        changed.insert("TxPower".to_string(), Variant(Box::new(4u16)));
+        Message::signal(
+            &"/org/bluez/hci0/dev_00".into(),
+            &PROPERTIES_INTERFACE.into(),
+            &"PropertiesChanged".into(),
+        )
+        .append3(interface, changed, invalidated)
+    }
+
+    #[test]
+    fn decode_device_property() {
+        let msg = properties_changed("org.bluez.Device1", vec!["TxPower".to_string()]);
+        let mut changes = DeviceProperty::from_message(&msg);
+        changes.sort_by_key(|change| format!("{:?}", change));
+        assert_eq!(
+            changes,
+            vec![
+                DeviceProperty::Invalidated("TxPower".to_string()),
+                DeviceProperty::Mismatch(
+                    "TxPower".to_string(),
+                    DecodeError {
+                        at: String::new(),
+                        expected: "n".to_string(),
+                        found: "q".to_string(),
+                    }
+                ),
+                DeviceProperty::Other("Unknown".to_string()),
+                DeviceProperty::Other("Value".to_string()),
+                DeviceProperty::Rssi(-42),
+            ]
+        );
+    }
+
+    #[test]
+    fn ignore_other_interface() {
+        let msg = properties_changed("org.bluez.GattCharacteristic1", vec![]);
+        assert!(DeviceProperty::from_message(&msg).is_empty());
+        let changes = CharacteristicProperty::from_message(&msg);
+        assert!(changes.contains(&CharacteristicProperty::Value(vec![1, 2, 3])));
+    }
+
+    #[test]
+    fn decode_generated_property() {
+        let mut changed: HashMap<String, Variant<Box<dyn RefArg>>> = HashMap::new();
+        changed.insert(
+            "Flags".to_string(),
+            Variant(Box::new(vec!["read".to_string(), "notify".to_string()])),
+        );
+        let msg = Message::signal(
+            &"/org/bluez/hci0/dev_00/service0001/char0002".into(),
+            &PROPERTIES_INTERFACE.into(),
+            &"PropertiesChanged".into(),
+        )
+        .append3(
+            "org.bluez.GattCharacteristic1",
+            changed,
+            Vec::<String>::new(),
+        );
+        assert_eq!(
+            CharacteristicProperty::from_message(&msg),
+            vec![CharacteristicProperty::Flags(vec![
+                "read".to_string(),
+                "notify".to_string()
+            ])]
+        );
+    }
+
+    #[test]
+    fn decode_dict_property() {
+        let mut manufacturer_data: HashMap<u16, Variant<Box<dyn RefArg>>> = HashMap::new();
+        manufacturer_data.insert(0x004c, Variant(Box::new(vec![0x02u8, 0x15])));
+        let mut changed: HashMap<String, Variant<Box<dyn RefArg>>> = HashMap::new();
+        changed.insert(
+            "ManufacturerData".to_string(),
+            Variant(Box::new(manufacturer_data)),
+        );
+        let msg = Message::signal(
+            &"/org/bluez/hci0/dev_00".into(),
+            &PROPERTIES_INTERFACE.into(),
+            &"PropertiesChanged".into(),
+        )
+        .append3("org.bluez.Device1", changed, Vec::<String>::new());
+        let mut expected = HashMap::new();
+        expected.insert(0x004c, vec![0x02, 0x15]);
+        assert_eq!(
+            DeviceProperty::from_message(&msg),
+            vec![DeviceProperty::ManufacturerData(expected)]
+        );
+
+        let mut service_data: HashMap<String, Variant<Box<dyn RefArg>>> = HashMap::new();
+        service_data.insert("feaa".to_string(), Variant(Box::new(1u32)));
+        let mut properties: HashMap<String, Variant<Box<dyn RefArg>>> = HashMap::new();
+        properties.insert("ServiceData".to_string(), Variant(Box::new(service_data)));
+        let properties = Properties::from(properties);
+        match properties.decode::<HashMap<String, Vec<u8>>>("ServiceData") {
+            Err(BluezError::TypeMismatch(message)) => {
+                assert_eq!(message, "ServiceData[\"feaa\"]: expected ay, found u")
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(properties.decode::<String>("Alias").unwrap(), None);
+    }
+
+    #[test]
+    fn read_info_from_interfaces() {
+        let mut properties: HashMap<String, Variant<Box<dyn RefArg>>> = HashMap::new();
+        properties.insert(
+            "UUID".to_string(),
+            Variant(Box::new("00002902-0000-1000-8000-00805f9b34fb".to_string())),
+        );
+        properties.insert(
+            "Characteristic".to_string(),
+            Variant(Box::new(Path::from(
+                "/org/bluez/hci0/dev_00/service0001/char0002",
+            ))),
+        );
+        properties.insert(
+            "Flags".to_string(),
+            Variant(Box::new(vec!["read".to_string()])),
+        );
+        let mut interfaces = HashMap::new();
+        interfaces.insert("org.bluez.GattDescriptor1".to_string(), properties);
+
+        let info = DescriptorInfo::from_interfaces(&interfaces)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            info.characteristic.as_str(),
+            "/org/bluez/hci0/dev_00/service0001/char0002"
+        );
+        assert_eq!(info.flags, vec!["read".to_string()]);
+        assert_eq!(info.value, None);
+        assert_eq!(DeviceInfo::from_interfaces(&interfaces).unwrap(), None);
+
+        let properties = interfaces.get_mut("org.bluez.GattDescriptor1").unwrap();
+        properties.insert("Value".to_string(), Variant(Box::new(1u32)));
+        match DescriptorInfo::from_interfaces(&interfaces) {
+            Err(BluezError::TypeMismatch(message)) => assert_eq!(
+                message,
+                "org.bluez.GattDescriptor1.Value: expected ay, found u"
+            ),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        let properties = interfaces.get_mut("org.bluez.GattDescriptor1").unwrap();
+        properties.remove("Value");
+        properties.remove("Flags");
+        assert!(matches!(
+            DescriptorInfo::from_interfaces(&interfaces),
+            Err(BluezError::TypeMismatch(_))
+        ));
+    }
+}
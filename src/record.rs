@@ -0,0 +1,541 @@
+use crate::*;
+use dbus::arg::messageitem::{MessageItem, MessageItemArray, MessageItemDict};
+use dbus::strings::Signature;
+use dbus::Message;
+use futures::future::{self, BoxFuture};
+use serde_json::{json, Map, Value};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// BlueZとのD-Busの通信の記録
+///
+/// `SessionBuilder::record`に指定すると、セッションのメソッド呼び出し、応答、エラー、
+/// 受信したシグナルを、時刻(UNIX時間の秒)とともに1行に1件のJSONで書き出す。
+/// 記録したファイルは`Replay`で再生できる。
+///
+/// ```text
+/// {"args":[],"id":1,"interface":"org.bluez.Adapter1","member":"StartDiscovery","path":"/org/bluez/hci0","time":1700000000.1,"type":"call"}
+/// {"id":1,"message":"Operation already in progress","name":"org.bluez.Error.InProgress","time":1700000000.2,"type":"error"}
+/// {"args":[["s","org.bluez.Adapter1"],["a{sv}",[[["s","Discovering"],["v",["b",true]]]]],["as",[]]],"interface":"org.freedesktop.DBus.Properties","member":"PropertiesChanged","path":"/org/bluez/hci0","sender":":1.2","time":1700000000.3,"type":"signal"}
+/// ```
+///
+/// 引数は型を保つため`["<シグネチャ>", 値]`の形で記録する(`ay`は16進数の文字列)。
+/// 書き出しに失敗してもセッションの動作には影響しない。
+///
+/// ```no_run
+/// use bluez_dbus::blocking::{Adapter, Session};
+/// use bluez_dbus::Recorder;
+///
+/// let s = Session::builder()
+///     .record(Recorder::create("/tmp/bluez.jsonl")?)
+///     .build()?;
+/// let adapter = Adapter::create(&s, &"/org/bluez/hci0".parse()?)?.unwrap();
+/// adapter.start_discovery()?;
+/// # Ok::<(), bluez_dbus::BluezError>(())
+/// ```
+pub struct Recorder {
+    out: Mutex<Box<dyn Write + Send>>,
+    next: AtomicU64,
+}
+
+impl Recorder {
+    /// 指定のファイルに書き出す(既に存在する場合は上書きする)
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, BluezError> {
+        let file = File::create(path).map_err(|e| BluezError::Runtime(e.to_string()))?;
+        Ok(Recorder::new(file))
+    }
+
+    /// 指定の書き出し先に書き出す
+    pub fn new<W: Write + Send + 'static>(out: W) -> Self {
+        Recorder {
+            out: Mutex::new(Box::new(out)),
+            next: AtomicU64::new(1),
+        }
+    }
+
+    /// メソッド呼び出しを記録し、応答の記録に使用する番号を返す
+    pub(in crate) fn call(&self, msg: &Message) -> u64 {
+        let id = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut record = header(msg);
+        record.insert("type".to_string(), json!("call"));
+        record.insert("id".to_string(), json!(id));
+        self.write(record);
+        id
+    }
+
+    /// メソッド呼び出しの応答かエラーを記録する
+    pub(in crate) fn reply(&self, id: u64, result: &Result<Message, BluezError>) {
+        let reply = match result {
+            Ok(reply) => reply,
+            Err(err) => return self.error(id, err),
+        };
+        let mut record = Map::new();
+        record.insert("type".to_string(), json!("return"));
+        record.insert("id".to_string(), json!(id));
+        record.insert("args".to_string(), encode_args(reply));
+        self.write(record);
+    }
+
+    /// メソッド呼び出しのエラー(タイムアウトや切断を含む)を記録する
+    pub(in crate) fn error(&self, id: u64, err: &BluezError) {
+        let mut record = Map::new();
+        record.insert("type".to_string(), json!("error"));
+        record.insert("id".to_string(), json!(id));
+        record.insert("name".to_string(), json!(err.dbus_name()));
+        record.insert("message".to_string(), json!(err.message()));
+        self.write(record);
+    }
+
+    /// 受信したシグナルを記録する
+    pub(in crate) fn signal(&self, msg: &Message) {
+        let mut record = header(msg);
+        record.insert("type".to_string(), json!("signal"));
+        record.insert(
+            "sender".to_string(),
+            json!(msg.sender().map(|sender| sender.to_string())),
+        );
+        self.write(record);
+    }
+
+    fn write(&self, mut record: Map<String, Value>) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        record.insert("time".to_string(), json!(time));
+        let mut line = Value::Object(record).to_string();
+        line.push('\n');
+        let mut out = self.out.lock().unwrap();
+        let _ = out.write_all(line.as_bytes()).and_then(|()| out.flush());
+    }
+}
+
+/// `Recorder`で記録した通信の再生
+///
+/// `blocking::Transport`、`nonblock::Transport`を実装しているため、
+/// `SessionBuilder::transport`に指定すると、記録した応答をそのまま返すセッションになる。
+/// 実機で記録した応答の並びを、BlueZなしで決まった通りに再現できる。
+///
+/// メソッド呼び出しには、オブジェクトのパス、インターフェース、メソッド名、引数が一致する
+/// 記録のうち、まだ使用していない最初のものの応答を返す。
+/// 一致する記録がない場合は`BluezError::DBus`で失敗する。
+///
+/// ```no_run
+/// use bluez_dbus::blocking::{Adapter, Session};
+/// use bluez_dbus::{BluezError, Replay};
+/// use std::sync::Arc;
+///
+/// let replay = Arc::new(Replay::open("tests/captures/discovery.jsonl")?);
+/// let s = Session::builder().transport(replay.clone()).build()?;
+/// let adapter = Adapter::create(&s, &"/org/bluez/hci0".parse()?)?.unwrap();
+/// assert!(matches!(adapter.start_discovery(), Err(BluezError::InProgress(_))));
+/// assert_eq!(replay.remaining(), 0);
+/// # Ok::<(), BluezError>(())
+/// ```
+pub struct Replay {
+    state: Mutex<ReplayState>,
+    signals: Vec<Map<String, Value>>,
+}
+
+struct ReplayState {
+    exchanges: Vec<Exchange>,
+    // 応答の作成に必要なため、呼び出しに振るシリアル番号
+    serial: u32,
+}
+
+/// 記録したメソッド呼び出しと応答の組
+struct Exchange {
+    path: String,
+    interface: String,
+    member: String,
+    args: Value,
+    // 記録が応答の前に終わっている場合は`None`
+    reply: Option<Map<String, Value>>,
+    used: bool,
+}
+
+impl Replay {
+    /// `Recorder`で書き出したファイルを読み込む
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, BluezError> {
+        let file = File::open(path).map_err(|e| BluezError::Runtime(e.to_string()))?;
+        Replay::from_reader(BufReader::new(file))
+    }
+
+    /// `Recorder`で書き出した内容を読み込む
+    pub fn from_reader<R: BufRead>(reader: R) -> Result<Self, BluezError> {
+        let mut exchanges: Vec<Exchange> = vec![];
+        let mut ids = HashMap::new();
+        let mut signals = vec![];
+        for (n, line) in reader.lines().enumerate() {
+            let line = line.map_err(|e| BluezError::Runtime(e.to_string()))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let invalid =
+                |message: &str| BluezError::Runtime(format!("line {}: {}", n + 1, message));
+            let record = match serde_json::from_str(&line) {
+                Ok(Value::Object(record)) => record,
+                Ok(_) => return Err(invalid("not an object")),
+                Err(e) => return Err(invalid(&e.to_string())),
+            };
+            let id = record.get("id").and_then(Value::as_u64);
+            match record.get("type").and_then(Value::as_str) {
+                Some("call") => {
+                    let id = id.ok_or_else(|| invalid("missing id"))?;
+                    ids.insert(id, exchanges.len());
+                    exchanges.push(Exchange {
+                        path: string(&record, "path"),
+                        interface: string(&record, "interface"),
+                        member: string(&record, "member"),
+                        args: record.get("args").cloned().unwrap_or_else(|| json!([])),
+                        reply: None,
+                        used: false,
+                    });
+                }
+                Some("return") | Some("error") => {
+                    let index = id
+                        .and_then(|id| ids.get(&id))
+                        .ok_or_else(|| invalid("reply to an unknown call"))?;
+                    exchanges[*index].reply = Some(record);
+                }
+                Some("signal") => signals.push(record),
+                _ => return Err(invalid("unknown type")),
+            }
+        }
+        Ok(Replay {
+            state: Mutex::new(ReplayState {
+                exchanges,
+                serial: 0,
+            }),
+            signals,
+        })
+    }
+
+    /// まだ応答に使用していないメソッド呼び出しの記録の数
+    pub fn remaining(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state
+            .exchanges
+            .iter()
+            .filter(|exchange| !exchange.used)
+            .count()
+    }
+
+    /// 記録したシグナルから作成したオブジェクトの追加・削除のイベント
+    pub fn events(&self) -> Vec<Event> {
+        self.signals
+            .iter()
+            .filter_map(|record| {
+                let mut msg = Message::new_signal(
+                    string(record, "path"),
+                    string(record, "interface"),
+                    string(record, "member"),
+                )
+                .ok()?;
+                msg.append_items(&decode_args(record.get("args")?).ok()?);
+                Some(msg)
+            })
+            .flat_map(|msg| Event::from_message(&msg))
+            .collect()
+    }
+
+    /// 記録した応答を返す
+    fn call(&self, mut msg: Message) -> Result<Message, BluezError> {
+        let mut state = self.state.lock().unwrap();
+        state.serial += 1;
+        msg.set_serial(state.serial);
+        let path = msg.path().map(|p| p.to_string()).unwrap_or_default();
+        let interface = msg.interface().map(|i| i.to_string()).unwrap_or_default();
+        let member = msg.member().map(|m| m.to_string()).unwrap_or_default();
+        let args = encode_args(&msg);
+        let exchange = state
+            .exchanges
+            .iter_mut()
+            .find(|exchange| {
+                !exchange.used
+                    && exchange.path == path
+                    && exchange.interface == interface
+                    && exchange.member == member
+                    && exchange.args == args
+            })
+            .ok_or_else(|| {
+                BluezError::DBus(dbus::Error::new_failed(&format!(
+                    "no recorded reply for {}.{} on {}",
+                    interface, member, path
+                )))
+            })?;
+        exchange.used = true;
+        let reply = match &exchange.reply {
+            Some(reply) => reply,
+            None => return Err(BluezError::Timeout(format!("{}.{}", interface, member))),
+        };
+        if reply.get("type").and_then(Value::as_str) == Some("error") {
+            let name = string(reply, "name");
+            return Err(dbus::Error::new_custom(&name, &string(reply, "message")).into());
+        }
+        let items = decode_args(reply.get("args").unwrap_or(&json!([])))
+            .map_err(|e| BluezError::Runtime(format!("invalid recorded reply: {}", e)))?;
+        let mut reply = msg.method_return();
+        reply.append_items(&items);
+        Ok(reply)
+    }
+}
+
+impl blocking::Transport for Replay {
+    fn call(&self, msg: Message, _timeout: Duration) -> Result<Message, BluezError> {
+        self.call(msg)
+    }
+}
+
+impl nonblock::Transport for Replay {
+    fn call(
+        &self,
+        msg: Message,
+        _timeout: Duration,
+    ) -> BoxFuture<'static, Result<Message, BluezError>> {
+        Box::pin(future::ready(self.call(msg)))
+    }
+
+    /// 応答を待たない送信は記録に含まれないため何もしない
+    fn send(&self, _msg: Message) {}
+}
+
+/// メッセージの宛先などの記録
+fn header(msg: &Message) -> Map<String, Value> {
+    let mut record = Map::new();
+    let name = |name: Option<String>| json!(name.unwrap_or_default());
+    record.insert("path".to_string(), name(msg.path().map(|p| p.to_string())));
+    record.insert(
+        "interface".to_string(),
+        name(msg.interface().map(|i| i.to_string())),
+    );
+    record.insert(
+        "member".to_string(),
+        name(msg.member().map(|m| m.to_string())),
+    );
+    record.insert("args".to_string(), encode_args(msg));
+    record
+}
+
+fn string(record: &Map<String, Value>, key: &str) -> String {
+    record
+        .get(key)
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn encode_args(msg: &Message) -> Value {
+    Value::Array(msg.get_items().iter().map(encode).collect())
+}
+
+fn decode_args(args: &Value) -> Result<Vec<MessageItem>, String> {
+    args.as_array()
+        .ok_or_else(|| "args is not an array".to_string())?
+        .iter()
+        .map(decode)
+        .collect()
+}
+
+/// 値を`["<シグネチャ>", 値]`の形にする
+fn encode(item: &MessageItem) -> Value {
+    let value = match item {
+        MessageItem::Str(s) => json!(s),
+        MessageItem::Bool(b) => json!(b),
+        MessageItem::Byte(n) => json!(n),
+        MessageItem::Int16(n) => json!(n),
+        MessageItem::Int32(n) => json!(n),
+        MessageItem::Int64(n) => json!(n),
+        MessageItem::UInt16(n) => json!(n),
+        MessageItem::UInt32(n) => json!(n),
+        MessageItem::UInt64(n) => json!(n),
+        MessageItem::Double(n) => json!(n),
+        MessageItem::ObjectPath(path) => json!(path.to_string()),
+        MessageItem::Signature(sig) => json!(sig.to_string()),
+        MessageItem::Variant(item) => encode(item),
+        MessageItem::Struct(items) => Value::Array(items.iter().map(encode).collect()),
+        MessageItem::Array(array) if &**array.signature() == "ay" => json!(array
+            .iter()
+            .filter_map(|item| match item {
+                MessageItem::Byte(b) => Some(format!("{:02x}", b)),
+                _ => None,
+            })
+            .collect::<String>()),
+        MessageItem::Array(array) => Value::Array(array.iter().map(encode).collect()),
+        MessageItem::Dict(dict) => Value::Array(
+            dict.iter()
+                .map(|(key, value)| json!([encode(key), encode(value)]))
+                .collect(),
+        ),
+        // ファイルディスクリプターは記録できない
+        MessageItem::UnixFd(_) => Value::Null,
+    };
+    json!([item.signature().to_string(), value])
+}
+
+fn decode(value: &Value) -> Result<MessageItem, String> {
+    let invalid = || format!("invalid value: {}", value);
+    let (sig, value) = match value.as_array().map(|pair| pair.as_slice()) {
+        Some([Value::String(sig), value]) => (sig.as_str(), value),
+        _ => return Err(invalid()),
+    };
+    let int = || value.as_i64().ok_or_else(invalid);
+    let uint = || value.as_u64().ok_or_else(invalid);
+    let string = || value.as_str().map(str::to_string).ok_or_else(invalid);
+    let items = || value.as_array().ok_or_else(invalid);
+    let signature = |sig: &str| Signature::new(sig.to_string()).map_err(|_| invalid());
+    let item = match sig {
+        "s" => MessageItem::Str(string()?),
+        "b" => MessageItem::Bool(value.as_bool().ok_or_else(invalid)?),
+        "y" => MessageItem::Byte(uint()? as u8),
+        "n" => MessageItem::Int16(int()? as i16),
+        "i" => MessageItem::Int32(int()? as i32),
+        "x" => MessageItem::Int64(int()?),
+        "q" => MessageItem::UInt16(uint()? as u16),
+        "u" => MessageItem::UInt32(uint()? as u32),
+        "t" => MessageItem::UInt64(uint()?),
+        "d" => MessageItem::Double(value.as_f64().ok_or_else(invalid)?),
+        "o" => MessageItem::ObjectPath(dbus::Path::new(string()?).map_err(|_| invalid())?),
+        "g" => MessageItem::Signature(signature(&string()?)?),
+        "v" => MessageItem::Variant(Box::new(decode(value)?)),
+        "ay" if value.is_string() => {
+            let hex = string()?;
+            let bytes = (0..hex.len())
+                .step_by(2)
+                .map(|i| {
+                    hex.get(i..i + 2)
+                        .and_then(|b| u8::from_str_radix(b, 16).ok())
+                        .map(MessageItem::Byte)
+                        .ok_or_else(invalid)
+                })
+                .collect::<Result<_, _>>()?;
+            MessageItem::Array(
+                MessageItemArray::new(bytes, signature(sig)?).map_err(|_| invalid())?,
+            )
+        }
+        _ if sig.starts_with("a{") && sig.ends_with('}') => {
+            let pairs = items()?
+                .iter()
+                .map(|pair| match pair.as_array().map(|pair| pair.as_slice()) {
+                    Some([key, value]) => Ok((decode(key)?, decode(value)?)),
+                    _ => Err(invalid()),
+                })
+                .collect::<Result<_, _>>()?;
+            let (key, value) = sig[2..sig.len() - 1].split_at(1);
+            MessageItem::Dict(
+                MessageItemDict::new(pairs, signature(key)?, signature(value)?)
+                    .map_err(|_| invalid())?,
+            )
+        }
+        _ if sig.starts_with('a') => {
+            let items = items()?.iter().map(decode).collect::<Result<_, _>>()?;
+            MessageItem::Array(
+                MessageItemArray::new(items, signature(sig)?).map_err(|_| invalid())?,
+            )
+        }
+        _ if sig.starts_with('(') => {
+            MessageItem::Struct(items()?.iter().map(decode).collect::<Result<_, _>>()?)
+        }
+        _ => return Err(invalid()),
+    };
+    Ok(item)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocking::{Adapter, Characteristic, Session};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    /// 書き出した内容を共有するバッファ
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let fake = FakeBluez::new();
+        let characteristic = "/org/bluez/hci0/dev_00_11_22_33_44_55/service0001/char0002";
+        fake.set_property("/org/bluez/hci0", ADAPTER_INTERFACE, "Powered", true);
+        fake.add_object(characteristic, "org.bluez.GattCharacteristic1");
+        fake.on_call(ADAPTER_INTERFACE, "StartDiscovery", |_| {
+            Err(BluezError::InProgress(
+                "Operation already in progress".to_string(),
+            ))
+        });
+        fake.on_call("org.bluez.GattCharacteristic1", "ReadValue", |msg| {
+            Ok(msg.method_return().append1(vec![0x01u8, 0xff]))
+        });
+
+        let buffer = Buffer::default();
+        let s = Session::builder()
+            .transport(Arc::new(fake))
+            .record(Recorder::new(buffer.clone()))
+            .build()
+            .unwrap();
+        let adapter = Adapter::create(&s, &"/org/bluez/hci0".parse().unwrap())
+            .unwrap()
+            .unwrap();
+        assert!(adapter.is_powered().unwrap());
+        assert!(adapter.start_discovery().is_err());
+        assert_eq!(
+            Characteristic::new(&s, &characteristic.parse().unwrap())
+                .read_value(HashMap::new())
+                .unwrap(),
+            vec![0x01, 0xff]
+        );
+
+        let recorded = buffer.0.lock().unwrap().clone();
+        let replay = Arc::new(Replay::from_reader(&recorded[..]).unwrap());
+        assert_eq!(replay.remaining(), 4);
+        let s = Session::builder()
+            .transport(replay.clone())
+            .build()
+            .unwrap();
+        let adapter = Adapter::create(&s, &"/org/bluez/hci0".parse().unwrap())
+            .unwrap()
+            .unwrap();
+        assert!(adapter.is_powered().unwrap());
+        assert!(matches!(
+            adapter.start_discovery(),
+            Err(BluezError::InProgress(_))
+        ));
+        assert_eq!(
+            Characteristic::new(&s, &characteristic.parse().unwrap())
+                .read_value(HashMap::new())
+                .unwrap(),
+            vec![0x01, 0xff]
+        );
+        assert_eq!(replay.remaining(), 0);
+        assert!(adapter.start_discovery().is_err());
+    }
+
+    #[test]
+    fn encode_round_trip() {
+        let mut props = HashMap::new();
+        props.insert(
+            "Name".to_string(),
+            dbus::arg::Variant(Box::new("x".to_string()) as Box<dyn dbus::arg::RefArg>),
+        );
+        let msg = Message::new_signal("/", "org.bluez.Test", "Test")
+            .unwrap()
+            .append3(dbus::Path::from("/org/bluez"), props, vec![1u8, 2, 3])
+            .append2(Vec::<String>::new(), (1u16, -2i64, 0.5f64));
+        let args = encode_args(&msg);
+        assert_eq!(decode_args(&args).unwrap(), msg.get_items());
+    }
+}
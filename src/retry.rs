@@ -0,0 +1,125 @@
+use crate::*;
+use std::cmp;
+use std::fmt;
+use std::time::Duration;
+
+/// 一時的なエラーに対する再試行の方針
+///
+/// 待ち時間は`initial_delay`から始まり、再試行のたびに倍になる(`max_delay`が上限)。
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// 最初の試行を含めた試行回数の上限
+    pub max_attempts: u32,
+    /// 最初の再試行までの待ち時間
+    pub initial_delay: Duration,
+    /// 待ち時間の上限
+    pub max_delay: Duration,
+    /// 再試行するエラーの判定(既定値は`BluezError::is_transient`)
+    pub retry_on: fn(&BluezError) -> bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_delay: Duration::from_millis(200),
+            max_delay: Duration::from_secs(5),
+            retry_on: BluezError::is_transient,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// 再試行しない方針
+    pub fn never() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// `attempts`回目の試行が`err`で失敗した後、再試行までの待ち時間
+    ///
+    /// 再試行しない場合は`None`を返す。
+    pub(in crate) fn backoff(&self, err: &BluezError, attempts: u32) -> Option<Duration> {
+        if attempts >= self.max_attempts || !(self.retry_on)(err) {
+            return None;
+        }
+        let factor = 1u32.checked_shl(attempts - 1).unwrap_or(u32::MAX);
+        let delay = cmp::min(
+            self.initial_delay
+                .checked_mul(factor)
+                .unwrap_or(self.max_delay),
+            self.max_delay,
+        );
+        #[cfg(feature = "tracing")]
+        tracing::debug!(attempts, ?delay, error = %err, "retrying bluez operation");
+        Some(delay)
+    }
+}
+
+/// 再試行を伴う操作の結果と試行回数
+///
+/// 失敗した場合は`Retried<BluezError>`となり、`?`で`BluezError`に変換できる。
+#[derive(Debug)]
+pub struct Retried<T> {
+    pub value: T,
+    /// 最初の試行を含めた試行回数
+    pub attempts: u32,
+}
+
+impl<T> Retried<T> {
+    /// 試行回数を捨てて値を取り出す
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+}
+
+impl fmt::Display for Retried<BluezError> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (after {} attempts)", self.value, self.attempts)
+    }
+}
+
+impl std::error::Error for Retried<BluezError> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.value)
+    }
+}
+
+impl From<Retried<BluezError>> for BluezError {
+    fn from(err: Retried<BluezError>) -> Self {
+        err.value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exponential_backoff() {
+        let policy = RetryPolicy {
+            max_attempts: 6,
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(500),
+            ..Default::default()
+        };
+        let busy = BluezError::InProgress("In Progress".to_string());
+        let delays: Vec<_> = (1..=6).map(|n| policy.backoff(&busy, n)).collect();
+        assert_eq!(
+            delays,
+            vec![
+                Some(Duration::from_millis(100)),
+                Some(Duration::from_millis(200)),
+                Some(Duration::from_millis(400)),
+                Some(Duration::from_millis(500)),
+                Some(Duration::from_millis(500)),
+                None,
+            ]
+        );
+        let failed = BluezError::AuthenticationFailed("Authentication Failed".to_string());
+        assert_eq!(policy.backoff(&failed, 1), None);
+        assert_eq!(RetryPolicy::never().backoff(&busy, 1), None);
+    }
+}
@@ -0,0 +1,472 @@
+use dbus::arg::{ArgType, IterAppend, RefArg};
+use dbus::strings::{Path, Signature};
+use std::any::Any;
+use std::fmt;
+use std::iter;
+
+/// 所有する形式で保持するD-Busの値
+///
+/// 受信したメッセージの値(`Box<dyn RefArg>`)は`Rc`などを含み得るため、スレッド間で受け渡せる形式に変換して保持する。
+/// 作成の際にシグネチャと配列の要素の型を確認するため、常にメッセージに書き込める。
+#[derive(Clone, PartialEq)]
+pub(in crate) struct Value {
+    kind: Kind,
+    signature: Signature<'static>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Kind {
+    Bool(bool),
+    Byte(u8),
+    Int16(i16),
+    UInt16(u16),
+    Int32(i32),
+    UInt32(u32),
+    Int64(i64),
+    UInt64(u64),
+    Double(f64),
+    String(String),
+    ObjectPath(Path<'static>),
+    Signature(Signature<'static>),
+    Variant(Box<Value>),
+    Struct(Vec<Value>),
+    /// 要素の型と要素
+    Array(Signature<'static>, Vec<Value>),
+    /// キーの型、値の型と要素
+    Dict(Signature<'static>, Signature<'static>, Vec<(Value, Value)>),
+}
+
+impl Value {
+    /// `RefArg`の値から変換する
+    ///
+    /// ファイルディスクリプタなど、変換できない値の場合はエラーを返す。
+    pub(in crate) fn from_refarg(arg: &dyn RefArg) -> Result<Self, String> {
+        let unsupported = || format!("unsupported value: {}", arg.signature());
+        let int = || arg.as_i64().ok_or_else(unsupported);
+        let uint = || arg.as_u64().ok_or_else(unsupported);
+        let str = || arg.as_str().map(|s| s.to_string()).ok_or_else(unsupported);
+        let items = || arg.as_iter().ok_or_else(unsupported);
+        let kind = match arg.arg_type() {
+            ArgType::Boolean => Kind::Bool(int()? != 0),
+            ArgType::Byte => Kind::Byte(uint()? as u8),
+            ArgType::Int16 => Kind::Int16(int()? as i16),
+            ArgType::UInt16 => Kind::UInt16(uint()? as u16),
+            ArgType::Int32 => Kind::Int32(int()? as i32),
+            ArgType::UInt32 => Kind::UInt32(uint()? as u32),
+            ArgType::Int64 => Kind::Int64(int()?),
+            ArgType::UInt64 => Kind::UInt64(uint()?),
+            ArgType::Double => Kind::Double(arg.as_f64().ok_or_else(unsupported)?),
+            ArgType::String => Kind::String(str()?),
+            ArgType::ObjectPath => Kind::ObjectPath(Path::new(str()?)?),
+            ArgType::Signature => Kind::Signature(Signature::new(str()?)?),
+            ArgType::Variant => {
+                let inner = items()?.next().ok_or_else(unsupported)?;
+                Kind::Variant(Box::new(Value::from_refarg(inner)?))
+            }
+            ArgType::Struct => {
+                Kind::Struct(items()?.map(Value::from_refarg).collect::<Result<_, _>>()?)
+            }
+            ArgType::Array => {
+                let signature = arg.signature();
+                if !single_type(&signature) {
+                    return Err(unsupported());
+                }
+                match signature
+                    .strip_prefix("a{")
+                    .and_then(|entry| entry.strip_suffix('}'))
+                {
+                    Some(entry) => {
+                        let (key, value) = entry.split_at(1);
+                        // 辞書の`as_iter`はキーと値を交互に返す
+                        let mut items = items()?;
+                        let mut entries = vec![];
+                        while let (Some(key), Some(value)) = (items.next(), items.next()) {
+                            entries.push((Value::from_refarg(key)?, Value::from_refarg(value)?));
+                        }
+                        Kind::Dict(Signature::new(key)?, Signature::new(value)?, entries)
+                    }
+                    None => Kind::Array(
+                        Signature::new(&signature[1..])?,
+                        items()?.map(Value::from_refarg).collect::<Result<_, _>>()?,
+                    ),
+                }
+            }
+            _ => return Err(unsupported()),
+        };
+        Value::new(kind)
+    }
+
+    /// 値のシグネチャを求め、メッセージに書き込める値かどうかを確認して作成する
+    fn new(kind: Kind) -> Result<Self, String> {
+        let signature = match &kind {
+            Kind::Bool(_) => "b".to_string(),
+            Kind::Byte(_) => "y".to_string(),
+            Kind::Int16(_) => "n".to_string(),
+            Kind::UInt16(_) => "q".to_string(),
+            Kind::Int32(_) => "i".to_string(),
+            Kind::UInt32(_) => "u".to_string(),
+            Kind::Int64(_) => "x".to_string(),
+            Kind::UInt64(_) => "t".to_string(),
+            Kind::Double(_) => "d".to_string(),
+            Kind::String(_) => "s".to_string(),
+            Kind::ObjectPath(_) => "o".to_string(),
+            Kind::Signature(_) => "g".to_string(),
+            Kind::Variant(_) => "v".to_string(),
+            Kind::Struct(items) => format!(
+                "({})",
+                items
+                    .iter()
+                    .map(|item| &*item.signature)
+                    .collect::<String>()
+            ),
+            Kind::Array(element, _) => format!("a{}", element),
+            Kind::Dict(key, value, _) => format!("a{{{}{}}}", key, value),
+        };
+        // 空の構造体や辞書のキーが基本型でないものなど、一つの完全な型でないものは書き込めない
+        let signature = Signature::new(signature)?;
+        if !single_type(&signature) {
+            return Err(format!("invalid signature: {}", signature));
+        }
+        let matched = match &kind {
+            Kind::Array(element, items) => items.iter().all(|item| item.signature == *element),
+            Kind::Dict(key, value, entries) => entries
+                .iter()
+                .all(|(k, v)| k.signature == *key && v.signature == *value),
+            _ => true,
+        };
+        if !matched {
+            return Err(format!("items do not match the signature: {}", signature));
+        }
+        Ok(Value { kind, signature })
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.kind.fmt(f)
+    }
+}
+
+/// 数値や文字列の取得は、dbusクレートの各型の実装と同じ範囲で値を返す
+impl RefArg for Value {
+    fn arg_type(&self) -> ArgType {
+        match self.kind {
+            Kind::Bool(_) => ArgType::Boolean,
+            Kind::Byte(_) => ArgType::Byte,
+            Kind::Int16(_) => ArgType::Int16,
+            Kind::UInt16(_) => ArgType::UInt16,
+            Kind::Int32(_) => ArgType::Int32,
+            Kind::UInt32(_) => ArgType::UInt32,
+            Kind::Int64(_) => ArgType::Int64,
+            Kind::UInt64(_) => ArgType::UInt64,
+            Kind::Double(_) => ArgType::Double,
+            Kind::String(_) => ArgType::String,
+            Kind::ObjectPath(_) => ArgType::ObjectPath,
+            Kind::Signature(_) => ArgType::Signature,
+            Kind::Variant(_) => ArgType::Variant,
+            Kind::Struct(_) => ArgType::Struct,
+            Kind::Array(..) | Kind::Dict(..) => ArgType::Array,
+        }
+    }
+
+    fn signature(&self) -> Signature<'static> {
+        self.signature.clone()
+    }
+
+    fn append(&self, i: &mut IterAppend) {
+        match &self.kind {
+            Kind::Bool(b) => b.append(i),
+            Kind::Byte(n) => n.append(i),
+            Kind::Int16(n) => n.append(i),
+            Kind::UInt16(n) => n.append(i),
+            Kind::Int32(n) => n.append(i),
+            Kind::UInt32(n) => n.append(i),
+            Kind::Int64(n) => n.append(i),
+            Kind::UInt64(n) => n.append(i),
+            Kind::Double(n) => n.append(i),
+            Kind::String(s) => s.append(i),
+            Kind::ObjectPath(path) => path.append(i),
+            Kind::Signature(sig) => sig.append(i),
+            Kind::Variant(value) => i.append_variant(&value.signature, |i| value.append(i)),
+            Kind::Struct(items) => {
+                i.append_struct(|i| items.iter().for_each(|item| item.append(i)))
+            }
+            Kind::Array(element, items) => {
+                i.append_array(element, |i| items.iter().for_each(|item| item.append(i)))
+            }
+            Kind::Dict(key, value, entries) => i.append_dict(key, value, |i| {
+                for (k, v) in entries {
+                    i.append_dict_entry(|i| {
+                        k.append(i);
+                        v.append(i);
+                    })
+                }
+            }),
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn as_i64(&self) -> Option<i64> {
+        match self.kind {
+            Kind::Bool(b) => Some(b as i64),
+            Kind::Byte(n) => Some(n.into()),
+            Kind::Int16(n) => Some(n.into()),
+            Kind::UInt16(n) => Some(n.into()),
+            Kind::Int32(n) => Some(n.into()),
+            Kind::UInt32(n) => Some(n.into()),
+            Kind::Int64(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    fn as_u64(&self) -> Option<u64> {
+        match self.kind {
+            Kind::Bool(b) => Some(b as u64),
+            Kind::Byte(n) => Some(n.into()),
+            Kind::UInt16(n) => Some(n.into()),
+            Kind::UInt32(n) => Some(n.into()),
+            Kind::UInt64(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    fn as_f64(&self) -> Option<f64> {
+        match self.kind {
+            Kind::Bool(b) => Some(if b { 1.0 } else { 0.0 }),
+            Kind::Byte(n) => Some(n.into()),
+            Kind::Int16(n) => Some(n.into()),
+            Kind::UInt16(n) => Some(n.into()),
+            Kind::Int32(n) => Some(n.into()),
+            Kind::UInt32(n) => Some(n.into()),
+            Kind::Double(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match &self.kind {
+            Kind::String(s) => Some(s),
+            Kind::ObjectPath(path) => Some(&**path),
+            Kind::Signature(sig) => Some(&**sig),
+            _ => None,
+        }
+    }
+
+    fn as_iter<'a>(&'a self) -> Option<Box<dyn Iterator<Item = &'a dyn RefArg> + 'a>> {
+        match &self.kind {
+            Kind::Variant(value) => Some(Box::new(iter::once(&**value as &dyn RefArg))),
+            Kind::Struct(items) | Kind::Array(_, items) => {
+                Some(Box::new(items.iter().map(|item| item as &dyn RefArg)))
+            }
+            Kind::Dict(_, _, entries) => Some(Box::new(entries.iter().flat_map(|(key, value)| {
+                iter::once(key as &dyn RefArg).chain(iter::once(value as &dyn RefArg))
+            }))),
+            _ => None,
+        }
+    }
+
+    fn box_clone(&self) -> Box<dyn RefArg + 'static> {
+        Box::new(self.clone())
+    }
+}
+
+/// シグネチャが一つの完全な型かどうか
+fn single_type(signature: &str) -> bool {
+    type_len(signature.as_bytes()) == Some(signature.len())
+}
+
+/// シグネチャの先頭の完全な型の長さ(ファイルディスクリプタは扱わない)
+fn type_len(signature: &[u8]) -> Option<usize> {
+    const BASIC: &[u8] = b"ybnqiuxtdsog";
+    match *signature.first()? {
+        c if BASIC.contains(&c) || c == b'v' => Some(1),
+        b'a' if signature.get(1) == Some(&b'{') => {
+            // 辞書のキーは基本型のみ
+            let key = *signature.get(2)?;
+            let value = type_len(signature.get(3..)?)?;
+            (BASIC.contains(&key) && signature.get(3 + value) == Some(&b'}')).then_some(4 + value)
+        }
+        b'a' => type_len(&signature[1..]).map(|len| len + 1),
+        b'(' => {
+            let mut len = 1;
+            while *signature.get(len)? != b')' {
+                len += type_len(&signature[len..])?;
+            }
+            (len > 1).then_some(len + 1)
+        }
+        _ => None,
+    }
+}
+
+/// シリアライズの際の値
+///
+/// 読み込み直したときに同じ型に戻るよう、D-Busの型ごとに区別する。
+/// 配列と辞書は空の場合にも型が分かるよう、要素のシグネチャを持つ。
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+enum Repr {
+    Bool(bool),
+    Byte(u8),
+    Int16(i16),
+    UInt16(u16),
+    Int32(i32),
+    UInt32(u32),
+    Int64(i64),
+    UInt64(u64),
+    Double(f64),
+    String(String),
+    ObjectPath(String),
+    Signature(String),
+    Variant(Box<Repr>),
+    Struct(Vec<Repr>),
+    Array {
+        signature: String,
+        items: Vec<Repr>,
+    },
+    Dict {
+        key: String,
+        value: String,
+        entries: Vec<(Repr, Repr)>,
+    },
+}
+
+#[cfg(feature = "serde")]
+impl From<&Value> for Repr {
+    fn from(value: &Value) -> Self {
+        let list = |items: &[Value]| items.iter().map(Repr::from).collect();
+        match &value.kind {
+            Kind::Bool(b) => Repr::Bool(*b),
+            Kind::Byte(n) => Repr::Byte(*n),
+            Kind::Int16(n) => Repr::Int16(*n),
+            Kind::UInt16(n) => Repr::UInt16(*n),
+            Kind::Int32(n) => Repr::Int32(*n),
+            Kind::UInt32(n) => Repr::UInt32(*n),
+            Kind::Int64(n) => Repr::Int64(*n),
+            Kind::UInt64(n) => Repr::UInt64(*n),
+            Kind::Double(n) => Repr::Double(*n),
+            Kind::String(s) => Repr::String(s.clone()),
+            Kind::ObjectPath(path) => Repr::ObjectPath(path.to_string()),
+            Kind::Signature(sig) => Repr::Signature(sig.to_string()),
+            Kind::Variant(value) => Repr::Variant(Box::new(Repr::from(&**value))),
+            Kind::Struct(items) => Repr::Struct(list(items)),
+            Kind::Array(element, items) => Repr::Array {
+                signature: element.to_string(),
+                items: list(items),
+            },
+            Kind::Dict(key, value, entries) => Repr::Dict {
+                key: key.to_string(),
+                value: value.to_string(),
+                entries: entries
+                    .iter()
+                    .map(|(key, value)| (Repr::from(key), Repr::from(value)))
+                    .collect(),
+            },
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Repr {
+    fn into_value(self) -> Result<Value, String> {
+        let list = |reprs: Vec<Repr>| {
+            reprs
+                .into_iter()
+                .map(Repr::into_value)
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let kind = match self {
+            Repr::Bool(b) => Kind::Bool(b),
+            Repr::Byte(n) => Kind::Byte(n),
+            Repr::Int16(n) => Kind::Int16(n),
+            Repr::UInt16(n) => Kind::UInt16(n),
+            Repr::Int32(n) => Kind::Int32(n),
+            Repr::UInt32(n) => Kind::UInt32(n),
+            Repr::Int64(n) => Kind::Int64(n),
+            Repr::UInt64(n) => Kind::UInt64(n),
+            Repr::Double(n) => Kind::Double(n),
+            Repr::String(s) => Kind::String(s),
+            Repr::ObjectPath(path) => Kind::ObjectPath(Path::new(path)?),
+            Repr::Signature(sig) => Kind::Signature(Signature::new(sig)?),
+            Repr::Variant(repr) => Kind::Variant(Box::new(repr.into_value()?)),
+            Repr::Struct(reprs) => Kind::Struct(list(reprs)?),
+            Repr::Array { signature, items } => {
+                Kind::Array(Signature::new(signature)?, list(items)?)
+            }
+            Repr::Dict {
+                key,
+                value,
+                entries,
+            } => Kind::Dict(
+                Signature::new(key)?,
+                Signature::new(value)?,
+                entries
+                    .into_iter()
+                    .map(|(key, value)| Ok((key.into_value()?, value.into_value()?)))
+                    .collect::<Result<_, String>>()?,
+            ),
+        };
+        Value::new(kind)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Value {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serde::Serialize::serialize(&Repr::from(self), serializer)
+    }
+}
+
+/// 読み込んだ値はメッセージに書き込める値かどうかを確認する
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Value {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+        let repr: Repr = serde::Deserialize::deserialize(deserializer)?;
+        repr.into_value().map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dbus::arg::Variant;
+    use std::collections::HashMap;
+
+    #[test]
+    fn owned_value() {
+        let mut data: HashMap<u16, Variant<Box<dyn RefArg>>> = HashMap::new();
+        data.insert(0x004c, Variant(Box::new(vec![0x02u8, 0x15])));
+        let value = Value::from_refarg(&data).unwrap();
+        assert_eq!(&*value.signature(), "a{qv}");
+
+        let value = std::thread::spawn(move || value).join().unwrap();
+        let items: Vec<&dyn RefArg> = value.as_iter().unwrap().collect();
+        assert_eq!(items[0].as_u64(), Some(0x004c));
+        let bytes = items[1].as_iter().unwrap().next().unwrap();
+        assert_eq!(&*bytes.signature(), "ay");
+
+        // 送信する形式に戻しても同じ値になる
+        let msg = dbus::Message::new_signal("/", "org.example", "Test")
+            .unwrap()
+            .append1(Variant(value.box_clone()));
+        let sent: Variant<Box<dyn RefArg>> = msg.read1().unwrap();
+        assert_eq!(Value::from_refarg(&*sent.0).unwrap(), value);
+    }
+
+    #[test]
+    fn signature_checks() {
+        for signature in &["s", "a{sv}", "aa(ib)", "(sa{oq})", "v"] {
+            assert!(single_type(signature), "{}", signature);
+        }
+        for signature in &["", "ss", "()", "a{vs}", "a{s}", "(s", "h"] {
+            assert!(!single_type(signature), "{}", signature);
+        }
+    }
+}
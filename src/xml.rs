@@ -0,0 +1,75 @@
+/// 開始タグ(属性付き)か終了タグ
+///
+/// 空要素タグは開始タグと終了タグの組として扱う。
+pub(in crate) enum Tag {
+    Start(String, Vec<(String, String)>),
+    End(String),
+}
+
+/// タグの一覧に分解する(宣言、コメント、DOCTYPE、テキストは読み飛ばす)
+pub(in crate) fn tags(xml: &str) -> Result<Vec<Tag>, String> {
+    let mut tags = vec![];
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start..];
+        if rest.starts_with("<!--") {
+            let end = rest.find("-->").ok_or("unterminated comment")?;
+            rest = &rest[end + 3..];
+            continue;
+        }
+        let end = rest.find('>').ok_or("unterminated tag")?;
+        let body = &rest[1..end];
+        rest = &rest[end + 1..];
+        if body.starts_with('?') || body.starts_with('!') {
+            continue;
+        }
+        if let Some(name) = body.strip_prefix('/') {
+            tags.push(Tag::End(name.trim().to_string()));
+            continue;
+        }
+        let (body, empty) = match body.strip_suffix('/') {
+            Some(body) => (body, true),
+            None => (body, false),
+        };
+        let body = body.trim();
+        let name_end = body.find(char::is_whitespace).unwrap_or(body.len());
+        let name = body[..name_end].to_string();
+        let attrs = attributes(&body[name_end..])?;
+        tags.push(Tag::Start(name.clone(), attrs));
+        if empty {
+            tags.push(Tag::End(name));
+        }
+    }
+    Ok(tags)
+}
+
+/// `key="value"`の並びを読み込む
+fn attributes(mut s: &str) -> Result<Vec<(String, String)>, String> {
+    let mut attrs = vec![];
+    loop {
+        s = s.trim_start();
+        if s.is_empty() {
+            return Ok(attrs);
+        }
+        let eq = s
+            .find('=')
+            .ok_or_else(|| format!("invalid attribute: {}", s))?;
+        let key = s[..eq].trim().to_string();
+        s = s[eq + 1..].trim_start();
+        let quote = s.chars().next().filter(|c| *c == '"' || *c == '\'');
+        let quote = quote.ok_or_else(|| format!("unquoted attribute: {}", key))?;
+        let end = s[1..]
+            .find(quote)
+            .ok_or_else(|| format!("unterminated attribute: {}", key))?;
+        attrs.push((key, unescape(&s[1..end + 1])));
+        s = &s[end + 2..];
+    }
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}